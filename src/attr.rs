@@ -0,0 +1,254 @@
+// Structured colours and attributes, with conversions to and from
+// the compact `hfb` encodings used by `TermOut::hfb` and the page
+//
+// In `hfb` the colour digits are in colour-intensity order (blue=1,
+// red=2, green=4), whereas the ANSI codes are in RGB bit order
+// (red=1, green=2, blue=4), so conversions need to swap the bits
+// around.
+
+/// A foreground or background colour
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Colour {
+    /// The terminal's default colour
+    #[default]
+    Default,
+
+    /// One of the 16 basic ANSI colours, in ANSI order: 0 black, 1
+    /// red, 2 green, 3 yellow, 4 blue, 5 magenta, 6 cyan, 7 white,
+    /// then 8-15 the bright versions of the same.  Values above 15
+    /// are taken modulo 16.
+    Ansi(u8),
+
+    /// Index into the 256-colour palette
+    Indexed(u8),
+
+    /// 24-bit colour
+    Rgb(u8, u8, u8),
+}
+
+impl Colour {
+    /// Get the nearest basic colour in ANSI order 0-7, or `None` for
+    /// the default colour.  Intensities are thresholded at half
+    /// brightness, and brightness is otherwise dropped.
+    pub fn basic(self) -> Option<u8> {
+        let rgb = |r: bool, g: bool, b: bool| u8::from(r) | u8::from(g) << 1 | u8::from(b) << 2;
+        match self {
+            Colour::Default => None,
+            Colour::Ansi(v) => Some(v & 7),
+            Colour::Indexed(v) if v < 16 => Some(v & 7),
+            Colour::Indexed(v) if v < 232 => {
+                let v = v - 16;
+                Some(rgb(v / 36 >= 3, v / 6 % 6 >= 3, v % 6 >= 3))
+            }
+            Colour::Indexed(v) => Some(if v >= 244 { 7 } else { 0 }),
+            Colour::Rgb(r, g, b) => Some(rgb(r >= 128, g >= 128, b >= 128)),
+        }
+    }
+
+    /// Get the approximate RGB value of this colour, assuming the
+    /// standard xterm palette, or `None` for the default colour
+    pub fn rgb(self) -> Option<(u8, u8, u8)> {
+        const ANSI: [(u8, u8, u8); 16] = [
+            (0, 0, 0),
+            (205, 0, 0),
+            (0, 205, 0),
+            (205, 205, 0),
+            (0, 0, 238),
+            (205, 0, 205),
+            (0, 205, 205),
+            (229, 229, 229),
+            (127, 127, 127),
+            (255, 0, 0),
+            (0, 255, 0),
+            (255, 255, 0),
+            (92, 92, 255),
+            (255, 0, 255),
+            (0, 255, 255),
+            (255, 255, 255),
+        ];
+        const LEVEL: [u8; 6] = [0, 95, 135, 175, 215, 255];
+        match self {
+            Colour::Default => None,
+            Colour::Ansi(v) => Some(ANSI[usize::from(v & 15)]),
+            Colour::Indexed(v) if v < 16 => Some(ANSI[usize::from(v)]),
+            Colour::Indexed(v) if v < 232 => {
+                let v = usize::from(v - 16);
+                Some((LEVEL[v / 36], LEVEL[v / 6 % 6], LEVEL[v % 6]))
+            }
+            Colour::Indexed(v) => {
+                let g = 8 + (v - 232) * 10;
+                Some((g, g, g))
+            }
+            Colour::Rgb(r, g, b) => Some((r, g, b)),
+        }
+    }
+
+    /// Downgrade this colour to one available in the given palette,
+    /// choosing the nearest by distance in RGB space.  This is the
+    /// default downgrade used by [`TermOut`].  Colours already
+    /// available in the palette are returned unchanged.
+    ///
+    /// [`TermOut`]: struct.TermOut.html
+    pub fn downgrade(self, palette: Palette) -> Colour {
+        let nearest = |lo: u8, hi: u8| {
+            let (r, g, b) = self.rgb().unwrap_or_default();
+            let dist = |i: &u8| {
+                let (r2, g2, b2) = Colour::Indexed(*i).rgb().unwrap_or_default();
+                let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+                d(r, r2) + d(g, g2) + d(b, b2)
+            };
+            (lo..=hi).min_by_key(dist).unwrap_or(lo)
+        };
+        match (self, palette) {
+            (Colour::Default, _) | (_, Palette::Rgb) => self,
+            (Colour::Ansi(_), _) => self,
+            (Colour::Indexed(_), Palette::Indexed256) => self,
+            (Colour::Indexed(v), Palette::Basic) if v < 16 => Colour::Ansi(v),
+            (_, Palette::Basic) => Colour::Ansi(nearest(0, 15)),
+            (Colour::Rgb(..), Palette::Indexed256) => Colour::Indexed(nearest(16, 255)),
+        }
+    }
+
+    // Add the SGR parameters for this colour to `out`, with `base`
+    // 30 for foreground or 40 for background
+    pub(crate) fn sgr(self, out: &mut String, base: u8) {
+        use std::fmt::Write;
+        let _ = match self {
+            Colour::Default => write!(out, "{}", base + 9),
+            Colour::Ansi(v) if v & 15 < 8 => write!(out, "{}", base + (v & 7)),
+            Colour::Ansi(v) => write!(out, "{}", base + 60 + (v & 7)),
+            Colour::Indexed(v) => write!(out, "{};5;{}", base + 8, v),
+            Colour::Rgb(r, g, b) => write!(out, "{};2;{};{};{}", base + 8, r, g, b),
+        };
+    }
+
+    // Convert to an `hfb` digit, 0-7 or 8 for default
+    fn hfb_digit(self) -> u16 {
+        match self.basic() {
+            None => 8,
+            Some(v) => u16::from((v & 1) << 1 | (v & 2) << 1 | (v & 4) >> 2),
+        }
+    }
+
+    // Convert from an `hfb` digit
+    fn from_hfb_digit(d: u16) -> Self {
+        match d {
+            0..=7 => Colour::Ansi(((d & 2) >> 1 | (d & 4) >> 1 | (d & 1) << 2) as u8),
+            _ => Colour::Default,
+        }
+    }
+}
+
+/// The range of colours that a terminal can display, for downgrading
+/// colours that it doesn't support
+///
+/// See [`TermOut::set_downgrade`].
+///
+/// [`TermOut::set_downgrade`]: struct.TermOut.html#method.set_downgrade
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Palette {
+    /// Only the 16 basic ANSI colours
+    Basic,
+
+    /// The 256-colour palette
+    Indexed256,
+
+    /// 24-bit colour
+    Rgb,
+}
+
+/// A set of colours and attributes for some text
+///
+/// This can be converted to and from the compact `hfb` encoding used
+/// by [`TermOut::hfb`] and by the page, but note that `hfb` can only
+/// represent the 8 basic colours and bold.  Other colours are
+/// converted to the nearest basic colour (see [`Colour::basic`]) and
+/// other attributes are dropped.
+///
+/// [`Colour::basic`]: enum.Colour.html#method.basic
+/// [`TermOut::hfb`]: struct.TermOut.html#method.hfb
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Attr {
+    /// Foreground colour
+    pub fg: Colour,
+
+    /// Background colour
+    pub bg: Colour,
+
+    /// Attribute flags, a combination of [`Attr::BOLD`] and so on
+    ///
+    /// [`Attr::BOLD`]: struct.Attr.html#associatedconstant.BOLD
+    pub flags: u8,
+}
+
+impl Attr {
+    /// Bold or increased intensity
+    pub const BOLD: u8 = 1;
+    /// Dim or decreased intensity
+    pub const DIM: u8 = 2;
+    /// Italic
+    pub const ITALIC: u8 = 4;
+    /// Underline
+    pub const UNDERLINE: u8 = 8;
+    /// Blink
+    pub const BLINK: u8 = 16;
+    /// Reverse video
+    pub const REVERSE: u8 = 32;
+
+    /// Create a set of attributes with the given colours and no
+    /// attribute flags
+    pub fn new(fg: Colour, bg: Colour) -> Self {
+        Self { fg, bg, flags: 0 }
+    }
+
+    /// Return a copy with the given attribute flags added
+    pub fn with(mut self, flags: u8) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// Convert from the compact `hfb` encoding.  An `H` digit with
+    /// the bottom bit set gives bold.
+    pub fn from_hfb(hfb: u16) -> Self {
+        Self {
+            fg: Colour::from_hfb_digit(hfb / 10 % 10),
+            bg: Colour::from_hfb_digit(hfb % 10),
+            flags: if hfb / 100 % 2 == 1 { Attr::BOLD } else { 0 },
+        }
+    }
+
+    /// Convert to the compact `hfb` encoding, downgrading colours
+    /// and dropping attributes which can't be represented
+    pub fn to_hfb(&self) -> u16 {
+        let h = if self.flags & Attr::BOLD != 0 { 100 } else { 0 };
+        h + self.fg.hfb_digit() * 10 + self.bg.hfb_digit()
+    }
+
+    // Get the SGR parameters to select exactly these attributes,
+    // starting from a reset
+    pub(crate) fn sgr(&self) -> String {
+        const CODES: [&str; 6] = ["1;", "2;", "3;", "4;", "5;", "7;"];
+        let mut out = String::from("0;");
+        for (i, code) in CODES.iter().enumerate() {
+            if self.flags & (1 << i) != 0 {
+                out.push_str(code);
+            }
+        }
+        self.fg.sgr(&mut out, 30);
+        out.push(';');
+        self.bg.sgr(&mut out, 40);
+        out
+    }
+}
+
+impl From<u16> for Attr {
+    fn from(hfb: u16) -> Self {
+        Attr::from_hfb(hfb)
+    }
+}
+
+impl From<Attr> for u16 {
+    fn from(attr: Attr) -> Self {
+        attr.to_hfb()
+    }
+}
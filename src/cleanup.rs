@@ -0,0 +1,125 @@
+// Registry of cleanups to run on panic, shared by all the `Terminal`
+// instances in the process
+//
+// Only one panic hook is installed however many terminals there are.
+// It is installed when the first terminal registers, and runs the
+// cleanups of all the registered terminals, most recent first, before
+// calling on to the panic hook that was in place before.  When the
+// last terminal is dropped, the previous hook is put back.
+
+use std::panic::PanicHookInfo;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type PanicHook = Box<dyn Fn(&PanicHookInfo<'_>) + 'static + Sync + Send>;
+pub(crate) type Cleanup = Box<dyn Fn() + Send + Sync + 'static>;
+
+struct Registry {
+    next_id: u64,
+    cleanups: Vec<(u64, Option<Cleanup>)>,
+    prev_hook: Option<Arc<PanicHook>>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    next_id: 0,
+    cleanups: Vec::new(),
+    prev_hook: None,
+});
+
+// Lock the registry, ignoring poisoning, since the cleanups must run
+// even if another thread panicked whilst holding the lock
+fn lock() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Registry {
+    // Add an entry with no cleanup, returning its ID
+    fn add(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.cleanups.push((id, None));
+        id
+    }
+
+    fn set(&mut self, id: u64, cleanup: Option<Cleanup>) {
+        if let Some(entry) = self.cleanups.iter_mut().find(|e| e.0 == id) {
+            entry.1 = cleanup;
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.cleanups.retain(|e| e.0 != id);
+    }
+
+    // Run all the cleanups, most recent first
+    fn run_all(&self) {
+        for cleanup in self.cleanups.iter().rev().filter_map(|e| e.1.as_ref()) {
+            cleanup();
+        }
+    }
+}
+
+/// Register a new terminal, with no cleanup yet, installing the
+/// panic hook if this is the first.  Returns the ID to use for
+/// further calls.
+pub(crate) fn register() -> u64 {
+    let mut reg = lock();
+    if reg.prev_hook.is_none() {
+        let prev_hook = Arc::new(std::panic::take_hook());
+        reg.prev_hook = Some(prev_hook.clone());
+        std::panic::set_hook(Box::new(move |info| {
+            lock().run_all();
+            prev_hook(info);
+        }));
+    }
+    reg.add()
+}
+
+/// Replace the cleanup for the given terminal, or set `None` if
+/// nothing needs doing on panic, e.g. whilst paused
+pub(crate) fn set(id: u64, cleanup: Option<Cleanup>) {
+    lock().set(id, cleanup);
+}
+
+/// Remove the given terminal, putting back the previous panic hook
+/// if this is the last
+pub(crate) fn unregister(id: u64) {
+    let mut reg = lock();
+    reg.remove(id);
+    // The hook can't be changed whilst panicking
+    if reg.cleanups.is_empty() && !std::thread::panicking() {
+        if let Some(prev_hook) = reg.prev_hook.take() {
+            drop(std::panic::take_hook());
+            std::panic::set_hook(Box::new(move |info| prev_hook(info)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry() {
+        let mut reg = Registry {
+            next_id: 0,
+            cleanups: Vec::new(),
+            prev_hook: None,
+        };
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let ids: Vec<u64> = (0..3).map(|_| reg.add()).collect();
+        for &id in &ids[..2] {
+            let log = log.clone();
+            reg.set(id, Some(Box::new(move || log.lock().unwrap().push(id))));
+        }
+        reg.run_all();
+        assert_eq!(*log.lock().unwrap(), [ids[1], ids[0]]);
+
+        log.lock().unwrap().clear();
+        reg.remove(ids[1]);
+        reg.set(ids[0], None);
+        let l = log.clone();
+        reg.set(ids[2], Some(Box::new(move || l.lock().unwrap().push(99))));
+        reg.run_all();
+        assert_eq!(*log.lock().unwrap(), [99]);
+    }
+}
@@ -0,0 +1,265 @@
+// Rendering of page contents to an RGBA image, for screenshots in
+// documentation or visual regression tests
+//
+// This uses a tiny built-in 5x7 font for ASCII, scaled to the cell
+// size with nearest-neighbour sampling, and draws box-drawing and
+// block characters procedurally.  Anything else is drawn as a hollow
+// box.
+
+/// RGBA image produced by [`Page::render_image`]
+///
+/// [`Page::render_image`]: struct.Page.html#method.render_image
+#[derive(Clone, Debug)]
+pub struct ImageBuffer {
+    /// Width in pixels
+    pub width: u32,
+
+    /// Height in pixels
+    pub height: u32,
+
+    /// Pixel data, 4 bytes per pixel (R, G, B, A), in rows from the
+    /// top-left
+    pub data: Vec<u8>,
+}
+
+impl ImageBuffer {
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    /// Get the colour of a pixel as `[R, G, B, A]`, or `None` if it
+    /// is outside the image
+    pub fn pixel(&self, x: u32, y: u32) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        Some([
+            self.data[i],
+            self.data[i + 1],
+            self.data[i + 2],
+            self.data[i + 3],
+        ])
+    }
+
+    fn set(&mut self, x: u32, y: u32, rgb: [u8; 3]) {
+        if x < self.width && y < self.height {
+            let i = (y as usize * self.width as usize + x as usize) * 4;
+            self.data[i..i + 4].copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+        }
+    }
+
+    // Draw a character into the cell with top-left (x, y) and size
+    // (w, h) in pixels, or just the background if `ch` is `None`
+    pub(crate) fn draw_cell(&mut self, x: u32, y: u32, w: u32, h: u32, ch: Option<char>, hfb: u16) {
+        let (fg, bg) = colours(hfb);
+        for py in 0..h {
+            for px in 0..w {
+                let on = ch.map(|c| lit(c, px, py, w, h)).unwrap_or(false);
+                self.set(x + px, y + py, if on { fg } else { bg });
+            }
+        }
+    }
+}
+
+// Map HFB to foreground and background RGB.  Colours are in
+// colour-intensity order: black, blue, red, magenta, green, cyan,
+// yellow, white, then 8 and 9 are the default colours.
+fn colours(hfb: u16) -> ([u8; 3], [u8; 3]) {
+    const DIM: [[u8; 3]; 10] = [
+        [0, 0, 0],
+        [0, 0, 205],
+        [205, 0, 0],
+        [205, 0, 205],
+        [0, 205, 0],
+        [0, 205, 205],
+        [205, 205, 0],
+        [229, 229, 229],
+        [229, 229, 229],
+        [229, 229, 229],
+    ];
+    const BRIGHT: [[u8; 3]; 10] = [
+        [127, 127, 127],
+        [92, 92, 255],
+        [255, 0, 0],
+        [255, 0, 255],
+        [0, 255, 0],
+        [0, 255, 255],
+        [255, 255, 0],
+        [255, 255, 255],
+        [255, 255, 255],
+        [255, 255, 255],
+    ];
+    let f = (hfb / 10 % 10) as usize;
+    let b = (hfb % 10) as usize;
+    let fg = if hfb / 100 % 2 == 1 {
+        BRIGHT[f]
+    } else {
+        DIM[f]
+    };
+    let bg = if b >= 8 { [0, 0, 0] } else { DIM[b] };
+    (fg, bg)
+}
+
+// Test whether pixel (px, py) of a cell of size (w, h) is lit for
+// the given character
+fn lit(c: char, px: u32, py: u32, w: u32, h: u32) -> bool {
+    let code = c as u32;
+    if (0x20..0x7F).contains(&code) {
+        // Font is 5x7 in a 6x8 cell, column-major, LSB at top
+        let fx = px * 6 / w;
+        let fy = py * 8 / h;
+        return fx < 5 && fy < 7 && FONT[(code - 0x20) as usize][fx as usize] >> fy & 1 != 0;
+    }
+    if let Some(arms) = box_arms(c) {
+        // Lines through the centre of the cell to the given edges
+        let (cx, cy) = (w / 2, h / 2);
+        let tx = (w / 8).max(1);
+        let ty = (h / 16).max(1);
+        let on_v = px + tx / 2 >= cx && px < cx + tx - tx / 2;
+        let on_h = py + ty / 2 >= cy && py < cy + ty - ty / 2;
+        return (on_v && ((arms & UP != 0 && py <= cy) || (arms & DOWN != 0 && py >= cy)))
+            || (on_h && ((arms & LEFT != 0 && px <= cx) || (arms & RIGHT != 0 && px >= cx)));
+    }
+    match code {
+        0x2580 => py < h / 2,
+        0x2581..=0x2588 => py * 8 >= h * (8 - (code - 0x2580)),
+        0x2591 => (px + py) & 3 == 0,
+        0x2592 => (px + py) & 1 == 0,
+        0x2593 => (px + py) & 3 != 0,
+        _ => {
+            // Hollow box for anything we can't draw
+            let (x0, y0, x1, y1) = (w / 6, h / 8, w - 1 - w / 6, h - 1 - h / 8);
+            (px >= x0 && px <= x1 && (py == y0 || py == y1))
+                || (py >= y0 && py <= y1 && (px == x0 || px == x1))
+        }
+    }
+}
+
+const UP: u8 = 1;
+const DOWN: u8 = 2;
+const LEFT: u8 = 4;
+const RIGHT: u8 = 8;
+
+// Get the arms of a box-drawing character.  Heavy and double lines
+// are drawn the same as light lines.
+fn box_arms(c: char) -> Option<u8> {
+    Some(match c {
+        '─' | '━' | '═' | '┄' | '┈' | '╌' => LEFT | RIGHT,
+        '│' | '┃' | '║' | '┆' | '┊' | '╎' => UP | DOWN,
+        '┌' | '┏' | '╔' | '╭' => RIGHT | DOWN,
+        '┐' | '┓' | '╗' | '╮' => LEFT | DOWN,
+        '└' | '┗' | '╚' | '╰' => RIGHT | UP,
+        '┘' | '┛' | '╝' | '╯' => LEFT | UP,
+        '├' | '┣' | '╠' => UP | DOWN | RIGHT,
+        '┤' | '┫' | '╣' => UP | DOWN | LEFT,
+        '┬' | '┳' | '╦' => LEFT | RIGHT | DOWN,
+        '┴' | '┻' | '╩' => LEFT | RIGHT | UP,
+        '┼' | '╋' | '╬' => UP | DOWN | LEFT | RIGHT,
+        _ => return None,
+    })
+}
+
+// 5x7 font for ASCII 0x20 to 0x7E, as 5 columns per character, with
+// bit 0 at the top
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x01, 0x01], // F
+    [0x3E, 0x41, 0x41, 0x51, 0x32], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x04, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x7F, 0x20, 0x18, 0x20, 0x7F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x03, 0x04, 0x78, 0x04, 0x03], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x08, 0x54, 0x54, 0x54, 0x3C], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x02, 0x01, 0x02, 0x04, 0x02], // ~
+];
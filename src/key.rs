@@ -1,8 +1,30 @@
 use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 // TODO: Add any extended keys sent by xterm or rxvt or whatever
 
+// Marker at the end of a bracketed paste
+pub(crate) const PASTE_END: &[u8] = b"\x1B[201~";
+
+// Search paste text for the end marker.  Returns `Ok` with the offset
+// of the marker, or else `Err` with the length of the data that is
+// certainly paste text.  Anything that might be the start of a marker
+// split across reads is left out of that length, so that the caller
+// can resume the search from there once more data arrives.
+pub(crate) fn find_paste_end(data: &[u8]) -> Result<usize, usize> {
+    match data.windows(PASTE_END.len()).position(|w| w == PASTE_END) {
+        Some(i) => Ok(i),
+        None => {
+            let keep = (1..PASTE_END.len())
+                .rev()
+                .find(|&n| data.ends_with(&PASTE_END[..n]))
+                .unwrap_or(0);
+            Err(data.len() - keep)
+        }
+    }
+}
+
 /// A keypress
 ///
 /// Note that not all possible combinations of keys can actually be
@@ -45,7 +67,18 @@ pub enum Key {
     Delete,
     End,
 
-    /// Meta/Alt key combination
+    /// Meta/Alt key combination.  Most terminals send Alt-x as Esc
+    /// followed by `x`, so Esc followed quickly by another key is
+    /// decoded as this, whereas an Esc with nothing following it
+    /// within 100ms (see [`Terminal::set_esc_timeout`]) is decoded
+    /// as [`Key::Esc`].  Note that when
+    /// running under `screen` or `tmux`, the multiplexer makes its
+    /// own decision about this, according to its `escape-time` or
+    /// `maptimeout` setting, and may pass on Esc and `x` with a gap
+    /// between them, in which case they arrive here as two keys.
+    ///
+    /// [`Key::Esc`]: enum.Key.html#variant.Esc
+    /// [`Terminal::set_esc_timeout`]: struct.Terminal.html#method.set_esc_timeout
     Meta(char),
 
     /// Meta/Alt with Ctrl key combination
@@ -75,6 +108,106 @@ pub enum Key {
     /// Appears after pasted text on some terminals
     PasteEnd,
 
+    /// Text pasted between [`Key::PasteStart`] and [`Key::PasteEnd`]
+    /// markers, collected together by [`Key::decode`], or by the
+    /// [`Terminal`] if the paste arrives slowly.  Escape
+    /// sequences within the pasted text are passed through verbatim,
+    /// not decoded as keys.  Invalid UTF-8 is replaced with U+FFFD.
+    ///
+    /// [`Key::PasteEnd`]: enum.Key.html#variant.PasteEnd
+    /// [`Key::PasteStart`]: enum.Key.html#variant.PasteStart
+    /// [`Key::decode`]: enum.Key.html#method.decode
+    /// [`Terminal`]: struct.Terminal.html
+    Paste(String),
+
+    /// Files dropped onto the terminal window.  No terminal sends a
+    /// structured sequence for this that is widely deployed, so
+    /// instead terminals paste the paths of the dropped files as
+    /// text.  If file drop detection is enabled with
+    /// [`Terminal::file_drop`], then a paste which consists entirely
+    /// of absolute paths or `file://` URIs is converted to this key
+    /// (see [`Key::paste_paths`]).  Otherwise drops arrive as a
+    /// [`Key::Paste`], or as plain keypresses on terminals without
+    /// bracketed paste.
+    ///
+    /// [`Key::Paste`]: enum.Key.html#variant.Paste
+    /// [`Key::paste_paths`]: enum.Key.html#method.paste_paths
+    /// [`Terminal::file_drop`]: struct.Terminal.html#method.file_drop
+    FileDrop(Vec<PathBuf>),
+
+    /// Mouse event, with 0-based coordinates.  This requires mouse
+    /// reporting to be enabled on the terminal.  `pressed` is false
+    /// for a button release.  Some encodings don't say which button
+    /// was released, in which case `button` is [`Button::None`].
+    /// `motion` is true if the mouse moved rather than a button
+    /// changing, which is only reported if motion tracking is
+    /// enabled.  In that case `button` is the button held down
+    /// during the drag, with `pressed` true, or else
+    /// [`Button::None`] with `pressed` false.
+    ///
+    /// [`Button::None`]: enum.Button.html#variant.None
+    Mouse {
+        button: Button,
+        row: i32,
+        col: i32,
+        pressed: bool,
+        motion: bool,
+        modifiers: Mods,
+    },
+
+    /// Navigation or function key with modifiers, as reported by
+    /// xterm and compatible terminals, e.g. `ESC [ 1 ; 5 D` for
+    /// Ctrl-Left.  The key is one of the plain keys [`Key::Up`],
+    /// [`Key::Down`], [`Key::Left`], [`Key::Right`], [`Key::Home`],
+    /// [`Key::End`], [`Key::PgUp`], [`Key::PgDn`], [`Key::Insert`],
+    /// [`Key::Delete`] or [`Key::F`].  Alt on its own is instead
+    /// reported with the `Meta*` variant of the key, e.g.
+    /// [`Key::MetaLeft`], the same as when Esc is used as a prefix.
+    /// So for example Ctrl-Shift-Up gives `Key::Modified(key, mods)`
+    /// with `*key == Key::Up` and `mods == Mods::CTRL | Mods::SHIFT`.
+    ///
+    /// [`Key::Delete`]: enum.Key.html#variant.Delete
+    /// [`Key::Down`]: enum.Key.html#variant.Down
+    /// [`Key::End`]: enum.Key.html#variant.End
+    /// [`Key::F`]: enum.Key.html#variant.F
+    /// [`Key::Home`]: enum.Key.html#variant.Home
+    /// [`Key::Insert`]: enum.Key.html#variant.Insert
+    /// [`Key::Left`]: enum.Key.html#variant.Left
+    /// [`Key::MetaLeft`]: enum.Key.html#variant.MetaLeft
+    /// [`Key::PgDn`]: enum.Key.html#variant.PgDn
+    /// [`Key::PgUp`]: enum.Key.html#variant.PgUp
+    /// [`Key::Right`]: enum.Key.html#variant.Right
+    /// [`Key::Up`]: enum.Key.html#variant.Up
+    Modified(Box<Key>, Mods),
+
+    /// Key event reported using the kitty keyboard protocol
+    /// (`ESC [ ... u`), which terminals only send once the app has
+    /// enabled it.  `code` is the Unicode codepoint of the key
+    /// without Shift applied, e.g. `'\t'` for Tab or `'\x1B'` for
+    /// Esc, or a private-use codepoint for keys that have no
+    /// character, as listed in the kitty documentation.  Modifiers
+    /// are reported separately, so Ctrl-I can be told apart from Tab.
+    /// If the terminal was asked to report key repeats and releases,
+    /// `event` says which this is.  Caps Lock and Num Lock states are
+    /// ignored, and Super, Hyper and Meta are all reported as
+    /// [`Mods::META`].
+    ///
+    /// [`Mods::META`]: struct.Mods.html#associatedconstant.META
+    Code {
+        code: char,
+        modifiers: Mods,
+        event: KeyEvent,
+    },
+
+    /// A complete CSI, SS3 or OSC escape sequence which isn't
+    /// recognised, with its raw bytes including the initial Esc.
+    /// This lets an app handle sequences that this crate doesn't
+    /// know about, or show them when debugging terminal quirks.
+    /// Since Esc followed by `]` starts an OSC sequence, Alt-`]` is
+    /// only recognised once no more input arrives within the Esc
+    /// timeout.
+    Unknown(Box<[u8]>),
+
     /// `Check` can be sent in a pause in typing, 300ms after the last
     /// keypress.  It's a good time to do field validation if that
     /// validation is expensive.  See [`Terminal::check`].
@@ -87,6 +220,102 @@ pub enum Key {
     Invalid,
 }
 
+/// Mouse button for a [`Key::Mouse`] event
+///
+/// [`Key::Mouse`]: enum.Key.html#variant.Mouse
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+
+    /// Extra buttons, numbered from 8 as in X11
+    Other(u8),
+
+    /// No button, e.g. a release where the encoding doesn't say
+    /// which button was released
+    None,
+}
+
+/// Type of event for a [`Key::Code`] keypress
+///
+/// [`Key::Code`]: enum.Key.html#variant.Code
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum KeyEvent {
+    Press,
+    Repeat,
+    Release,
+}
+
+/// Set of modifier keys held down
+///
+/// These may be combined with `|`, and tested with
+/// [`Mods::contains`], for example `mods.contains(Mods::CTRL |
+/// Mods::SHIFT)`.
+///
+/// [`Mods::contains`]: struct.Mods.html#method.contains
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Mods(u8);
+
+impl Mods {
+    /// No modifiers
+    pub const NONE: Mods = Mods(0);
+    /// Shift key
+    pub const SHIFT: Mods = Mods(1);
+    /// Alt key, which terminals often call Meta
+    pub const ALT: Mods = Mods(2);
+    /// Ctrl key
+    pub const CTRL: Mods = Mods(4);
+    /// Meta or Super key, where the terminal reports it separately
+    /// from Alt
+    pub const META: Mods = Mods(8);
+
+    /// Test whether all the modifiers in `other` are present
+    pub fn contains(self, other: Mods) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Test whether there are no modifiers
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl std::ops::BitOr for Mods {
+    type Output = Mods;
+    fn bitor(self, rhs: Mods) -> Mods {
+        Mods(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Mods {
+    fn bitor_assign(&mut self, rhs: Mods) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl fmt::Display for Mods {
+    /// Display using the same `M-`, `C-` and `S-` style of prefixes
+    /// as `Key`, with `Meta` shown as `W-`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (m, prefix) in [
+            (Mods::META, "W-"),
+            (Mods::ALT, "M-"),
+            (Mods::CTRL, "C-"),
+            (Mods::SHIFT, "S-"),
+        ] {
+            if self.contains(m) {
+                write!(f, "{}", prefix)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Debug for Key {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self)
@@ -94,8 +323,26 @@ impl fmt::Debug for Key {
 }
 
 impl fmt::Display for Key {
-    /// Convert to a display string, using `M-` and `C-` prefixes for
-    /// Meta and Ctrl.
+    /// Convert to a terse display string which can be parsed back
+    /// again with `FromStr`, e.g. for keybindings in a configuration
+    /// file.  The form is:
+    ///
+    /// - A character key as the character itself, e.g. `a` or `%`
+    /// - Other keys by the variant name, e.g. `PgUp` or `F5`
+    /// - Modifiers as prefixes: `M-` for Meta/Alt, `C-` for Ctrl,
+    ///   `S-` for Shift and `W-` for Meta/Super reported separately
+    ///   from Alt, e.g. `M-x`, `C-S-Up` or `M-C-F5`
+    /// - Kitty protocol key codes as `U+` and the hex codepoint,
+    ///   followed by `-Repeat` or `-Release` if not a press, e.g.
+    ///   `C-U+0069`
+    /// - Mouse events as `Mouse-` followed by button, `Press`,
+    ///   `Release` or `Move` and position, e.g.
+    ///   `S-Mouse-Left-Press@3,10`
+    /// - Pastes, file drops and unknown sequences with their contents
+    ///   in Rust debug form, e.g. `Paste("text")`
+    ///
+    /// Unknown sequences which aren't valid UTF-8 can't be converted
+    /// back exactly.
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Key::Pr(ch) => write!(f, "{}", ch),
@@ -134,77 +381,299 @@ impl fmt::Display for Key {
             Key::MetaEnd => write!(f, "M-End"),
             Key::PasteStart => write!(f, "PasteStart"),
             Key::PasteEnd => write!(f, "PasteEnd"),
+            Key::Paste(text) => write!(f, "Paste({:?})", text),
+            Key::FileDrop(paths) => write!(f, "FileDrop({:?})", paths),
+            Key::Mouse {
+                button,
+                row,
+                col,
+                pressed,
+                motion,
+                modifiers,
+            } => write!(
+                f,
+                "{}Mouse-{:?}-{}@{},{}",
+                modifiers,
+                button,
+                match (*motion, *pressed) {
+                    (true, _) => "Move",
+                    (false, true) => "Press",
+                    (false, false) => "Release",
+                },
+                row,
+                col
+            ),
+            Key::Modified(key, mods) => write!(f, "{}{}", mods, key),
+            Key::Code {
+                code,
+                modifiers,
+                event,
+            } => {
+                write!(f, "{}U+{:04X}", modifiers, u32::from(*code))?;
+                match event {
+                    KeyEvent::Press => Ok(()),
+                    KeyEvent::Repeat => write!(f, "-Repeat"),
+                    KeyEvent::Release => write!(f, "-Release"),
+                }
+            }
+            Key::Unknown(seq) => write!(f, "Unknown({:?})", String::from_utf8_lossy(seq)),
             Key::Check => write!(f, "Check"),
             Key::Invalid => write!(f, "Invalid"),
         }
     }
 }
 
-pub struct ParseKeyError {}
+/// Error from parsing a [`Key`] or [`Mods`] from a string
+///
+/// [`Key`]: enum.Key.html
+/// [`Mods`]: struct.Mods.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseKeyError {
+    input: String,
+    reason: &'static str,
+}
 
-impl FromStr for Key {
+impl ParseKeyError {
+    fn new<T>(input: &str, reason: &'static str) -> Result<T, Self> {
+        Err(Self {
+            input: input.to_string(),
+            reason,
+        })
+    }
+}
+
+impl fmt::Display for ParseKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid key {:?}: {}", self.input, self.reason)
+    }
+}
+
+impl std::error::Error for ParseKeyError {}
+
+impl FromStr for Mods {
     type Err = ParseKeyError;
 
-    /// Convert back from the `Display` representation of a key
-    fn from_str(mut s: &str) -> Result<Key, ParseKeyError> {
-        let meta = if s.starts_with("M-") {
-            s = s.split_at(2).1;
-            true
-        } else {
-            false
-        };
+    /// Convert back from the `Display` representation, a series of
+    /// `W-`, `M-`, `C-` and `S-` prefixes in any order.  An empty
+    /// string gives no modifiers.
+    fn from_str(s: &str) -> Result<Mods, ParseKeyError> {
+        let mut mods = Mods::NONE;
+        let mut rest = s;
+        while !rest.is_empty() {
+            match mod_prefix(rest) {
+                None => return ParseKeyError::new(s, "expecting W-, M-, C- or S-"),
+                Some(m) if mods.contains(m) => return ParseKeyError::new(s, "repeated modifier"),
+                Some(m) => mods |= m,
+            }
+            rest = &rest[2..];
+        }
+        Ok(mods)
+    }
+}
 
-        let ctrl = if s.starts_with("C-") {
-            s = s.split_at(2).1;
-            true
-        } else {
-            false
-        };
+impl FromStr for Key {
+    type Err = ParseKeyError;
 
-        let mut it = s.chars();
-        if let Some(ch) = it.next() {
-            if it.as_str().is_empty() {
-                return Ok(match (meta, ctrl) {
-                    (false, false) => Key::Pr(ch),
-                    (false, true) => Key::Ctrl(ch),
-                    (true, false) => Key::Meta(ch),
-                    (true, true) => Key::MetaCtrl(ch),
-                });
+    /// Convert back from the `Display` representation of a key.
+    /// Modifier prefixes are accepted in any order.  Where a key
+    /// could be represented in more than one way, the same form is
+    /// returned as [`Key::decode`] would give, so for example
+    /// `"M-Up"` always gives [`Key::MetaUp`], never
+    /// `Key::Modified(Key::Up, Mods::ALT)`.
+    ///
+    /// [`Key::MetaUp`]: enum.Key.html#variant.MetaUp
+    /// [`Key::decode`]: enum.Key.html#method.decode
+    fn from_str(s: &str) -> Result<Key, ParseKeyError> {
+        // Take modifier prefixes, leaving at least one character for
+        // the key itself, e.g. "C-" is not Ctrl with nothing
+        let mut mods = Mods::NONE;
+        let mut rest = s;
+        while rest.len() > 2 {
+            match mod_prefix(rest) {
+                None => break,
+                Some(m) if mods.contains(m) => return ParseKeyError::new(s, "repeated modifier"),
+                Some(m) => mods |= m,
             }
-            if ch == 'F' && !ctrl {
-                if let Ok(v) = it.as_str().parse::<u32>() {
-                    if v >= 1 && v <= 20 {
-                        return Ok(if meta { Key::MetaF(v) } else { Key::F(v) });
-                    }
-                }
+            rest = &rest[2..];
+        }
+        let no_mods = |key: Key| {
+            if mods.is_empty() {
+                Ok(key)
+            } else {
+                ParseKeyError::new(s, "modifiers not allowed with this key")
             }
+        };
+
+        let mut it = rest.chars();
+        if let (Some(ch), None) = (it.next(), it.next()) {
+            return match (mods.contains(Mods::ALT), mods.contains(Mods::CTRL)) {
+                _ if mods.contains(Mods::SHIFT) || mods.contains(Mods::META) => {
+                    ParseKeyError::new(s, "only M- and C- are allowed with a character")
+                }
+                (false, false) => Ok(Key::Pr(ch)),
+                (false, true) => Ok(Key::Ctrl(ch)),
+                (true, false) => Ok(Key::Meta(ch)),
+                (true, true) => Ok(Key::MetaCtrl(ch)),
+            };
         }
 
-        if !ctrl {
-            let keys = match s {
-                "Return" => (Key::Return, Key::MetaReturn),
-                "BackSp" => (Key::BackSp, Key::MetaBackSp),
-                "Esc" => (Key::Esc, Key::MetaEsc),
-                "Up" => (Key::Up, Key::MetaUp),
-                "Down" => (Key::Down, Key::MetaDown),
-                "Left" => (Key::Left, Key::MetaLeft),
-                "Right" => (Key::Right, Key::MetaRight),
-                "PgUp" => (Key::PgUp, Key::MetaPgUp),
-                "PgDn" => (Key::PgDn, Key::MetaPgDn),
-                "Home" => (Key::Home, Key::MetaHome),
-                "Insert" => (Key::Insert, Key::MetaInsert),
-                "Delete" => (Key::Delete, Key::MetaDelete),
-                "End" => (Key::End, Key::MetaEnd),
-                "PasteStart" if !meta => (Key::PasteStart, Key::PasteStart),
-                "PasteEnd" if !meta => (Key::PasteEnd, Key::PasteEnd),
-                "Check" if !meta => (Key::Check, Key::Check),
-                "Invalid" if !meta => (Key::Invalid, Key::Invalid),
-                _ => return Err(ParseKeyError {}),
+        if let Some(args) = rest.strip_prefix("Mouse-") {
+            return match parse_mouse(args, mods) {
+                Some(key) => Ok(key),
+                None => ParseKeyError::new(s, "bad mouse event"),
+            };
+        }
+        if let Some(args) = rest.strip_prefix("U+") {
+            return match parse_code(args, mods) {
+                Some(key) => Ok(key),
+                None => ParseKeyError::new(s, "bad Unicode key code"),
             };
-            return Ok(if meta { keys.1 } else { keys.0 });
         }
+        if let Some(args) = rest.strip_prefix("Paste(") {
+            return match unquote(args) {
+                Some((text, ")")) => no_mods(Key::Paste(text)),
+                _ => ParseKeyError::new(s, "bad quoted string"),
+            };
+        }
+        if let Some(args) = rest.strip_prefix("Unknown(") {
+            return match unquote(args) {
+                Some((text, ")")) => no_mods(Key::Unknown(text.into_bytes().into())),
+                _ => ParseKeyError::new(s, "bad quoted string"),
+            };
+        }
+        if let Some(mut args) = rest.strip_prefix("FileDrop([") {
+            let mut paths = Vec::new();
+            while let Some((path, more)) = unquote(args) {
+                paths.push(PathBuf::from(path));
+                args = more.strip_prefix(", ").unwrap_or(more);
+            }
+            return match args {
+                "])" => no_mods(Key::FileDrop(paths)),
+                _ => ParseKeyError::new(s, "bad list of paths"),
+            };
+        }
+
+        let key = match rest {
+            "Tab" => Key::Tab,
+            "Return" => Key::Return,
+            "BackSp" => Key::BackSp,
+            "Esc" => Key::Esc,
+            "Up" => Key::Up,
+            "Down" => Key::Down,
+            "Left" => Key::Left,
+            "Right" => Key::Right,
+            "PgUp" => Key::PgUp,
+            "PgDn" => Key::PgDn,
+            "Home" => Key::Home,
+            "Insert" => Key::Insert,
+            "Delete" => Key::Delete,
+            "End" => Key::End,
+            "PasteStart" => return no_mods(Key::PasteStart),
+            "PasteEnd" => return no_mods(Key::PasteEnd),
+            "Check" => return no_mods(Key::Check),
+            "Invalid" => return no_mods(Key::Invalid),
+            "" => return ParseKeyError::new(s, "empty key"),
+            _ => match rest.strip_prefix('F').map(str::parse::<u32>) {
+                Some(Ok(num)) if (1..=20).contains(&num) => Key::F(num),
+                Some(Ok(_)) => return ParseKeyError::new(s, "function key out of range"),
+                _ => return ParseKeyError::new(s, "unknown key name"),
+            },
+        };
+        Ok(key.with_mods(mods))
+    }
+}
+
+// Get the modifier for a `W-`, `M-`, `C-` or `S-` prefix
+fn mod_prefix(s: &str) -> Option<Mods> {
+    match s.as_bytes().get(..2)? {
+        b"W-" => Some(Mods::META),
+        b"M-" => Some(Mods::ALT),
+        b"C-" => Some(Mods::CTRL),
+        b"S-" => Some(Mods::SHIFT),
+        _ => None,
+    }
+}
+
+// Parse the rest of a mouse event after "Mouse-",
+// e.g. "Left-Press@3,4"
+fn parse_mouse(s: &str, modifiers: Mods) -> Option<Key> {
+    let (button, s) = s.split_once('-')?;
+    let (pressed, s) = s.split_once('@')?;
+    let (row, col) = s.split_once(',')?;
+    let button = match button {
+        "Left" => Button::Left,
+        "Middle" => Button::Middle,
+        "Right" => Button::Right,
+        "WheelUp" => Button::WheelUp,
+        "WheelDown" => Button::WheelDown,
+        "WheelLeft" => Button::WheelLeft,
+        "WheelRight" => Button::WheelRight,
+        "None" => Button::None,
+        _ => Button::Other(
+            button
+                .strip_prefix("Other(")?
+                .strip_suffix(')')?
+                .parse()
+                .ok()?,
+        ),
+    };
+    let (pressed, motion) = match pressed {
+        "Press" => (true, false),
+        "Release" => (false, false),
+        "Move" => (button != Button::None, true),
+        _ => return None,
+    };
+    Some(Key::Mouse {
+        button,
+        row: row.parse().ok()?,
+        col: col.parse().ok()?,
+        pressed,
+        motion,
+        modifiers,
+    })
+}
+
+// Parse the rest of a key code after "U+", e.g. "0061-Release"
+fn parse_code(s: &str, modifiers: Mods) -> Option<Key> {
+    let (hex, event) = match s.split_once('-') {
+        None => (s, KeyEvent::Press),
+        Some((hex, "Repeat")) => (hex, KeyEvent::Repeat),
+        Some((hex, "Release")) => (hex, KeyEvent::Release),
+        Some(_) => return None,
+    };
+    if hex.len() < 4 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(Key::Code {
+        code: char::from_u32(u32::from_str_radix(hex, 16).ok()?)?,
+        modifiers,
+        event,
+    })
+}
 
-        Err(ParseKeyError {})
+// Parse a double-quoted string at the start of `s` in the form
+// generated by `{:?}`, returning the string and whatever follows it
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let mut it = s.strip_prefix('"')?.chars();
+    let mut out = String::new();
+    loop {
+        match it.next()? {
+            '"' => return Some((out, it.as_str())),
+            '\\' => out.push(match it.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                'u' => {
+                    let rest = it.as_str().strip_prefix('{')?;
+                    let (hex, rest) = rest.split_once('}')?;
+                    it = rest.chars();
+                    char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+                }
+                ch => ch,
+            }),
+            ch => out.push(ch),
+        }
     }
 }
 
@@ -235,6 +704,9 @@ impl<'a> Scan<'a> {
     //fn rest<'b>(&self, data: &'b [u8]) -> &'b [u8] {
     //    &data[self.pos..]
     //}
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
     fn next(&mut self) -> Option<u8> {
         if self.pos < self.data.len() {
             self.pos += 1;
@@ -253,12 +725,14 @@ impl<'a> Scan<'a> {
     }
     fn grab_num(&mut self) -> Option<u32> {
         let pos0 = self.pos;
-        let mut val = 0;
+        let mut val: u32 = 0;
         while self.pos < self.data.len()
             && self.data[self.pos] >= b'0'
             && self.data[self.pos] <= b'9'
         {
-            val = val * 10 + u32::from(self.data[self.pos] - b'0');
+            val = val
+                .saturating_mul(10)
+                .saturating_add(u32::from(self.data[self.pos] - b'0'));
             self.pos += 1;
         }
         if self.pos > pos0 {
@@ -270,6 +744,22 @@ impl<'a> Scan<'a> {
 }
 
 impl Key {
+    // Apply modifiers reported by the terminal to a key.  Alt alone
+    // gives the `Meta*` key where there is one, as for a key
+    // prefixed with Esc.  Other combinations give `Key::Modified`.
+    // This includes the function keys, so that Alt-F5 is always
+    // `MetaF(5)` however the terminal reports it, and `M-F5` parses
+    // back to that same key.
+    fn with_mods(self, mods: Mods) -> Key {
+        if mods.is_empty() {
+            return self;
+        }
+        match self.meta() {
+            Some(key) if mods == Mods::ALT => key,
+            _ => Key::Modified(Box::new(self), mods),
+        }
+    }
+
     /// Add meta to a key if possible, otherwise return `None`
     pub fn meta(&self) -> Option<Self> {
         match self {
@@ -294,10 +784,141 @@ impl Key {
         }
     }
 
+    /// Interpret pasted text as a list of dropped files, if possible.
+    /// The text is split into words as by a shell, respecting single
+    /// and double quotes and backslash escapes, with newlines also
+    /// separating words as in a `text/uri-list`.  Every word must be
+    /// either an absolute path or a `file://` URI (which is
+    /// percent-decoded), otherwise `None` is returned.  The paths are
+    /// not checked against the filesystem.
+    pub fn paste_paths(text: &str) -> Option<Vec<PathBuf>> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut it = text.chars();
+        while let Some(ch) = it.next() {
+            match ch {
+                ' ' | '\t' | '\r' | '\n' => words.extend(word.take()),
+                '\'' | '"' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match it.next() {
+                            None => return None,
+                            Some(c) if c == ch => break,
+                            Some('\\') if ch == '"' => word.push(it.next()?),
+                            Some(c) => word.push(c),
+                        }
+                    }
+                }
+                '\\' => word.get_or_insert_with(String::new).push(it.next()?),
+                _ => word.get_or_insert_with(String::new).push(ch),
+            }
+        }
+        words.extend(word);
+        if words.is_empty() {
+            return None;
+        }
+        words.iter().map(|w| file_path(w)).collect()
+    }
+
+    /// Describe the key in a friendly form suitable for showing to
+    /// the user, for example in a help screen listing keybindings:
+    /// "Page Up", "Ctrl-C", "Alt-X", "F5" and so on.  This is
+    /// separate from the terse `Display` form, which is intended to
+    /// be parsed back again, e.g. from a configuration file.
+    pub fn describe(&self) -> String {
+        match self {
+            Key::Pr(' ') => "Space".into(),
+            Key::Pr(ch) => ch.to_string(),
+            Key::Ctrl(ch) => format!("Ctrl-{}", ch),
+            Key::F(num) => format!("F{}", num),
+            Key::Meta(' ') => "Alt-Space".into(),
+            Key::Meta(ch) => format!("Alt-{}", ch),
+            Key::MetaCtrl(ch) => format!("Ctrl-Alt-{}", ch),
+            Key::MetaF(num) => format!("Alt-F{}", num),
+            Key::PasteStart => "Paste Start".into(),
+            Key::PasteEnd => "Paste End".into(),
+            Key::Paste(_) => "Paste".into(),
+            Key::FileDrop(_) => "File Drop".into(),
+            Key::Mouse { .. } => "Mouse".into(),
+            Key::Modified(key, mods) => format!("{}{}", describe_mods(*mods), key.describe()),
+            Key::Code {
+                code, modifiers, ..
+            } => {
+                let mut out = describe_mods(*modifiers);
+                match Key::decode(code.encode_utf8(&mut [0; 4]).as_bytes(), true) {
+                    Some((_, Key::Pr(' '))) => out.push_str("Space"),
+                    Some((_, Key::Pr(ch))) => out.push(ch),
+                    Some((_, key @ (Key::Tab | Key::Return | Key::BackSp | Key::Esc))) => {
+                        out.push_str(key.name())
+                    }
+                    _ => out.push_str(&format!("U+{:04X}", u32::from(*code))),
+                }
+                out
+            }
+            Key::Unknown(_) => "Unknown".into(),
+            Key::Check => "Check".into(),
+            Key::Invalid => "Invalid".into(),
+            key => match key.unmeta() {
+                Some(base) => format!("Alt-{}", base.name()),
+                None => key.name().into(),
+            },
+        }
+    }
+
+    // Friendly name of a plain named key
+    fn name(&self) -> &'static str {
+        match self {
+            Key::Tab => "Tab",
+            Key::Return => "Return",
+            Key::BackSp => "Backspace",
+            Key::Esc => "Esc",
+            Key::Up => "Up",
+            Key::Down => "Down",
+            Key::Left => "Left",
+            Key::Right => "Right",
+            Key::PgUp => "Page Up",
+            Key::PgDn => "Page Down",
+            Key::Home => "Home",
+            Key::Insert => "Insert",
+            Key::Delete => "Delete",
+            Key::End => "End",
+            _ => "?",
+        }
+    }
+
+    // Map a named Meta key back to its plain key
+    fn unmeta(&self) -> Option<Key> {
+        Some(match self {
+            Key::MetaTab => Key::Tab,
+            Key::MetaReturn => Key::Return,
+            Key::MetaBackSp => Key::BackSp,
+            Key::MetaEsc => Key::Esc,
+            Key::MetaUp => Key::Up,
+            Key::MetaDown => Key::Down,
+            Key::MetaLeft => Key::Left,
+            Key::MetaRight => Key::Right,
+            Key::MetaPgUp => Key::PgUp,
+            Key::MetaPgDn => Key::PgDn,
+            Key::MetaHome => Key::Home,
+            Key::MetaInsert => Key::Insert,
+            Key::MetaDelete => Key::Delete,
+            Key::MetaEnd => Key::End,
+            _ => return None,
+        })
+    }
+
     /// Attempt to decode a keypress from data received from the
     /// terminal.  Will not decode a partial sequence at the end of
     /// the buffer unless `force` is set.  Returns count of bytes
     /// consumed and the decoded key, or else `None`.
+    ///
+    /// A bracketed paste is returned as a single [`Key::Paste`] if the
+    /// end marker is already in the buffer.  Otherwise
+    /// [`Key::PasteStart`] is returned and the caller must collect the
+    /// rest of the paste itself, up to the `ESC [ 201 ~` end marker.
+    ///
+    /// [`Key::PasteStart`]: enum.Key.html#variant.PasteStart
+    /// [`Key::Paste`]: enum.Key.html#variant.Paste
     pub fn decode(data: &[u8], force: bool) -> Option<(usize, Key)> {
         let mut sc = Scan::new(data);
         let key = if sc.grab(27) {
@@ -309,10 +930,7 @@ impl Key {
         } else {
             Self::decode_bare(&mut sc, force)
         };
-        match key {
-            Some(key) => Some((sc.pos, key)),
-            None => None,
-        }
+        key.map(|key| (sc.pos, key))
     }
 
     // The expressions in these functions should either result in a
@@ -362,15 +980,31 @@ impl Key {
         } else if sc.grab(b'O') {
             return Self::decode_esc_o(sc, force);
         } else if sc.grab(b'[') {
-            return Self::decode_esc_bracket(sc, force);
+            let mark = sc.pos;
+            match Self::decode_esc_bracket(sc, force) {
+                Some(Key::Meta('[')) => match Self::skip_csi(sc) {
+                    Some(true) => Key::Unknown(sc.data[mark - 2..sc.pos].into()),
+                    None if !force => return None, // Wait for more
+                    _ => {
+                        sc.pos = mark;
+                        Key::Meta('[')
+                    }
+                },
+                key => return key,
+            }
+        } else if sc.grab(b']') {
+            return Self::decode_osc(sc, force);
         } else {
             // Something other than 'O' or '[': See if it's Meta-(bare)
             let mark = sc.pos;
-            if let Some(key) = Self::decode_bare(sc, force).and_then(|k| k.meta()) {
-                key
-            } else {
-                sc.pos = mark;
-                Key::Esc
+            match Self::decode_bare(sc, force).map(|k| k.meta()) {
+                Some(Some(key)) => key,
+                // Partial UTF-8 sequence, so wait for the rest
+                None if !force => return None,
+                _ => {
+                    sc.pos = mark;
+                    Key::Esc
+                }
             }
         })
     }
@@ -382,6 +1016,7 @@ impl Key {
             Some(b'Q') => Key::F(2),
             Some(b'R') => Key::F(3),
             Some(b'S') => Key::F(4),
+            Some(0x40..=0x7E) => Key::Unknown(sc.data[mark - 2..sc.pos].into()),
             None if !force => return None, // Wait for more
             _ => {
                 sc.pos = mark;
@@ -397,6 +1032,8 @@ impl Key {
             Some(b'B') => Key::Down,
             Some(b'C') => Key::Right,
             Some(b'D') => Key::Left,
+            Some(b'H') => Key::Home,
+            Some(b'F') => Key::End,
             Some(b'[') => match sc.next() {
                 Some(b'A') => Key::F(1),
                 Some(b'B') => Key::F(2),
@@ -409,13 +1046,59 @@ impl Key {
                     Key::Meta('[')
                 }
             },
+            Some(b'M') => {
+                // X10/normal mouse report: ESC [ M Cb Cx Cy, with each
+                // byte offset by 32.  Coordinates above 223 can't be
+                // represented, and xterm sends 0 for those, so a
+                // coordinate byte below 32 is taken as the maximum.
+                let coord = |b: u8| u32::from(if b < 32 { 255 } else { b }) - 32;
+                match sc.take(3) {
+                    Some(&[cb, cx, cy]) if cb >= 32 => {
+                        Self::mouse(u32::from(cb - 32), coord(cx), coord(cy), None)
+                    }
+                    Some(_) => Key::Invalid,
+                    None if !force => return None, // Wait for more
+                    None => {
+                        sc.pos = mark;
+                        Key::Meta('[')
+                    }
+                }
+            }
+            Some(b'<') => {
+                // SGR mouse report: ESC [ < Cb ; Cx ; Cy M, with
+                // final `m` instead for a release
+                let cb = sc.grab_num();
+                let col = if sc.grab(b';') { sc.grab_num() } else { None };
+                let row = if sc.grab(b';') { sc.grab_num() } else { None };
+                let pressed = if sc.grab(b'M') {
+                    Some(true)
+                } else if sc.grab(b'm') {
+                    Some(false)
+                } else {
+                    None
+                };
+                match (cb, col, row, pressed) {
+                    (Some(cb), Some(col), Some(row), Some(_)) => Self::mouse(cb, col, row, pressed),
+                    _ if sc.is_empty() && !force => return None, // Wait for more
+                    _ => {
+                        sc.pos = mark;
+                        Key::Meta('[')
+                    }
+                }
+            }
             Some(b'0'..=b'9') => {
                 sc.pos -= 1;
+                let start = sc.pos;
+                match Self::decode_csi_u(sc) {
+                    Some(key) => return Some(key),
+                    None if sc.is_empty() && !force => return None, // Wait for more
+                    None => sc.pos = start,
+                }
                 let num = sc.grab_num().unwrap();
-                let mut _modf = None;
+                let mut modf = None;
                 if sc.grab(b';') {
-                    _modf = sc.grab_num();
-                    if _modf.is_none() && sc.is_empty() {
+                    modf = sc.grab_num();
+                    if modf.is_none() && sc.is_empty() {
                         if !force {
                             return None; // Wait for more
                         }
@@ -425,9 +1108,43 @@ impl Key {
                     // If there's no number there, then just
                     // ignore the spurious ';'
                 }
+                if let (Some(col), true) = (modf, sc.grab(b';')) {
+                    // urxvt mouse report: ESC [ Cb ; Cx ; Cy M
+                    match sc.grab_num() {
+                        Some(row) if sc.grab(b'M') && num >= 32 => {
+                            return Some(Self::mouse(num - 32, col, row, None));
+                        }
+                        _ if sc.is_empty() && !force => return None, // Wait for more
+                        _ => {
+                            sc.pos = mark;
+                            return Some(Key::Meta('['));
+                        }
+                    }
+                }
+                // xterm modifier parameter, e.g. ESC [ 1 ; 5 D for Ctrl-Left
+                let mods = Mods((modf.unwrap_or(1).saturating_sub(1) & 15) as u8);
+                if modf.is_some() && num == 1 {
+                    let key = match sc.next() {
+                        Some(b'A') => Key::Up,
+                        Some(b'B') => Key::Down,
+                        Some(b'C') => Key::Right,
+                        Some(b'D') => Key::Left,
+                        Some(b'H') => Key::Home,
+                        Some(b'F') => Key::End,
+                        Some(b'P') => Key::F(1),
+                        Some(b'Q') => Key::F(2),
+                        Some(b'R') => Key::F(3),
+                        Some(b'S') => Key::F(4),
+                        None if !force => return None, // Wait for more
+                        _ => {
+                            sc.pos = mark;
+                            return Some(Key::Meta('['));
+                        }
+                    };
+                    return Some(key.with_mods(mods));
+                }
                 if sc.grab(b'~') {
-                    // TODO: Take account of the modifier value `modf`?  Ignore for now
-                    match num {
+                    let key = match num {
                         1 => Key::Home,
                         2 => Key::Insert,
                         3 => Key::Delete,
@@ -439,10 +1156,11 @@ impl Key {
                         23..=26 => Key::F(num - 12),
                         28..=29 => Key::F(num - 13),
                         31..=34 => Key::F(num - 14),
-                        200 => Key::PasteStart,
+                        200 => return Some(Self::decode_paste(sc)),
                         201 => Key::PasteEnd,
-                        _ => Key::Invalid,
-                    }
+                        _ => return Some(Key::Unknown(sc.data[mark - 2..sc.pos].into())),
+                    };
+                    key.with_mods(mods)
                 } else if sc.is_empty() && !force {
                     return None; // Wait for more
                 } else {
@@ -459,6 +1177,150 @@ impl Key {
         })
     }
 
+    // Kitty keyboard protocol: ESC [ code[:alternates] ; mods[:event]
+    // ; text u.  Returns `None` if this isn't that form, in which
+    // case the caller goes back and tries the legacy forms.
+    fn decode_csi_u(sc: &mut Scan<'_>) -> Option<Key> {
+        let code = sc.grab_num()?;
+        while sc.grab(b':') {
+            sc.grab_num();
+        }
+        let mut mods = 1;
+        let mut event = 1;
+        if sc.grab(b';') {
+            mods = sc.grab_num().unwrap_or(1);
+            if sc.grab(b':') {
+                event = sc.grab_num()?;
+            }
+            if sc.grab(b';') {
+                // Skip the associated text, which the app can get
+                // from the codepoint and modifiers
+                sc.grab_num();
+                while sc.grab(b':') {
+                    sc.grab_num();
+                }
+            }
+        }
+        if !sc.grab(b'u') {
+            return None;
+        }
+        let m = mods.saturating_sub(1);
+        let mut modifiers = Mods((m & 7) as u8);
+        if m & 56 != 0 {
+            modifiers |= Mods::META;
+        }
+        let event = match event {
+            2 => KeyEvent::Repeat,
+            3 => KeyEvent::Release,
+            _ => KeyEvent::Press,
+        };
+        Some(match char::from_u32(code) {
+            Some(code) => Key::Code {
+                code,
+                modifiers,
+                event,
+            },
+            None => Key::Invalid,
+        })
+    }
+
+    // Skip over a complete CSI sequence from just after the `[`:
+    // parameter bytes, intermediate bytes, then a final byte.  Returns
+    // false if it's malformed, or `None` if it's incomplete.
+    fn skip_csi(sc: &mut Scan<'_>) -> Option<bool> {
+        while let Some(0x30..=0x3F) = sc.peek() {
+            sc.pos += 1;
+        }
+        while let Some(0x20..=0x2F) = sc.peek() {
+            sc.pos += 1;
+        }
+        sc.next().map(|b| (0x40..=0x7E).contains(&b))
+    }
+
+    // OSC sequence, from just after the `]`, terminated by BEL or
+    // ST.  These aren't keys, so just pass them on as unknown.
+    fn decode_osc(sc: &mut Scan<'_>, force: bool) -> Option<Key> {
+        let mark = sc.pos;
+        let rest = &sc.data[mark..];
+        let end = rest
+            .iter()
+            .enumerate()
+            .find(|(i, &b)| b == 7 || (b == 27 && rest.get(i + 1) == Some(&b'\\')))
+            .map(|(i, &b)| if b == 7 { i + 1 } else { i + 2 });
+        Some(match end {
+            Some(len) => {
+                sc.pos += len;
+                Key::Unknown(sc.data[mark - 2..sc.pos].into())
+            }
+            None if !force => return None, // Wait for more
+            None => Key::Meta(']'),
+        })
+    }
+
+    // Return the text of a bracketed paste if the end marker is
+    // already in the buffer.  Otherwise return `PasteStart` straight
+    // away, without waiting, and leave the caller to collect the text
+    // as it arrives.  Waiting here would mean searching the whole
+    // paste again on every read.
+    fn decode_paste(sc: &mut Scan<'_>) -> Key {
+        let rest = &sc.data[sc.pos..];
+        match find_paste_end(rest) {
+            Ok(i) => {
+                sc.pos += i + PASTE_END.len();
+                Key::Paste(String::from_utf8_lossy(&rest[..i]).into_owned())
+            }
+            Err(_) => Key::PasteStart,
+        }
+    }
+
+    // Make a mouse event from the button code `cb` and 1-based
+    // coordinates.  The button code has bits 0-1 for the button (3
+    // meaning release in the older encodings), 2-4 for the modifiers,
+    // 5 for motion, 6 for the wheel and 7 for the extra buttons.
+    // `pressed` is given for encodings that say so separately, but a
+    // motion report is a press if a button is held, whatever the
+    // encoding says.
+    fn mouse(cb: u32, col: u32, row: u32, pressed: Option<bool>) -> Key {
+        let low = (cb & 3) as u8;
+        let (button, press) = match (cb & 0xC0, low) {
+            (0, 3) => (Button::None, false),
+            (0, _) => (
+                [Button::Left, Button::Middle, Button::Right][usize::from(low)],
+                true,
+            ),
+            (0x40, _) => (
+                [
+                    Button::WheelUp,
+                    Button::WheelDown,
+                    Button::WheelLeft,
+                    Button::WheelRight,
+                ][usize::from(low)],
+                true,
+            ),
+            _ => (Button::Other(8 + low), true),
+        };
+        let mut modifiers = Mods::NONE;
+        for (bit, m) in [(4, Mods::SHIFT), (8, Mods::ALT), (16, Mods::CTRL)] {
+            if cb & bit != 0 {
+                modifiers |= m;
+            }
+        }
+        let coord = |v: u32| v.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let motion = cb & 0x20 != 0;
+        Key::Mouse {
+            button,
+            row: coord(row),
+            col: coord(col),
+            pressed: if motion {
+                button != Button::None
+            } else {
+                pressed.unwrap_or(press)
+            },
+            motion,
+            modifiers,
+        }
+    }
+
     fn decode_esc_esc(sc: &mut Scan<'_>, force: bool) -> Option<Key> {
         let mark = sc.pos;
         Some(if sc.is_empty() {
@@ -474,3 +1336,322 @@ impl Key {
         })
     }
 }
+
+// Friendly prefix for a set of modifiers, e.g. "Ctrl-Shift-"
+fn describe_mods(mods: Mods) -> String {
+    let mut out = String::new();
+    for (m, prefix) in [
+        (Mods::CTRL, "Ctrl-"),
+        (Mods::ALT, "Alt-"),
+        (Mods::META, "Super-"),
+        (Mods::SHIFT, "Shift-"),
+    ] {
+        if mods.contains(m) {
+            out.push_str(prefix);
+        }
+    }
+    out
+}
+
+// Convert an absolute path or `file://` URI to a path
+fn file_path(word: &str) -> Option<PathBuf> {
+    let path = match word.strip_prefix("file://") {
+        None => word.to_string(),
+        Some(rest) => {
+            // Skip the host part, which should be empty or local
+            let rest = &rest[rest.find('/')?..];
+            let mut out = Vec::new();
+            let mut bytes = rest.bytes();
+            while let Some(b) = bytes.next() {
+                if b == b'%' {
+                    let hi = (bytes.next()? as char).to_digit(16)?;
+                    let lo = (bytes.next()? as char).to_digit(16)?;
+                    out.push((hi * 16 + lo) as u8);
+                } else {
+                    out.push(b);
+                }
+            }
+            String::from_utf8(out).ok()?
+        }
+    };
+    if path.starts_with('/') {
+        Some(PathBuf::from(path))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode and show the byte count and key in display form
+    fn k(data: &[u8], force: bool) -> Option<String> {
+        Key::decode(data, force).map(|(n, k)| format!("{n}:{k}"))
+    }
+
+    #[test]
+    fn urxvt_mouse() {
+        assert_eq!(
+            k(b"\x1B[32;10;5M", false).as_deref(),
+            Some("10:Mouse-Left-Press@4,9")
+        );
+        assert_eq!(
+            k(b"\x1B[35;1;1M", false).as_deref(),
+            Some("9:Mouse-None-Release@0,0")
+        );
+        assert_eq!(
+            k(b"\x1B[96;1;1Mx", false).as_deref(),
+            Some("9:Mouse-WheelUp-Press@0,0")
+        );
+        assert_eq!(
+            k(b"\x1B[50;99999999999;2M", false).as_deref(),
+            Some("19:C-Mouse-Right-Press@1,2147483647")
+        );
+        assert_eq!(k(b"\x1B[32;10;5", false), None);
+        assert_eq!(k(b"\x1B[32;10;", false), None);
+        assert_eq!(k(b"\x1B[5;2~", false).as_deref(), Some("6:S-PgUp"));
+
+        // Motion, with and without a button held
+        assert_eq!(
+            k(b"\x1B[64;3;4M", false).as_deref(),
+            Some("9:Mouse-Left-Move@3,2")
+        );
+        assert_eq!(
+            k(b"\x1B[67;3;4M", false).as_deref(),
+            Some("9:Mouse-None-Move@3,2")
+        );
+    }
+
+    #[test]
+    fn mouse_motion() {
+        let key = Key::decode(b"\x1B[<32;3;4M", false).map(|v| v.1);
+        assert_eq!(
+            key,
+            Some(Key::Mouse {
+                button: Button::Left,
+                row: 3,
+                col: 2,
+                pressed: true,
+                motion: true,
+                modifiers: Mods::NONE,
+            })
+        );
+        for s in ["Mouse-Left-Move@3,2", "M-Mouse-None-Move@0,0"] {
+            assert_eq!(s.parse::<Key>().unwrap().to_string(), s);
+        }
+        let key: Key = "Mouse-None-Move@0,0".parse().unwrap();
+        assert!(matches!(
+            key,
+            Key::Mouse {
+                pressed: false,
+                motion: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn sgr_mouse() {
+        assert_eq!(
+            k(b"\x1B[<0;10;5M", false).as_deref(),
+            Some("10:Mouse-Left-Press@4,9")
+        );
+        assert_eq!(
+            k(b"\x1B[<2;1;1m", false).as_deref(),
+            Some("9:Mouse-Right-Release@0,0")
+        );
+        assert_eq!(
+            k(b"\x1B[<20;3;4M", false).as_deref(),
+            Some("10:C-S-Mouse-Left-Press@3,2")
+        );
+        assert_eq!(
+            k(b"\x1B[<64;3;4M", false).as_deref(),
+            Some("10:Mouse-WheelUp-Press@3,2")
+        );
+        assert_eq!(
+            k(b"\x1B[<32;3;4M", false).as_deref(),
+            Some("10:Mouse-Left-Move@3,2")
+        );
+        assert_eq!(
+            k(b"\x1B[<35;3;4M", false).as_deref(),
+            Some("10:Mouse-None-Move@3,2")
+        );
+        assert_eq!(k(b"\x1B[<0;10;5", false), None);
+        assert_eq!(k(b"\x1B[<0;10;", false), None);
+        assert_eq!(k(b"\x1B[<", false), None);
+        assert_eq!(k(b"\x1B[<0;10;5", true).as_deref(), Some("2:M-["));
+        assert_eq!(
+            k(b"\x1B[<0;99999999999;5M", false).as_deref(),
+            Some("19:Mouse-Left-Press@4,2147483647")
+        );
+    }
+
+    #[test]
+    fn x10_mouse() {
+        assert_eq!(
+            k(b"\x1B[M *%", false).as_deref(),
+            Some("6:Mouse-Left-Press@4,9")
+        );
+        assert_eq!(
+            k(b"\x1B[M#!!", false).as_deref(),
+            Some("6:Mouse-None-Release@0,0")
+        );
+        assert_eq!(
+            k(b"\x1B[M`!!", false).as_deref(),
+            Some("6:Mouse-WheelUp-Press@0,0")
+        );
+        assert_eq!(
+            k(b"\x1B[M@!!", false).as_deref(),
+            Some("6:Mouse-Left-Move@0,0")
+        );
+        assert_eq!(
+            k(b"\x1B[MC!!", false).as_deref(),
+            Some("6:Mouse-None-Move@0,0")
+        );
+        assert_eq!(
+            k(b"\x1B[M \xFF\x00", false).as_deref(),
+            Some("6:Mouse-Left-Press@222,222")
+        );
+        assert_eq!(k(b"\x1B[M\x05!!", false).as_deref(), Some("6:Invalid"));
+        assert_eq!(k(b"\x1B[M *", false), None);
+        assert_eq!(k(b"\x1B[M", false), None);
+        assert_eq!(
+            k(b"\x1B[M *", true).as_deref(),
+            Some("3:Unknown(\"\\u{1b}[M\")")
+        );
+    }
+
+    #[test]
+    fn paste() {
+        assert_eq!(
+            Key::decode(b"\x1B[200~a\x1B[Ab\xFF\x1B[201~c", false),
+            Some((18, Key::Paste("a\x1B[Ab\u{FFFD}".into())))
+        );
+        // Without the end marker, the caller collects the rest
+        assert_eq!(
+            Key::decode(b"\x1B[200~abc\x1B[20", false),
+            Some((6, Key::PasteStart))
+        );
+        assert_eq!(find_paste_end(b"abc\x1B[201~d"), Ok(3));
+        assert_eq!(find_paste_end(b"abc\x1B[20"), Err(3));
+        assert_eq!(find_paste_end(b"abc\x1B[2"), Err(3));
+        assert_eq!(find_paste_end(b"abc\x1B[22"), Err(7));
+        assert_eq!(find_paste_end(b""), Err(0));
+    }
+
+    #[test]
+    fn csi_u() {
+        assert_eq!(k(b"\x1B[105;5u", false).as_deref(), Some("8:C-U+0069"));
+        assert_eq!(k(b"\x1B[9u", false).as_deref(), Some("4:U+0009"));
+        assert_eq!(
+            k(b"\x1B[97:65;2:3u", false).as_deref(),
+            Some("12:S-U+0061-Release")
+        );
+        assert_eq!(
+            k(b"\x1B[97;1:2;97u", false).as_deref(),
+            Some("12:U+0061-Repeat")
+        );
+        assert_eq!(k(b"\x1B[97;9u", false).as_deref(), Some("7:W-U+0061"));
+        assert_eq!(k(b"\x1B[97;5", false), None);
+        assert_eq!(k(b"\x1B[97:", false), None);
+        assert_eq!(k(b"\x1B[55296u", false).as_deref(), Some("8:Invalid"));
+        assert_eq!(k(b"\x1B[97;5", true).as_deref(), Some("2:M-["));
+        let (_, key) = Key::decode(b"\x1B[105;7u", false).unwrap();
+        assert_eq!(key.describe(), "Ctrl-Alt-i");
+        let (_, key) = Key::decode(b"\x1B[9;5u", false).unwrap();
+        assert_eq!(key.describe(), "Ctrl-Tab");
+    }
+
+    #[test]
+    fn modified_tilde_keys() {
+        assert_eq!(k(b"\x1B[15;5~", false).as_deref(), Some("7:C-F5"));
+        assert_eq!(k(b"\x1B[24;2~", false).as_deref(), Some("7:S-F12"));
+        assert_eq!(k(b"\x1B[15;3~", false).as_deref(), Some("7:M-F5"));
+        assert_eq!(k(b"\x1B[15~", false).as_deref(), Some("5:F5"));
+        assert_eq!(k(b"\x1B[3;5~", false).as_deref(), Some("6:C-Delete"));
+        assert_eq!(
+            Key::decode(b"\x1B[15;5~", false),
+            Some((7, Key::Modified(Box::new(Key::F(5)), Mods::CTRL)))
+        );
+        assert_eq!(
+            Key::decode(b"\x1B[99;5~", false),
+            Some((7, Key::Unknown(b"\x1B[99;5~"[..].into())))
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let plain = || {
+            [
+                Key::Pr('a'),
+                Key::Pr('-'),
+                Key::Pr(' '),
+                Key::Ctrl('x'),
+                Key::F(5),
+                Key::Tab,
+                Key::Return,
+                Key::BackSp,
+                Key::Esc,
+                Key::Up,
+                Key::Down,
+                Key::Left,
+                Key::Right,
+                Key::PgUp,
+                Key::PgDn,
+                Key::Home,
+                Key::Insert,
+                Key::Delete,
+                Key::End,
+            ]
+        };
+        let mut keys = Vec::from(plain());
+        keys.extend(plain().iter().filter_map(Key::meta));
+        for mods in [Mods::CTRL, Mods::SHIFT | Mods::ALT, Mods::META] {
+            for key in Vec::from(plain()) {
+                if let Key::F(_) | Key::Up | Key::Delete = key {
+                    keys.push(Key::Modified(Box::new(key), mods));
+                }
+            }
+        }
+        keys.extend([
+            Key::Check,
+            Key::PasteStart,
+            Key::PasteEnd,
+            Key::Paste("a\x1B[A\n\"b".into()),
+            Key::FileDrop(vec!["/tmp/a b".into(), "c".into()]),
+            Key::Unknown(b"\x1B[99;5~"[..].into()),
+        ]);
+        for (button, pressed, motion) in [
+            (Button::Left, true, false),
+            (Button::Other(9), false, false),
+            (Button::None, false, false),
+            (Button::Right, true, true),
+            (Button::None, false, true),
+        ] {
+            keys.push(Key::Mouse {
+                button,
+                row: 3,
+                col: 10,
+                pressed,
+                motion,
+                modifiers: Mods::SHIFT | Mods::CTRL,
+            });
+        }
+        for event in [KeyEvent::Press, KeyEvent::Repeat, KeyEvent::Release] {
+            keys.push(Key::Code {
+                code: 'i',
+                modifiers: Mods::CTRL,
+                event,
+            });
+        }
+        for key in keys {
+            let s = key.to_string();
+            assert_eq!(s.parse::<Key>(), Ok(key), "{s}");
+        }
+        // Alt alone always gives the `Meta*` form, including for F keys
+        assert_eq!("M-F5".parse::<Key>(), Ok(Key::MetaF(5)));
+        assert_eq!(k(b"\x1B[15;3~", false).as_deref(), Some("7:M-F5"));
+        assert_eq!(k(b"\x1B[1;3P", false).as_deref(), Some("6:M-F1"));
+    }
+}
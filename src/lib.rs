@@ -38,13 +38,19 @@
 
 #![deny(rust_2018_idioms)]
 
+mod attr;
+mod cleanup;
 mod key;
+mod reply;
 mod terminal;
+mod terminfo;
 mod termout;
+mod width;
 
-pub use key::Key;
-pub use terminal::Terminal;
-pub use termout::{Features, TermOut};
+pub use attr::{Attr, Colour, Palette};
+pub use key::{Button, Key, KeyEvent, Mods, ParseKeyError};
+pub use terminal::{InputFilter, ModeHandle, Terminal};
+pub use termout::{Features, FrameReset, ImageOpts, Mark, SizeSource, TermOut, TermState};
 
 #[cfg(unix)]
 mod os_mio_unix;
@@ -57,4 +63,26 @@ std::compile_error!("OS interface not yet implemented on this platform");
 #[cfg(feature = "unstable")]
 mod page;
 #[cfg(feature = "unstable")]
-pub use page::{Page, Region};
+mod status;
+#[cfg(feature = "unstable")]
+pub use page::{hfb_char, layout, push_hfb, Constraint, Page, Region, BOX_DRAWING_FALLBACK};
+#[cfg(feature = "unstable")]
+pub use status::{StatusBar, StatusLine};
+#[cfg(feature = "unstable")]
+mod textinput;
+#[cfg(feature = "unstable")]
+pub use textinput::TextInput;
+#[cfg(feature = "unstable")]
+mod textview;
+#[cfg(feature = "unstable")]
+pub use textview::TextView;
+
+#[cfg(feature = "image")]
+mod image;
+#[cfg(feature = "image")]
+pub use image::ImageBuffer;
+
+#[cfg(feature = "testing")]
+mod testing;
+#[cfg(feature = "testing")]
+pub use testing::TestTerminal;
@@ -1,6 +1,9 @@
-//! Handle WINCH through a UNIX signal forwarded through a pipe to
-//! MIO.  Dump output data straight to stdout FD, with a blocking
-//! call.  This will block the whole thread if the TTY is in XOFF.
+//! Handle WINCH, TSTP and CONT through UNIX signals forwarded
+//! through pipes to MIO.  The terminal is accessed either through
+//! stdin and stdout, or through `/dev/tty`.  Output data is written
+//! in non-blocking mode.  Whatever the OS doesn't accept immediately,
+//! e.g. if the TTY is in XOFF, is queued and written out later when
+//! MIO reports that the output FD is writable.
 
 use crate::terminal::Terminal;
 use libc::{c_int, c_ushort, c_void, ioctl, size_t, TIOCGWINSZ};
@@ -8,9 +11,12 @@ use signal_hook::SigId;
 use stakker::{call, fwd_do, Actor, Core};
 use stakker_mio::mio::Interest;
 use stakker_mio::{FdSource, MioPoll, MioSource};
+use std::fs::{File, OpenOptions};
 use std::io::{Error, ErrorKind, Result};
 use std::mem;
 use std::os::unix::io::AsRawFd;
+#[cfg(test)]
+use std::os::unix::io::FromRawFd;
 use std::os::unix::net::UnixStream;
 
 #[repr(C)]
@@ -22,51 +28,126 @@ struct WinSize {
     ypixel: c_ushort,
 }
 
+/// Standalone terminal cleanup function, safe to call from a panic
+/// handler
+pub type CleanupFn = Box<dyn Fn(&[u8]) + Send + Sync + 'static>;
+
 pub struct Glue {
     poll: MioPoll,
     term: Actor<Terminal>,
-    _read: UnixStream,
-    _winch_src: MioSource<FdSource>,
-    stdin_src: Option<MioSource<FdSource>>,
-    sigid: SigId,
+    _signals: Vec<(UnixStream, MioSource<FdSource>)>,
+    in_src: Option<MioSource<FdSource>>,
+    out_src: Option<MioSource<FdSource>>,
+    // Data waiting for the output FD to become writable
+    outbuf: Vec<u8>,
+    sigids: Vec<SigId>,
     saved: Option<libc::termios>,
+    // Original file status flags of the input and output FDs,
+    // whilst they are switched to non-blocking mode
+    flags: Option<(c_int, c_int)>,
+    in_fd: c_int,
+    out_fd: c_int,
+    // Keeps `/dev/tty` open for input and output, if used
+    _tty: Option<(File, File)>,
+    // Why `/dev/tty` couldn't be used, if stdin and stdout were used
+    // instead as a fallback
+    tty_error: Option<Error>,
 }
 
 const STDIN_FD: c_int = 0;
 const STDOUT_FD: c_int = 1;
 
+/// Where to find the terminal
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum TtySource {
+    /// Use stdin and stdout
+    Stdio,
+    /// Open `/dev/tty`.  If that fails, e.g. if there is no
+    /// controlling terminal, then fall back to stdin and stdout if
+    /// `fallback` is set, else fail.
+    DevTty { fallback: bool },
+    /// Use the slave side of a pseudo-terminal, taking ownership of
+    /// the FD.  This is for testing.
+    #[cfg(test)]
+    Pty(c_int),
+}
+
 impl Glue {
-    pub fn new(core: &mut Core, term: Actor<Terminal>) -> Result<Self> {
+    pub fn new(core: &mut Core, term: Actor<Terminal>, source: TtySource) -> Result<Self> {
         let poll = core.anymap_get::<MioPoll>();
 
-        // Setup notification of WINCH signals
-        let (read, write) = UnixStream::pair()?;
-        let sigid = signal_hook::pipe::register(signal_hook::SIGWINCH, write)?;
-        read.set_nonblocking(true)?;
-        let fd = read.as_raw_fd();
-        let fdsrc = FdSource::new(fd);
+        // Input and output are opened separately, since the same FD
+        // can't be registered with MIO twice
+        let mut tty_error = None;
+        let tty = match source {
+            TtySource::Stdio => None,
+            TtySource::DevTty { fallback } => {
+                let open = |write: bool| {
+                    OpenOptions::new()
+                        .read(!write)
+                        .write(write)
+                        .open("/dev/tty")
+                };
+                match (open(false), open(true)) {
+                    (Ok(i), Ok(o)) => Some((i, o)),
+                    (Err(e), _) | (_, Err(e)) => {
+                        let e = Error::new(e.kind(), format!("Unable to open /dev/tty: {}", e));
+                        if !fallback {
+                            return Err(e);
+                        }
+                        tty_error = Some(e);
+                        None
+                    }
+                }
+            }
+            #[cfg(test)]
+            TtySource::Pty(fd) => {
+                let dup = unsafe { libc::dup(fd) };
+                Some(unsafe { (File::from_raw_fd(fd), File::from_raw_fd(dup)) })
+            }
+        };
+        let (in_fd, out_fd) = match tty {
+            Some((ref i, ref o)) => (i.as_raw_fd(), o.as_raw_fd()),
+            None => (STDIN_FD, STDOUT_FD),
+        };
+
+        // Setup notification of WINCH, TSTP and CONT signals
+        let mut signals = Vec::new();
+        let mut sigids = Vec::new();
         let term2 = term.clone();
-        let fwd = fwd_do!(move |_| {
-            // Read all bytes from the notification pipe, to make sure
-            // we get a new Ready notification for the next byte sent
-            let mut buf = [0u8; 32];
-            while 0 < unsafe { libc::read(fd, &mut buf[0] as *mut u8 as *mut _, buf.len()) } {}
+        let (src, sigid) = Self::signal(&poll, libc::SIGWINCH, move || {
             call!([term2], handle_resize());
-        });
-        let winch_src = poll.add(fdsrc, Interest::READABLE, 16, fwd)?;
+        })?;
+        signals.push(src);
+        sigids.push(sigid);
+        let term2 = term.clone();
+        let (src, sigid) = Self::signal(&poll, libc::SIGTSTP, move || {
+            call!([term2], handle_suspend());
+        })?;
+        signals.push(src);
+        sigids.push(sigid);
+        let term2 = term.clone();
+        let (src, sigid) = Self::signal(&poll, libc::SIGCONT, move || {
+            call!([term2], handle_continue());
+        })?;
+        signals.push(src);
+        sigids.push(sigid);
 
         // Setup notification of input
-        if 0 > unsafe { libc::fcntl(STDIN_FD, libc::F_SETFL, libc::O_NONBLOCK) } {
-            return Err(Error::last_os_error());
-        }
         let mut this = Self {
             poll,
             term,
-            _read: read,
-            _winch_src: winch_src,
-            stdin_src: None,
-            sigid,
+            _signals: signals,
+            in_src: None,
+            out_src: None,
+            outbuf: Vec::new(),
+            sigids,
             saved: None,
+            flags: None,
+            in_fd,
+            out_fd,
+            _tty: tty,
+            tty_error,
         };
 
         this.input(true);
@@ -74,51 +155,152 @@ impl Glue {
         Ok(this)
     }
 
+    // Register a signal to be forwarded through a pipe to MIO,
+    // calling `handler` on the main thread for each batch received
+    fn signal(
+        poll: &MioPoll,
+        signal: c_int,
+        handler: impl Fn() + 'static,
+    ) -> Result<((UnixStream, MioSource<FdSource>), SigId)> {
+        let (read, write) = UnixStream::pair()?;
+        let sigid = signal_hook::pipe::register(signal, write)?;
+        read.set_nonblocking(true)?;
+        let fd = read.as_raw_fd();
+        let fdsrc = FdSource::new(fd);
+        let fwd = fwd_do!(move |_| {
+            // Read all bytes from the notification pipe, to make sure
+            // we get a new Ready notification for the next byte sent
+            let mut buf = [0u8; 32];
+            while 0 < unsafe { libc::read(fd, &mut buf[0] as *mut u8 as *mut _, buf.len()) } {}
+            handler();
+        });
+        match poll.add(fdsrc, Interest::READABLE, 16, fwd) {
+            Ok(src) => Ok(((read, src), sigid)),
+            Err(e) => {
+                signal_hook::unregister(sigid);
+                Err(e)
+            }
+        }
+    }
+
+    /// Stop the process, as the default action of TSTP would have
+    /// done had the signal not been caught.  Returns once the process
+    /// has been continued.  STOP is used since it can't be caught.
+    /// If falling back to stdin and stdout, return the reason why
+    /// `/dev/tty` couldn't be used
+    pub fn tty_error(&self) -> Option<Error> {
+        self.tty_error
+            .as_ref()
+            .map(|e| Error::new(e.kind(), e.to_string()))
+    }
+
+    pub fn stop(&mut self) {
+        unsafe { libc::raise(libc::SIGSTOP) };
+    }
+
     /// Get the terminal size
     pub fn get_size(&mut self) -> Result<(i32, i32)> {
         let mut ws = WinSize::default();
-        match unsafe { ioctl(1, TIOCGWINSZ, &mut ws as *mut _ as *mut u8) } {
+        match unsafe { ioctl(self.out_fd, TIOCGWINSZ, &mut ws as *mut _ as *mut u8) } {
             -1 => Err(Error::last_os_error()),
             _ => Ok((i32::from(ws.row), i32::from(ws.col))),
         }
     }
 
-    /// Write data to the terminal
+    /// Write data to the terminal.  If nothing is queued, all the
+    /// data is passed to a single `write` call, with further calls
+    /// only if the OS accepts just part of it.  Whatever can't be
+    /// written without blocking is queued after any data already
+    /// queued, to be written by `drain` when the output FD becomes
+    /// writable.
     pub fn write(&mut self, data: &[u8]) -> Result<()> {
-        Self::write_aux(data)
+        if self.outbuf.is_empty() {
+            let cnt = Self::write_some(self.out_fd, data)?;
+            self.outbuf.extend_from_slice(&data[cnt..]);
+        } else {
+            self.outbuf.extend_from_slice(data);
+            let cnt = Self::write_some(self.out_fd, &self.outbuf)?;
+            self.outbuf.drain(..cnt);
+        }
+        self.watch_output()
+    }
+
+    /// Write as much of the queued data as the output FD will accept
+    /// without blocking
+    pub fn drain(&mut self) -> Result<()> {
+        let cnt = Self::write_some(self.out_fd, &self.outbuf)?;
+        self.outbuf.drain(..cnt);
+        self.watch_output()
+    }
+
+    /// Get the number of bytes queued waiting for the output FD to
+    /// become writable
+    pub fn backlog(&self) -> usize {
+        self.outbuf.len()
     }
 
-    fn write_aux(mut data: &[u8]) -> Result<()> {
+    // Watch for the output FD becoming writable whilst there is data
+    // queued
+    fn watch_output(&mut self) -> Result<()> {
+        if self.outbuf.is_empty() {
+            // MioSource drop handler removes `mio` handler for output
+            self.out_src = None;
+        } else if self.out_src.is_none() {
+            let fdsrc = FdSource::new(self.out_fd);
+            let term = self.term.clone();
+            let fwd = fwd_do!(move |_| call!([term], handle_data_out()));
+            self.out_src = Some(self.poll.add(fdsrc, Interest::WRITABLE, 16, fwd)?);
+        }
+        Ok(())
+    }
+
+    // Write as much of `data` as the OS will accept without
+    // blocking, returning the number of bytes written
+    fn write_some(fd: c_int, mut data: &[u8]) -> Result<usize> {
+        let len = data.len();
         while !data.is_empty() {
             let cnt = unsafe {
                 libc::write(
-                    STDOUT_FD,
+                    fd,
                     &data[0] as *const _ as *const c_void,
                     data.len() as size_t,
                 )
             };
             if cnt < 0 {
-                return Err(Error::last_os_error());
+                let err = Error::last_os_error();
+                match err.kind() {
+                    ErrorKind::WouldBlock => break,
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
             }
             data = &data[cnt as usize..];
         }
-        Ok(())
+        Ok(len - data.len())
     }
 
     /// Enable or disable input
     pub fn input(&mut self, enable: bool) {
-        if enable && self.stdin_src.is_none() && self.termios_set_raw() {
-            let fdsrc = FdSource::new(STDIN_FD);
+        if enable && self.in_src.is_none() && self.termios_set_raw() {
+            if let Err(e) = self.set_nonblocking() {
+                call!([self.term], handle_error_in(e));
+            }
+            let fdsrc = FdSource::new(self.in_fd);
             let term = self.term.clone();
             let fwd = fwd_do!(move |_| call!([term], handle_data_in()));
             match self.poll.add(fdsrc, Interest::READABLE, 16, fwd) {
                 Err(e) => call!([self.term], handle_error_in(e)),
-                Ok(src) => self.stdin_src = Some(src),
+                Ok(src) => self.in_src = Some(src),
             }
         }
         if !enable {
-            // MioSource drop handler removes `mio` handler for stdin
-            self.stdin_src = None;
+            // MioSource drop handler removes `mio` handler for input
+            self.in_src = None;
+            // Back in blocking mode, the queued data all goes out
+            Self::restore_flags(self.in_fd, self.out_fd, self.flags.take());
+            if let Err(e) = self.drain() {
+                call!([self.term], handle_error_in(e));
+            }
             self.termios_restore();
         }
     }
@@ -127,21 +309,69 @@ impl Glue {
     /// best effort to restore the terminal to normal from the state
     /// that it's currently in, ignoring errors.  This is for use from
     /// a panic handler.
-    pub fn cleanup_fn(&mut self) -> Box<dyn Fn(&[u8]) + Send + Sync + 'static> {
+    pub fn cleanup_fn(&mut self) -> CleanupFn {
         let saved = self.saved;
+        let flags = self.flags;
+        let (in_fd, out_fd) = (self.in_fd, self.out_fd);
         Box::new(move |reset| {
-            let _ = Self::write_aux(reset);
-            if let Some(saved) = saved {
-                unsafe { libc::tcsetattr(STDIN_FD, libc::TCSANOW, &saved as *const libc::termios) };
-            };
+            Self::restore_flags(in_fd, out_fd, flags);
+            let _ = Self::write_some(out_fd, reset);
+            Self::restore_saved(in_fd, saved);
         })
     }
 
-    // Read all available stdin data into given Vec
+    /// Make a best effort to restore the saved terminal settings,
+    /// ignoring errors.  Unlike the cleanup function, this doesn't
+    /// write anything, so is safe to use when the output is gone.
+    pub fn restore_termios(&self) {
+        Self::restore_flags(self.in_fd, self.out_fd, self.flags);
+        Self::restore_saved(self.in_fd, self.saved);
+    }
+
+    fn restore_saved(fd: c_int, saved: Option<libc::termios>) {
+        if let Some(saved) = saved {
+            unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved as *const libc::termios) };
+        }
+    }
+
+    // Switch the input and output FDs to non-blocking mode, saving
+    // the original flags.  Often both refer to the same open file
+    // description, so both sets of flags must be read before either
+    // is changed.
+    fn set_nonblocking(&mut self) -> Result<()> {
+        if self.flags.is_none() {
+            let fl_in = unsafe { libc::fcntl(self.in_fd, libc::F_GETFL) };
+            let fl_out = unsafe { libc::fcntl(self.out_fd, libc::F_GETFL) };
+            if fl_in < 0 || fl_out < 0 {
+                return Err(Error::last_os_error());
+            }
+            self.flags = Some((fl_in, fl_out));
+            if 0 > unsafe { libc::fcntl(self.in_fd, libc::F_SETFL, fl_in | libc::O_NONBLOCK) }
+                || 0 > unsafe { libc::fcntl(self.out_fd, libc::F_SETFL, fl_out | libc::O_NONBLOCK) }
+            {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    // Make a best effort to restore the original flags of the input
+    // and output FDs, ignoring errors
+    fn restore_flags(in_fd: c_int, out_fd: c_int, flags: Option<(c_int, c_int)>) {
+        if let Some((fl_in, fl_out)) = flags {
+            unsafe {
+                libc::fcntl(out_fd, libc::F_SETFL, fl_out);
+                libc::fcntl(in_fd, libc::F_SETFL, fl_in);
+            }
+        }
+    }
+
+    // Read all available input data into given Vec
     pub fn read_data(&mut self, inbuf: &mut Vec<u8>) {
         let mut buf = [0u8; 32];
         loop {
-            let cnt = unsafe { libc::read(STDIN_FD, &mut buf[0] as *mut u8 as *mut _, buf.len()) };
+            let cnt =
+                unsafe { libc::read(self.in_fd, &mut buf[0] as *mut u8 as *mut _, buf.len()) };
             if cnt < 0 {
                 #[allow(unreachable_patterns)]
                 match errno::errno().0 {
@@ -161,14 +391,14 @@ impl Glue {
             return false;
         }
 
-        if 0 == unsafe { libc::isatty(STDIN_FD) } {
-            let err = Error::new(ErrorKind::Other, "Standard input is not a TTY");
+        if 0 == unsafe { libc::isatty(self.in_fd) } {
+            let err = Error::other("Terminal input is not a TTY");
             call!([self.term], handle_error_in(err));
             return false;
         }
 
         let mut tbuf = mem::MaybeUninit::uninit();
-        if 0 > unsafe { libc::tcgetattr(STDIN_FD, tbuf.as_mut_ptr()) } {
+        if 0 > unsafe { libc::tcgetattr(self.in_fd, tbuf.as_mut_ptr()) } {
             let err = Error::new(Error::last_os_error().kind(), "Unable to get terminal mode");
             call!([self.term], handle_error_in(err));
             return false;
@@ -178,7 +408,8 @@ impl Glue {
         self.saved = Some(tbuf);
         unsafe { libc::cfmakeraw(&mut tbuf as *mut _) };
 
-        if 0 > unsafe { libc::tcsetattr(STDIN_FD, libc::TCSANOW, &tbuf as *const libc::termios) } {
+        if 0 > unsafe { libc::tcsetattr(self.in_fd, libc::TCSANOW, &tbuf as *const libc::termios) }
+        {
             let err = Error::new(
                 Error::last_os_error().kind(),
                 "Unable to set terminal raw mode",
@@ -192,9 +423,9 @@ impl Glue {
 
     // Restore terminal settings
     fn termios_restore(&mut self) {
-        if let Some(saved) = mem::replace(&mut self.saved, None) {
+        if let Some(saved) = self.saved.take() {
             if 0 > unsafe {
-                libc::tcsetattr(STDIN_FD, libc::TCSANOW, &saved as *const libc::termios)
+                libc::tcsetattr(self.in_fd, libc::TCSANOW, &saved as *const libc::termios)
             } {
                 let err = Error::new(
                     Error::last_os_error().kind(),
@@ -208,7 +439,9 @@ impl Glue {
 
 impl Drop for Glue {
     fn drop(&mut self) {
-        // This call cleans up the UnixStream write end
-        signal_hook::unregister(self.sigid);
+        // These calls clean up the UnixStream write ends
+        for sigid in self.sigids.drain(..) {
+            signal_hook::unregister(sigid);
+        }
     }
 }
@@ -1,41 +1,170 @@
-use std::collections::VecDeque;
+use crate::width::char_width;
+use crate::{Attr, TermOut};
+use std::collections::HashMap;
 use std::mem;
 
-/// TODO: See about allowing this to be used for additional scenarios:
+// TODO: See about allowing this to be used for additional scenarios:
+//
+// - As a terminal emulator, accepting ANSI sequences via a pty
+// - As a direct driver for a display
+// - As a relay between a terminal emulator and a driver
+//   (i.e. Page->Page relay over TCP)
+// - To be queried locally (search for text, etc)
+//
+// This means it would be possible to write stuff like tmux, or
+// alacritty, or a TUI testing app (typebot), or something that wraps
+// a TUI app in an outer TUI shell (window in window).
+//
+// So this means simulating or downgrading more features.  So for
+// example we could run with 16 colours, and downgrade anything else,
+// or else directly support 256 or 16M colours.  Or downgrade
+// double-space chars to U+FFFD and pass them through like that.
+
+// TODO: Option to choose character handling model to match
+// capabilities of eventual display device: Simple, just common
+// single-space single-codepoint characters (e.g. European + lines),
+// no combining characters, no unicode tables, no right-to-left, no
+// wide characters, and anything unknown becomes U+FFFD.  Combining:
+// in addition normalizes combining characters (requires unicode
+// tables).  Right-to-left: also handles arab/hebrew script.  Wide:
+// also handles CJK / emojis.
+
+// TODO: To enable scrolling to be passed through, have a map from
+// display row to storage row.  So on scrolling, the map is scrolled,
+// but the storage isn't (except for the deleted line which is
+// cleared and becomes the new last line).  So the update relayed
+// would be a change in the map only.  For a display driver, this
+// means rendered line storage just needs writing to the display in a
+// different order.
+
+const ERR_HFB: u16 = 162; // Bright yellow on red
+
+/// Substitutions of plain ASCII for box-drawing characters and
+/// bullets, for use with [`Page::set_substitutions`] when the
+/// terminal font can't display these characters.
 ///
-/// - As a terminal emulator, accepting ANSI sequences via a pty
-/// - As a direct driver for a display
-/// - As a relay between a terminal emulator and a driver
-///    (i.e. Page->Page relay over TCP)
-/// - To be queried locally (search for text, etc)
+/// [`Page::set_substitutions`]: struct.Page.html#method.set_substitutions
+pub const BOX_DRAWING_FALLBACK: &[(char, char)] = &[
+    ('─', '-'),
+    ('━', '-'),
+    ('═', '='),
+    ('│', '|'),
+    ('┃', '|'),
+    ('║', '|'),
+    ('┌', '+'),
+    ('┐', '+'),
+    ('└', '+'),
+    ('┘', '+'),
+    ('├', '+'),
+    ('┤', '+'),
+    ('┬', '+'),
+    ('┴', '+'),
+    ('┼', '+'),
+    ('╔', '+'),
+    ('╗', '+'),
+    ('╚', '+'),
+    ('╝', '+'),
+    ('╠', '+'),
+    ('╣', '+'),
+    ('╦', '+'),
+    ('╩', '+'),
+    ('╬', '+'),
+    ('╭', '+'),
+    ('╮', '+'),
+    ('╯', '+'),
+    ('╰', '+'),
+    ('•', '*'),
+    ('·', '.'),
+];
+
+/// Get the attribute codepoint for the given `hfb` value, for
+/// embedding in text passed to [`Region::write`] to change colour
+/// part-way through.  Values beyond the 6400 available are clamped
+/// to the maximum.
 ///
-/// This means it would be possible to write stuff like tmux, or
-/// alacritty, or a TUI testing app (typebot), or something that wraps
-/// a TUI app in an outer TUI shell (window in window).
+/// [`Region::write`]: struct.Region.html#method.write
+pub fn hfb_char(hfb: u16) -> char {
+    char::from_u32(0xE000 + u32::from(hfb.min(6399))).unwrap()
+}
+
+/// Append the attribute codepoint for the given `hfb` value to a
+/// string.  See [`hfb_char`].
 ///
-/// So this means simulating or downgrading more features.  So for
-/// example we could run with 16 colours, and downgrade anything else,
-/// or else directly support 256 or 16M colours.  Or downgrade
-/// double-space chars to U+FFFD and pass them through like that.
-
-/// TODO: Option to choose character handling model to match
-/// capabilities of eventual display device: Simple, just common
-/// single-space single-codepoint characters (e.g. European + lines),
-/// no combining characters, no unicode tables, no right-to-left, no
-/// wide characters, and anything unknown becomes U+FFFD.  Combining:
-/// in addition normalizes combining characters (requires unicode
-/// tables).  Right-to-left: also handles arab/hebrew script.  Wide:
-/// also handles CJK / emojis.
-
-/// TODO: To enable scrolling to be passed through, have a map from
-/// display row to storage row.  So on scrolling, the map is scrolled,
-/// but the storage isn't (except for the deleted line which is
-/// cleared and becomes the new last line).  So the update relayed
-/// would be a change in the map only.  For a display driver, this
-/// means rendered line storage just needs writing to the display in a
-/// different order.
+/// [`hfb_char`]: fn.hfb_char.html
+pub fn push_hfb(s: &mut String, hfb: u16) {
+    s.push(hfb_char(hfb));
+}
 
-const ERR_HFB: u16 = 162; // Bright yellow on red
+/// Constraint on the size of one part of a layout, for use with
+/// [`layout`], [`Region::split_h`] and [`Region::split_v`]
+///
+/// [`Region::split_h`]: struct.Region.html#method.split_h
+/// [`Region::split_v`]: struct.Region.html#method.split_v
+/// [`layout`]: fn.layout.html
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Constraint {
+    /// Fixed size in cells (or pixels for a variable-width font
+    /// page)
+    Fixed(i32),
+
+    /// Percentage of the total size, rounded down
+    Percent(i32),
+
+    /// Share of whatever space is left over after the `Fixed` and
+    /// `Percent` parts have been allocated, in proportion to the
+    /// given ratio
+    Fill(i32),
+}
+
+/// Lay out parts along a line of length `total` according to the
+/// given constraints, returning the offset and size of each part.
+/// The `Fixed` and `Percent` parts are allocated first, in order.
+/// If they don't fit, then the later parts are shrunk, down to zero
+/// size if necessary.  The remaining space is then shared out
+/// between the `Fill` parts according to their ratios, with any
+/// rounding leftovers spread between them so that they exactly fill
+/// the space.  If there are no `Fill` parts, any remaining space is
+/// left unused at the end.
+pub fn layout(total: i32, constraints: &[Constraint]) -> Vec<(i32, i32)> {
+    let total = total.max(0);
+    let mut sizes = Vec::with_capacity(constraints.len());
+    let mut left = total;
+    let mut ratios = 0;
+    for c in constraints {
+        let size = match *c {
+            Constraint::Fixed(v) => v.max(0).min(left),
+            Constraint::Percent(v) => {
+                (i64::from(total) * i64::from(v.max(0)) / 100).min(i64::from(left)) as i32
+            }
+            Constraint::Fill(v) => {
+                ratios += i64::from(v.max(0));
+                0
+            }
+        };
+        left -= size;
+        sizes.push(size);
+    }
+    if ratios > 0 {
+        let mut acc = 0;
+        let mut used = 0;
+        for (c, size) in constraints.iter().zip(sizes.iter_mut()) {
+            if let Constraint::Fill(v) = *c {
+                acc += i64::from(v.max(0));
+                let end = (i64::from(left) * acc / ratios) as i32;
+                *size = end - used;
+                used = end;
+            }
+        }
+    }
+    let mut off = 0;
+    sizes
+        .into_iter()
+        .map(|size| {
+            off += size;
+            (off - size, size)
+        })
+        .collect()
+}
 
 /// This represents a local mutable copy of a whole page of text.
 ///
@@ -48,7 +177,10 @@ const ERR_HFB: u16 = 162; // Bright yellow on red
 /// normalized, usually just before being updated to the actual
 /// display.  For strings being displayed, private use codepoints from
 /// U+E000 to U+F8FF are used for zero-width attribute/colour changes,
-/// giving 6400 colour-pairs/attribute-sets.
+/// giving 6400 colour-pairs/attribute-sets.  East Asian wide
+/// characters take up two cells, and combining marks are kept
+/// together with the character before them as a single glyph.
+#[derive(Clone)]
 pub struct Page {
     // Page height (size-Y), in lines
     sy: i32,
@@ -64,6 +196,30 @@ pub struct Page {
 
     // Rows
     rows: Vec<Row>,
+
+    // Characters to replace on output
+    subst: HashMap<char, char>,
+
+    // Where the cursor should be left after rendering, if anywhere
+    cursor: Option<(i32, i32)>,
+
+    // Areas left to the app to fill with raw output
+    reserved: Vec<Reserved>,
+
+    // Rows whose contents on the display are unknown, when this page
+    // is the `prev` page for `render_rows_to`.  Empty if all known.
+    unknown: Vec<bool>,
+}
+
+// An area of the page reserved for raw output by the app
+#[derive(Clone, PartialEq, Eq)]
+struct Reserved {
+    y0: i32,
+    x0: u16,
+    y1: i32,
+    x1: u16,
+    data: Vec<u8>,
+    always: bool,
 }
 
 impl Page {
@@ -75,19 +231,134 @@ impl Page {
     pub fn new(sy: i32, sx: i32, hfb: u16) -> Self {
         let sy = sy.max(0);
         let sx = sx.max(0);
-        let csx = Scan(b"8").measure_rest() as i32;
+        let csx = 1;
         let mut rows = Vec::with_capacity(sy as usize);
         rows.resize_with(sy as usize, || Row::new(sx as u16, hfb));
-        Self { sy, sx, csx, rows }
+        Self {
+            sy,
+            sx,
+            csx,
+            rows,
+            subst: HashMap::new(),
+            cursor: None,
+            reserved: Vec::new(),
+            unknown: Vec::new(),
+        }
+    }
+
+    /// Set the position where the cursor should be left after
+    /// rendering, for example as returned from [`Region::field`], or
+    /// `None` to leave it wherever the last update finished.
+    ///
+    /// [`Region::field`]: struct.Region.html#method.field
+    pub fn set_cursor(&mut self, cursor: Option<(i32, i32)>) {
+        self.cursor = cursor;
+    }
+
+    /// Reserve the rectangle `(y, x, sy, sx)` for raw output from the
+    /// app, for example an inline image or some escape sequence that
+    /// the page model doesn't understand.  The contents of the page
+    /// within the rectangle are ignored when rendering, and instead
+    /// the cursor is moved to the top-left of the rectangle and
+    /// `data` is output unchanged.  This happens only when the
+    /// reservation is new or its data has changed since `prev`,
+    /// unless `always` is set, in which case `data` is output on
+    /// every render.  The cells around the rectangle are compared
+    /// and updated as normal.  `data` must not leave anything drawn
+    /// outside of the rectangle.  The cursor position is unknown
+    /// afterwards, so the next update will start with a cursor move.
+    ///
+    /// Reservations are kept until [`Page::clear_reserved`] is
+    /// called, after which the cells underneath are redrawn from the
+    /// page contents.
+    ///
+    /// [`Page::clear_reserved`]: struct.Page.html#method.clear_reserved
+    pub fn reserve(&mut self, rect: (i32, i32, i32, i32), data: Vec<u8>, always: bool) {
+        let (y, x, sy, sx) = rect;
+        let y0 = y.max(0);
+        let y1 = (y + sy).min(self.sy);
+        let x0 = x.max(0).min(self.sx) as u16;
+        let x1 = (x + sx).min(self.sx).max(0) as u16;
+        if y0 < y1 && x0 < x1 {
+            self.reserved.push(Reserved {
+                y0,
+                x0,
+                y1,
+                x1,
+                data,
+                always,
+            });
+        }
+    }
+
+    /// Remove all the reservations made with [`Page::reserve`]
+    ///
+    /// [`Page::reserve`]: struct.Page.html#method.reserve
+    pub fn clear_reserved(&mut self) {
+        self.reserved.clear();
+    }
+
+    /// Set a table of characters to replace with fallback characters
+    /// when rendering, for displays that can't show certain
+    /// characters.  For example [`BOX_DRAWING_FALLBACK`] replaces
+    /// box-drawing characters with ASCII.  Substitution only happens
+    /// on output, so the page contents are unchanged.  Each fallback
+    /// character must have the same width as the character it
+    /// replaces, otherwise the display will get out of step with the
+    /// page.  Pass an empty table to disable substitution.
+    ///
+    /// [`BOX_DRAWING_FALLBACK`]: constant.BOX_DRAWING_FALLBACK.html
+    pub fn set_substitutions(&mut self, table: &[(char, char)]) {
+        self.subst = table.iter().copied().collect();
     }
 
     /// Return the standard cell-width.  This will be the size of an
     /// average character for a variable-width font, or else 1 for a
-    /// monospaced font.
+    /// monospaced font.  It is 1 unless changed with
+    /// [`Page::set_cell_width`].
+    ///
+    /// [`Page::set_cell_width`]: struct.Page.html#method.set_cell_width
     pub fn cell_sx(&self) -> i32 {
         self.csx
     }
 
+    /// Change the standard cell-width to `csx` pixels, for example
+    /// when a graphical display zooms to a different font size.
+    /// Glyphs are measured as their width in cells (1, or 2 for East
+    /// Asian wide characters) multiplied by the cell-width, so this
+    /// changes the width of all text.  The whole page is reflowed:
+    /// every row is measured again and normalized.  Positions and
+    /// span widths on the page are in pixels, and these don't change,
+    /// so each piece of text written stays where it was, but its
+    /// glyphs are laid out again at the new width.  Text that now
+    /// overflows the span it was written into is cut off at the end
+    /// of the span, with a glyph cut in two shown as spaces, and text
+    /// that now underflows its span leaves the rest of the span as
+    /// spaces in the same colour.  Usually the app will lay out and
+    /// draw the page again anyway, since the number of cells that fit
+    /// across it has changed.  The next render against a page with
+    /// the old cell-width redraws everything.
+    ///
+    /// This is for graphical displays driven through
+    /// [`Page::for_each_change`].  A page rendered to a [`TermOut`]
+    /// must keep a cell-width of 1, since the terminal's cells are
+    /// its pixels.
+    ///
+    /// [`Page::for_each_change`]: struct.Page.html#method.for_each_change
+    /// [`TermOut`]: struct.TermOut.html
+    pub fn set_cell_width(&mut self, csx: i32) {
+        // Keep double-width glyphs within the range of a span width
+        let csx = csx.clamp(1, 16383);
+        if csx == self.csx {
+            return;
+        }
+        self.csx = csx;
+        for row in &mut self.rows {
+            row.normal = false;
+        }
+        self.normalize();
+    }
+
     /// Return a Region representing the full area of the page for
     /// drawing on.
     pub fn full(&mut self) -> Region<'_> {
@@ -127,17 +398,433 @@ impl Page {
 
     /// Measures some text to see how many pixels it will take up
     pub fn measure(&mut self, text: &str) -> i32 {
-        Scan(text.as_bytes()).measure_rest() as i32
+        Scan(text.as_bytes(), self.csx as u16).measure_rest() as i32
     }
 
     /// Normalize all rows in the page, meaning apply all the updates
     /// made and store the data in the minimum form.
     pub fn normalize(&mut self) {
-        let mut glyphs1 = VecDeque::with_capacity((self.sx * 2 / self.csx) as usize);
-        let mut glyphs2 = VecDeque::with_capacity((self.sx * 2 / self.csx) as usize);
+        let mut glyphs1 = Vec::with_capacity((self.sx * 2 / self.csx) as usize);
+        let mut glyphs2 = Vec::with_capacity((self.sx * 2 / self.csx) as usize);
         let mut spare = Row::new(self.sx as u16, ERR_HFB);
         for y in 0..self.sy {
-            self.rows[y as usize].normalize(self.sx as u16, &mut glyphs1, &mut glyphs2, &mut spare);
+            self.rows[y as usize].normalize(
+                self.sx as u16,
+                self.csx as u16,
+                &mut glyphs1,
+                &mut glyphs2,
+                &mut spare,
+            );
+        }
+    }
+
+    /// Get the glyph and `hfb` colour displayed at the given cell, or
+    /// `None` if the position is outside the page, as `(text, hfb,
+    /// continuation)`.  The row is normalized first.  For either half
+    /// of a double-width glyph, the whole glyph is returned, with
+    /// `continuation` set to true for the right half, so that the
+    /// glyph can be counted just once when scanning along a row.
+    /// Padding, or a glyph which can't be displayed complete, is
+    /// returned as a space in every cell it covers, and invalid UTF-8
+    /// as U+FFFD.
+    pub fn cell_at(&mut self, y: i32, x: i32) -> Option<(String, u16, bool)> {
+        if y < 0 || y >= self.sy || x < 0 || x >= self.sx {
+            return None;
+        }
+        let x = x as u16;
+        let mut glyphs1 = Vec::new();
+        let mut glyphs2 = Vec::new();
+        let mut spare = Row::new(self.sx as u16, ERR_HFB);
+        let row = &mut self.rows[y as usize];
+        row.normalize(
+            self.sx as u16,
+            self.csx as u16,
+            &mut glyphs1,
+            &mut glyphs2,
+            &mut spare,
+        );
+        let mut cell = None;
+        row.glyphs(self.sx as u16, self.csx as u16, |g, data| {
+            if cell.is_none() && g.x <= x && x < g.x + g.sx {
+                cell = Some(if g.len == 0 || g.shift != 0 || g.sx != g.wid {
+                    (" ".to_string(), g.hfb, false)
+                } else {
+                    let text = &data[g.off as usize..g.off as usize + g.len as usize];
+                    let text = std::str::from_utf8(text).unwrap_or("\u{FFFD}");
+                    (text.to_string(), g.hfb, x > g.x)
+                });
+            }
+        });
+        cell
+    }
+
+    /// Get the visible text of the page as a string, one line per
+    /// row, joined with newlines, without any colour information.
+    /// This is intended for snapshot tests.  The page is normalized
+    /// first.  A double-width glyph appears as its text, without
+    /// padding.  Padding, or a glyph which can't be displayed
+    /// complete, appears as spaces.  Invalid UTF-8 appears as U+FFFD.
+    /// Substitutions and reserved areas are ignored.
+    pub fn to_string_plain(&mut self) -> String {
+        self.visible_text(false)
+    }
+
+    /// Get the visible text of the page as a string, like
+    /// [`Page::to_string_plain`], but with SGR sequences to select
+    /// the colours, for example for golden-file tests.  The colours
+    /// are selected at the start of each line and wherever they
+    /// change, and are reset at the end of each line.
+    ///
+    /// [`Page::to_string_plain`]: struct.Page.html#method.to_string_plain
+    pub fn to_string_ansi(&mut self) -> String {
+        self.visible_text(true)
+    }
+
+    // Get the visible text of the page, with colours if `ansi` is set
+    fn visible_text(&mut self, ansi: bool) -> String {
+        self.normalize();
+        let csx = self.csx as u16;
+        let mut out = String::new();
+        for (y, row) in self.rows.iter().enumerate() {
+            if y > 0 {
+                out.push('\n');
+            }
+            let mut curr_hfb = None;
+            row.glyphs(self.sx as u16, self.csx as u16, |g, data| {
+                if ansi && curr_hfb != Some(g.hfb) {
+                    curr_hfb = Some(g.hfb);
+                    out.push_str("\x1B[");
+                    out.push_str(&Attr::from_hfb(g.hfb).sgr());
+                    out.push('m');
+                }
+                if g.len == 0 || g.shift != 0 || g.sx != g.wid {
+                    out.extend(std::iter::repeat(' ').take(usize::from(g.sx.div_ceil(csx))));
+                } else {
+                    let text = &data[g.off as usize..g.off as usize + g.len as usize];
+                    out.push_str(std::str::from_utf8(text).unwrap_or("\u{FFFD}"));
+                }
+            });
+            if ansi {
+                out.push_str("\x1B[0m");
+            }
+        }
+        out
+    }
+
+    /// Render the page to an RGBA image, with each cell taking up
+    /// `cell_w` by `cell_h` pixels, where a cell is
+    /// [`Page::cell_sx`] pixels wide on the page.  This is intended
+    /// for screenshots in documentation or for visual regression
+    /// tests, not for display.  The page is normalized first.
+    /// Colours are mapped to a fixed palette, with the default
+    /// colours as light grey on black.  The built-in font only covers
+    /// ASCII, using a small 5x7 bitmap scaled to fit the cell, plus
+    /// box-drawing and block characters which are drawn as lines and
+    /// fills.  Heavy and double box-drawing lines are drawn as light
+    /// lines.  Any other character is drawn as a hollow box.  Only
+    /// available with the `image` cargo feature.
+    ///
+    /// [`Page::cell_sx`]: struct.Page.html#method.cell_sx
+    #[cfg(feature = "image")]
+    pub fn render_image(&mut self, cell_w: u32, cell_h: u32) -> crate::ImageBuffer {
+        let (cw, ch) = (cell_w.max(1), cell_h.max(1));
+        let csx = self.csx as u16;
+        let cells = (self.sx as u16).div_ceil(csx);
+        let mut img = crate::ImageBuffer::new(u32::from(cells) * cw, self.sy as u32 * ch);
+        self.normalize();
+        for (y, row) in self.rows.iter().enumerate() {
+            row.glyphs(self.sx as u16, self.csx as u16, |g, data| {
+                let text = &data[g.off as usize..g.off as usize + g.len as usize];
+                let c = if g.len == 0 || g.shift != 0 || g.sx != g.wid {
+                    None
+                } else {
+                    Some(
+                        std::str::from_utf8(text)
+                            .ok()
+                            .and_then(|t| t.chars().next())
+                            .unwrap_or('\u{FFFD}'),
+                    )
+                };
+                for x in (g.x..g.x + g.sx).step_by(usize::from(csx)) {
+                    let cell = c.filter(|_| x == g.x);
+                    let x = u32::from(x / csx) * cw;
+                    img.draw_cell(x, y as u32 * ch, cw, ch, cell, g.hfb);
+                }
+            });
+        }
+        img
+    }
+
+    /// Add to `out` the output required to update the display from
+    /// `prev` to the contents of this page.  This is the same as
+    /// [`Page::render_region_to`] with a rectangle covering the whole
+    /// page, so only the glyphs which have changed are output, and
+    /// if nothing has changed then nothing is output.
+    ///
+    /// [`Page::render_region_to`]: struct.Page.html#method.render_region_to
+    pub fn render_diff(&mut self, prev: &Page, out: &mut TermOut) {
+        self.render_region_to(prev, (0, 0, self.sy, self.sx), out);
+    }
+
+    /// Add to `out` the output required to update the display from
+    /// `prev` to the contents of this page, but only within the
+    /// rectangle `(y, x, sy, sx)`.  Only the rows within the
+    /// rectangle are normalized and compared, so this is cheap when
+    /// the caller knows that only a small area has changed.  `prev`
+    /// should be a normalized copy of what is currently displayed,
+    /// for example a clone of this page taken after the previous
+    /// render.  If `prev` is a different size, then everything
+    /// within the rectangle is redrawn.
+    ///
+    /// Cursor moves are only output where the next changed glyph
+    /// doesn't follow on from the previous one.  Afterwards the cursor
+    /// is moved to the position given to [`Page::set_cursor`], if
+    /// any.  The last cursor position output is tracked by the
+    /// [`TermOut`], so if nothing has changed since the last render
+    /// and the cursor is already in the right place, then nothing at
+    /// all is output.  This assumes that any output added to the
+    /// [`TermOut`] other than through rendering finishes with a call
+    /// to [`TermOut::at`].
+    ///
+    /// Areas reserved with [`Page::reserve`] are output after the
+    /// rest of the changes, if their top-left corner falls within
+    /// the rectangle.  Finally [`TermOut::end_frame`] is called, if
+    /// anything was output, so an unchanged page produces no output
+    /// whatever the [`FrameReset`] policy.
+    ///
+    /// [`FrameReset`]: enum.FrameReset.html
+    /// [`Page::reserve`]: struct.Page.html#method.reserve
+    /// [`TermOut::end_frame`]: struct.TermOut.html#method.end_frame
+    /// [`Page::set_cursor`]: struct.Page.html#method.set_cursor
+    /// [`TermOut::at`]: struct.TermOut.html#method.at
+    /// [`TermOut`]: struct.TermOut.html
+    pub fn render_region_to(&mut self, prev: &Page, rect: (i32, i32, i32, i32), out: &mut TermOut) {
+        let (y, x, sy, sx) = rect;
+        let y0 = y.max(0);
+        let y1 = (y + sy).min(self.sy);
+        let x0 = x.max(0) as u16;
+        let x1 = (x + sx).min(self.sx).max(0) as u16;
+        let mut changed = y0 < y1 && x0 < x1 && self.render_rows(prev, y0, y1, x0, x1, out);
+        let full = prev.sy != self.sy || prev.sx != self.sx || prev.csx != self.csx;
+        for r in &self.reserved {
+            if r.y0 >= y0
+                && r.y0 < y1
+                && r.x0 >= x0
+                && r.x0 < x1
+                && (full || r.always || !prev.reserved.contains(r))
+            {
+                out.at(r.y0, i32::from(r.x0));
+                out.bytes(&r.data);
+                out.cursor = None;
+                out.attr_default = false;
+                changed = true;
+            }
+        }
+        if changed {
+            out.end_frame();
+        }
+        if let Some((y, x)) = self.cursor {
+            if out.cursor != Some((y, x)) {
+                out.at(y, x);
+            }
+        }
+    }
+
+    /// Render rows `y..y+sy` like [`Page::render_region_to`], and
+    /// then copy those rows into `prev`, so that `prev` continues to
+    /// mirror what is on the display.  This is for progressive
+    /// rendering by a slow producer, for example a large table which
+    /// takes several frames to compute.  Each batch of rows can be
+    /// written to this page, rendered and flushed as soon as it is
+    /// ready, and meanwhile the rows below still show the previous
+    /// frame until they are overwritten, instead of the display
+    /// being cleared first.  The cursor position is tracked across
+    /// batches by the [`TermOut`] as usual.
+    ///
+    /// `prev` should be the same size as this page.  If not, it is
+    /// replaced with a page of the right size, and the rows not yet
+    /// rendered are considered unknown, so they will be redrawn in
+    /// full when their turn comes.
+    ///
+    /// [`Page::render_region_to`]: struct.Page.html#method.render_region_to
+    /// [`TermOut`]: struct.TermOut.html
+    pub fn render_rows_to(&mut self, prev: &mut Page, y: i32, sy: i32, out: &mut TermOut) {
+        self.render_region_to(prev, (y, 0, sy, self.sx), out);
+        if prev.sy != self.sy || prev.sx != self.sx || prev.csx != self.csx {
+            *prev = Page::new(self.sy, self.sx, ERR_HFB);
+            prev.csx = self.csx;
+            prev.unknown = vec![true; self.sy as usize];
+        }
+        let y0 = y.max(0);
+        let y1 = (y + sy).min(self.sy);
+        for y in y0..y1 {
+            prev.rows[y as usize] = self.rows[y as usize].clone();
+            if let Some(unknown) = prev.unknown.get_mut(y as usize) {
+                *unknown = false;
+            }
+        }
+        let within = |r: &Reserved| r.y0 >= y0 && r.y0 < y1;
+        prev.reserved.retain(|r| !within(r));
+        prev.reserved
+            .extend(self.reserved.iter().filter(|r| within(r)).cloned());
+    }
+
+    /// Run through all the changes between `prev` and this page,
+    /// passing each changed glyph to the callback as `(y, x, hfb,
+    /// text)`.  This is for driving a custom display, e.g. something
+    /// other than an ANSI terminal.  Rows are normalized first.  The
+    /// text has any substitutions applied (see
+    /// [`Page::set_substitutions`]), and invalid UTF-8 is passed as
+    /// U+FFFD.  Padding, or a glyph which can't be displayed
+    /// complete, is passed as spaces.  If `prev` is a different size
+    /// to this page, then every glyph is passed.  Cells within areas
+    /// reserved with [`Page::reserve`] are skipped.
+    ///
+    /// [`Page::reserve`]: struct.Page.html#method.reserve
+    /// [`Page::set_substitutions`]: struct.Page.html#method.set_substitutions
+    pub fn for_each_change(&mut self, prev: &Page, mut cb: impl FnMut(i32, i32, u16, &str)) {
+        let (sy, sx) = (self.sy, self.sx as u16);
+        self.changes(prev, 0, sy, 0, sx, |y, x, _, hfb, text| {
+            cb(y, i32::from(x), hfb, text)
+        });
+    }
+
+    // Returns true if any changes were output
+    fn render_rows(
+        &mut self,
+        prev: &Page,
+        y0: i32,
+        y1: i32,
+        x0: u16,
+        x1: u16,
+        out: &mut TermOut,
+    ) -> bool {
+        let mut changed = false;
+        let mut curr_hfb = None;
+        self.changes(prev, y0, y1, x0, x1, |y, gx0, gx1, hfb, text| {
+            if out.cursor != Some((y, i32::from(gx0))) {
+                out.at(y, i32::from(gx0));
+            }
+            let attr = Attr::from_hfb(hfb);
+            if curr_hfb != Some(hfb) {
+                curr_hfb = Some(hfb);
+                out.set_attr(attr);
+            }
+            if text.bytes().all(|b| b == b' ') {
+                out.clear_run(i32::from(gx1 - gx0), attr);
+            } else {
+                out.out(text);
+            }
+            out.cursor = Some((y, i32::from(gx1)));
+            changed = true;
+        });
+        changed
+    }
+
+    // Normalize rows `y0..y1` and pass the changed glyphs to the
+    // callback clipped to `x0..x1`, as `(y, x0, x1, hfb, text)`
+    fn changes(
+        &mut self,
+        prev: &Page,
+        y0: i32,
+        y1: i32,
+        x0: u16,
+        x1: u16,
+        mut cb: impl FnMut(i32, u16, u16, u16, &str),
+    ) {
+        let full = prev.sy != self.sy || prev.sx != self.sx || prev.csx != self.csx;
+        let csx = self.csx as u16;
+        let mut glyphs1 = Vec::with_capacity((self.sx * 2 / self.csx) as usize);
+        let mut glyphs2 = Vec::with_capacity((self.sx * 2 / self.csx) as usize);
+        let mut spare = Row::new(self.sx as u16, ERR_HFB);
+        let mut spaces = String::new();
+        let mut skip = Vec::new();
+        let subst = &self.subst;
+        for y in y0..y1 {
+            // Reserved cells are skipped, and when a reservation is
+            // removed, the rows it covered are redrawn in full
+            let covers = |r: &Reserved| r.y0 <= y && y < r.y1;
+            skip.clear();
+            skip.extend(
+                self.reserved
+                    .iter()
+                    .filter(|r| covers(r))
+                    .map(|r| (r.x0, r.x1)),
+            );
+            skip.sort_unstable();
+            let redraw = full
+                || prev.unknown.get(y as usize) == Some(&true)
+                || prev.reserved.iter().any(|p| {
+                    covers(p)
+                        && !self
+                            .reserved
+                            .iter()
+                            .any(|r| (r.y0, r.x0, r.y1, r.x1) == (p.y0, p.x0, p.y1, p.x1))
+                });
+
+            let row = &mut self.rows[y as usize];
+            row.normalize(
+                self.sx as u16,
+                self.csx as u16,
+                &mut glyphs1,
+                &mut glyphs2,
+                &mut spare,
+            );
+            let mut glyph_cb = |g: Glyph, data: &[u8]| {
+                if g.x + g.sx <= x0 || g.x >= x1 {
+                    return;
+                }
+                let mut gx0 = g.x.max(x0);
+                let gx1 = (g.x + g.sx).min(x1);
+                for &(rx0, rx1) in &skip {
+                    if rx0 < gx1 && rx1 > gx0 {
+                        if rx0 > gx0 {
+                            // Part of the glyph before the reserved area
+                            spaces.clear();
+                            spaces.extend(
+                                std::iter::repeat(' ').take(usize::from((rx0 - gx0).div_ceil(csx))),
+                            );
+                            cb(y, gx0, rx0, g.hfb, &spaces);
+                        }
+                        gx0 = gx0.max(rx1);
+                    }
+                }
+                if gx0 >= gx1 {
+                    return;
+                }
+                if g.len == 0 || g.shift != 0 || g.sx != g.wid || gx0 != g.x || gx1 != g.x + g.sx {
+                    // Padding, or only part of the glyph is visible
+                    spaces.clear();
+                    spaces.extend(
+                        std::iter::repeat(' ').take(usize::from((gx1 - gx0).div_ceil(csx))),
+                    );
+                    cb(y, gx0, gx1, g.hfb, &spaces);
+                } else {
+                    let text = &data[g.off as usize..g.off as usize + g.len as usize];
+                    match std::str::from_utf8(text) {
+                        Ok(text) => {
+                            let mut it = text.chars();
+                            match (it.next().and_then(|c| subst.get(&c)), it.next()) {
+                                (Some(c), None) => {
+                                    cb(y, gx0, gx1, g.hfb, c.encode_utf8(&mut [0; 4]))
+                                }
+                                _ => cb(y, gx0, gx1, g.hfb, text),
+                            }
+                        }
+                        Err(_) => cb(y, gx0, gx1, g.hfb, "\u{FFFD}"),
+                    }
+                }
+            };
+            if redraw {
+                row.glyphs(self.sx as u16, self.csx as u16, &mut glyph_cb);
+            } else {
+                prev.rows[y as usize].difference(
+                    row,
+                    self.sx as u16,
+                    self.csx as u16,
+                    &mut glyph_cb,
+                );
+            }
         }
     }
 }
@@ -205,10 +892,77 @@ impl<'a> Region<'a> {
         }
     }
 
+    /// Get the height of the region in rows
+    pub fn sy(&self) -> i32 {
+        self.sy
+    }
+
+    /// Get the width of the region in pixels (i.e. cells for a
+    /// monospaced terminal)
+    pub fn sx(&self) -> i32 {
+        self.sx
+    }
+
+    /// Return the standard cell-width of the page.  See
+    /// [`Page::cell_sx`].
+    ///
+    /// [`Page::cell_sx`]: struct.Page.html#method.cell_sx
+    pub fn cell_sx(&self) -> i32 {
+        self.page.csx
+    }
+
+    /// Measures some text to see how many pixels it will take up
+    pub fn measure(&self, text: &str) -> i32 {
+        Scan(text.as_bytes(), self.page.csx as u16).measure_rest() as i32
+    }
+
+    /// Generate a sub-region inset from the edges of this region by
+    /// the given margins, for example to leave room for a border and
+    /// padding.  If the margins are larger than the region, the
+    /// sub-region is empty.  Negative margins give a sub-region
+    /// extending beyond this one, but it is still clipped to this
+    /// region when drawn to.
+    pub fn inset(&mut self, top: i32, right: i32, bottom: i32, left: i32) -> Region<'_> {
+        let sy = (self.sy - top - bottom).max(0);
+        let sx = (self.sx - left - right).max(0);
+        self.region(top, left, sy, sx)
+    }
+
+    /// Split this region horizontally into side-by-side sub-regions
+    /// according to the given constraints (see [`layout`]), and call
+    /// `f` with the index and sub-region of each in turn.  The
+    /// sub-regions don't overlap, and each one is clipped to this
+    /// region.
+    ///
+    /// [`layout`]: fn.layout.html
+    pub fn split_h(&mut self, constraints: &[Constraint], mut f: impl FnMut(usize, Region<'_>)) {
+        let sy = self.sy;
+        for (i, (x, sx)) in layout(self.sx, constraints).into_iter().enumerate() {
+            f(i, self.region(0, x, sy, sx));
+        }
+    }
+
+    /// Split this region vertically into sub-regions one above the
+    /// other according to the given constraints (see [`layout`]), and
+    /// call `f` with the index and sub-region of each in turn.  The
+    /// sub-regions don't overlap, and each one is clipped to this
+    /// region.
+    ///
+    /// [`layout`]: fn.layout.html
+    pub fn split_v(&mut self, constraints: &[Constraint], mut f: impl FnMut(usize, Region<'_>)) {
+        let sx = self.sx;
+        for (i, (y, sy)) in layout(self.sy, constraints).into_iter().enumerate() {
+            f(i, self.region(y, 0, sy, sx));
+        }
+    }
+
     /// Clear the whole region to space characters of the given `hfb`
     /// colour.  This will be clipped according to the current and
     /// parent regions.
     pub fn clear(&mut self, hfb: u16) {
+        if self.cx0 >= self.cx1 {
+            return;
+        }
         if self.cx0 <= 0 && self.cx1 >= self.page.sx {
             for y in self.cy0..self.cy1 {
                 let row = &mut self.page.rows[y as usize];
@@ -219,12 +973,24 @@ impl<'a> Region<'a> {
         } else {
             for y in self.cy0..self.cy1 {
                 let row = &mut self.page.rows[y as usize];
+                row.normal = false;
                 row.span(self.cx0 as u16, (self.cx1 - self.cx0) as u16, 0);
                 row.hfb(hfb);
             }
         }
     }
 
+    /// Clear from the given location to the end of the region to
+    /// space characters of the given `hfb` colour.  This clears the
+    /// rest of row `y` from `x` onwards, and all the rows below it.
+    /// This will be clipped according to the current and parent
+    /// regions.
+    pub fn clear_from(&mut self, y: i32, x: i32, hfb: u16) {
+        let (sy, sx) = (self.sy, self.sx);
+        self.region(y, x, 1, sx - x).clear(hfb);
+        self.region(y + 1, 0, sy - y - 1, sx).clear(hfb);
+    }
+
     /// Write some text rightwards from the given location.  This will
     /// be clipped according to the current and parent regions.
     /// Embedded colour changes using U+E000 to U+F8FF are permitted.
@@ -239,8 +1005,179 @@ impl<'a> Region<'a> {
         self.writeb(y, x, hfb, text.as_bytes())
     }
 
+    /// Write a run of cells rightwards from the given location, each
+    /// with its own `hfb` colour, for example for a heatmap or a
+    /// syntax-highlighted line.  This is equivalent to calling
+    /// [`Region::write`] with a string containing an attribute
+    /// codepoint wherever the colour changes, so adjacent cells of
+    /// the same colour are coalesced.  Clipping and the returned
+    /// X-position are the same as for [`Region::write`].
+    ///
+    /// [`Region::write`]: struct.Region.html#method.write
+    pub fn write_cells(&mut self, y: i32, x: i32, cells: &[(u16, char)]) -> i32 {
+        let hfb0 = match cells.first() {
+            Some(&(hfb, _)) => hfb,
+            None => return x,
+        };
+        let mut text = String::with_capacity(cells.len() * 2);
+        let mut curr = hfb0;
+        for &(hfb, c) in cells {
+            if hfb != curr {
+                curr = hfb;
+                push_hfb(&mut text, hfb);
+            }
+            text.push(c);
+        }
+        self.write(y, x, hfb0, &text)
+    }
+
+    /// Write some text rightwards from the given location, like
+    /// [`Region::write`], but taking structured attributes.  These
+    /// are converted to the compact `hfb` form used by the page, so
+    /// only basic colours and bold are kept.
+    ///
+    /// [`Region::write`]: struct.Region.html#method.write
+    pub fn write_attr(&mut self, y: i32, x: i32, attr: Attr, text: &str) -> i32 {
+        self.write(y, x, attr.to_hfb(), text)
+    }
+
+    /// Copy the glyphs and colours of a rectangle of `src` into this
+    /// region, clipped as usual.  The rectangle is the size of this
+    /// region, with its top-left corner at `(src_y, src_x)` in `src`.
+    /// Parts of the rectangle outside `src` are left unchanged.  A
+    /// double-width glyph cut by the edge of the rectangle, or which
+    /// was only partly visible in `src`, is copied as spaces.  To
+    /// scroll a page, blit from a clone of it.
+    pub fn blit_from(&mut self, src: &Page, src_y: i32, src_x: i32) {
+        let x0 = src_x.max(0);
+        let x1 = (src_x + self.sx).min(src.sx);
+        if x0 >= x1 {
+            return;
+        }
+        let (x0, x1) = (x0 as u16, x1 as u16);
+        let mut glyphs1 = Vec::new();
+        let mut glyphs2 = Vec::new();
+        let mut spare = Row::new(src.sx as u16, ERR_HFB);
+        let mut run = Vec::new();
+        for y in src_y.max(0)..(src_y + self.sy).min(src.sy) {
+            let mut normal;
+            let mut row = &src.rows[y as usize];
+            if !row.normal {
+                normal = row.clone();
+                normal.normalize(
+                    src.sx as u16,
+                    src.csx as u16,
+                    &mut glyphs1,
+                    &mut glyphs2,
+                    &mut spare,
+                );
+                row = &normal;
+            }
+
+            // Collect runs of the same colour, writing each one out
+            // when the colour changes
+            let dy = y - src_y;
+            let mut run_x = 0;
+            let mut run_hfb = None;
+            row.glyphs(src.sx as u16, src.csx as u16, |g, data| {
+                if g.x + g.sx <= x0 || g.x >= x1 {
+                    return;
+                }
+                let gx0 = g.x.max(x0);
+                let gx1 = (g.x + g.sx).min(x1);
+                let pad =
+                    g.len == 0 || g.shift != 0 || g.sx != g.wid || gx0 != g.x || gx1 != g.x + g.sx;
+                if pad || run_hfb != Some(g.hfb) {
+                    if let Some(hfb) = run_hfb.take() {
+                        self.writeb(dy, run_x, hfb, &run);
+                    }
+                    run.clear();
+                }
+                if pad {
+                    // Cleared to the exact width, which a run of
+                    // spaces can't always match with a wider cell
+                    let x = i32::from(gx0) - src_x;
+                    self.region(dy, x, 1, i32::from(gx1 - gx0)).clear(g.hfb);
+                } else {
+                    if run_hfb.is_none() {
+                        run_x = i32::from(gx0) - src_x;
+                        run_hfb = Some(g.hfb);
+                    }
+                    run.extend_from_slice(&data[g.off as usize..g.off as usize + g.len as usize]);
+                }
+            });
+            if let Some(hfb) = run_hfb {
+                self.writeb(dy, run_x, hfb, &run);
+            }
+        }
+    }
+
+    /// Write some text downwards from the given location, one glyph
+    /// per row, all starting in the same column.  This will be
+    /// clipped according to the current and parent regions.
+    /// Embedded colour changes using U+E000 to U+F8FF are permitted,
+    /// and apply to the glyphs that follow.  A double-width glyph
+    /// takes up its full width on its own row, and the following
+    /// glyph still starts in column `x` on the next row.  Returns the
+    /// next Y-position after the text, even if some or all of the
+    /// text was clipped.
+    pub fn write_vertical(&mut self, mut y: i32, x: i32, mut hfb: u16, text: &str) -> i32 {
+        let mut p = Scan(text.as_bytes(), self.page.csx as u16);
+        loop {
+            let start = p;
+            match p.measure() {
+                Meas::End => return y,
+                Meas::Attr(v) => hfb = v,
+                Meas::Glyph(_) => {
+                    self.writeb(y, x, hfb, start.slice_to(&p));
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    /// Draw a sparkline of the given data series rightwards from the
+    /// given location, using up to `len` block-element glyphs
+    /// (`▁` to `█`).  The bars are scaled to the minimum and maximum
+    /// of the data.  If there are more data points than `len`, then
+    /// neighbouring points are averaged to fit.  If there are fewer,
+    /// then only that many glyphs are drawn.  If all the values are
+    /// equal (including the case of a single value), a flat line of
+    /// the lowest bar is drawn.  Values which are not finite are
+    /// drawn as spaces.  This will be clipped according to the
+    /// current and parent regions.  Returns the next X-position
+    /// after the sparkline.
+    pub fn sparkline(&mut self, y: i32, mut x: i32, len: i32, data: &[f64], hfb: u16) -> i32 {
+        const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let len = len.max(0) as usize;
+        let values: Vec<f64> = if data.len() > len {
+            (0..len)
+                .map(|i| {
+                    let part = &data[i * data.len() / len..(i + 1) * data.len() / len];
+                    part.iter().sum::<f64>() / part.len() as f64
+                })
+                .collect()
+        } else {
+            data.to_vec()
+        };
+        let finite = values.iter().copied().filter(|v| v.is_finite());
+        let min = finite.clone().fold(f64::INFINITY, f64::min);
+        let max = finite.fold(f64::NEG_INFINITY, f64::max);
+        for v in values {
+            let ch = if !v.is_finite() {
+                ' '
+            } else if max > min {
+                BARS[((v - min) / (max - min) * 7.0).round().clamp(0.0, 7.0) as usize]
+            } else {
+                BARS[0]
+            };
+            x = self.writeb(y, x, hfb, ch.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        x
+    }
+
     fn writeb(&mut self, y: i32, x: i32, mut hfb: u16, text: &[u8]) -> i32 {
-        let mut p = Scan(text);
+        let mut p = Scan(text, self.page.csx as u16);
         let y = y + self.oy;
         let mut x = x + self.ox;
 
@@ -288,6 +1225,7 @@ impl<'a> Region<'a> {
                 Meas::Glyph(inc) => {
                     x += inc as i32;
                     if x >= self.cx1 {
+                        row.normal = false;
                         row.span(x0 as u16, (self.cx1 - x0) as u16, shift as u16);
                         row.hfb(hfb);
                         row.add_slice(start.slice_to(&p));
@@ -296,9 +1234,13 @@ impl<'a> Region<'a> {
                 }
                 Meas::Attr(_) => (),
                 Meas::End => {
-                    row.span(x0 as u16, (x - x0) as u16, shift as u16);
-                    row.hfb(hfb);
-                    row.add_slice(start.0);
+                    // A zero-width span would confuse `GlyphScan`
+                    if x > x0 {
+                        row.normal = false;
+                        row.span(x0 as u16, (x - x0) as u16, shift as u16);
+                        row.hfb(hfb);
+                        row.add_slice(start.0);
+                    }
                     return x - self.ox;
                 }
             }
@@ -325,7 +1267,7 @@ impl<'a> Region<'a> {
         text: &str,
     ) -> Option<(i32, i32)> {
         let curs_len = text.len().saturating_sub(cursor);
-        let mut p = Scan(text.as_bytes());
+        let mut p = Scan(text.as_bytes(), self.page.csx as u16);
         let mut x = 0;
         let mut y = 0;
 
@@ -333,14 +1275,15 @@ impl<'a> Region<'a> {
         if shift > 0 {
             x = self.writeb(y, x, ov_hfb, b"<");
             loop {
-                let rewind = p;
                 match p.measure() {
                     Meas::End => break,
                     Meas::Attr(v) => hfb = v,
                     Meas::Glyph(inc) => {
                         shift -= inc as i32;
                         if shift < 0 {
-                            p = rewind;
+                            // Only part of a wide glyph would show, so
+                            // show spaces in its place
+                            x = self.writeb(y, x, hfb, &b"  "[..(-shift).min(2) as usize]);
                         }
                         if shift <= 0 {
                             break;
@@ -371,21 +1314,31 @@ impl<'a> Region<'a> {
                 }
                 overflow = scan_x >= sx;
                 if overflow {
-                    sx -= Scan(b">").measure_rest() as i32;
+                    sx -= Scan(b">", self.page.csx as u16).measure_rest() as i32;
                 }
             }
 
             let start = p;
             let x0 = x;
+            let hfb0 = hfb;
             loop {
                 let rewind = p;
                 match p.measure() {
-                    Meas::End => break,
+                    Meas::End => {
+                        x = self.writeb(y, x0, hfb0, start.slice_to(&p));
+                        if before_curs && x < sx {
+                            curs = Some((y, x));
+                        }
+                        if x < sx {
+                            self.region(y, x, 1, sx - x).clear(bg_hfb);
+                        }
+                        break;
+                    }
                     Meas::Attr(v) => hfb = v,
                     Meas::Glyph(inc) => {
                         if x + inc as i32 > sx {
                             p = rewind;
-                            x = self.writeb(y, x0, hfb, start.slice_to(&p));
+                            x = self.writeb(y, x0, hfb0, start.slice_to(&p));
                             if p.0.len() == curs_len && x < sx {
                                 // This will be overridden by code
                                 // below if we have another line
@@ -409,7 +1362,7 @@ impl<'a> Region<'a> {
         }
 
         if overflow {
-            self.writeb(y, sx, ov_hfb, b">");
+            self.writeb(sy - 1, sx, ov_hfb, b">");
         }
 
         curs
@@ -417,6 +1370,7 @@ impl<'a> Region<'a> {
 }
 
 /// A row of the display
+#[derive(Clone)]
 struct Row {
     /// Is the row currently normalized?
     normal: bool,
@@ -435,10 +1389,12 @@ struct Row {
     /// occurs when the page is sent to the screen.  A span is
     /// introduced with a FC-FF byte, which is invalid UTF-8.
     ///
-    ///     FC            sx utf-8-text...
-    ///     FD shift      sx utf-8-text...
-    ///     FE       xpos sx utf-8-text...
-    ///     FF shift xpos sx utf-8-text...
+    /// ```text
+    /// FC            sx utf-8-text...
+    /// FD shift      sx utf-8-text...
+    /// FE       xpos sx utf-8-text...
+    /// FF shift xpos sx utf-8-text...
+    /// ```
     ///
     /// `sx` specifies the width of the span in pixels/cells.  `xpos`
     /// specifies where to place the span.  If omitted, it follows on
@@ -520,7 +1476,7 @@ impl Row {
 
     // Write a colour-change sequence in UTF-8 (U+E000 to U+F8FF)
     fn hfb(&mut self, hfb: u16) {
-        let v = (0xE000 + hfb).max(0xF8FF);
+        let v = 0xE000 + hfb.min(0xF8FF - 0xE000);
         self.data.push(0xE0 + (v >> 12) as u8);
         self.data.push(0x80 + ((v >> 6) & 63) as u8);
         self.data.push(0x80 + (v & 63) as u8);
@@ -543,8 +1499,9 @@ impl Row {
     fn normalize(
         &mut self,
         sx: u16,
-        glyphs1: &mut VecDeque<Glyph>,
-        glyphs2: &mut VecDeque<Glyph>,
+        csx: u16,
+        glyphs1: &mut Vec<Glyph>,
+        glyphs2: &mut Vec<Glyph>,
         spare: &mut Row,
     ) {
         if !self.normal {
@@ -552,7 +1509,7 @@ impl Row {
             // immediately replaced by the initial data in 'row', so
             // any red padding remaining indicates a bug somewhere.
             glyphs1.clear();
-            glyphs1.push_back(Glyph {
+            glyphs1.push(Glyph {
                 x: 0,
                 sx,
                 shift: 0,
@@ -564,8 +1521,9 @@ impl Row {
 
             // Merge all updates on top of the background
             let data_len = self.data.len();
-            let mut scan = GlyphScan::new(Scan(&self.data[..]), sx, data_len);
+            let mut scan = GlyphScan::new(Scan(&self.data[..], csx), sx, data_len);
             let mut x = 0;
+            let mut pos = 0; // Read position in `glyphs1`
             glyphs2.clear();
             loop {
                 let g = scan.next();
@@ -575,26 +1533,24 @@ impl Row {
                 if x > g.x {
                     // Need to go backwards, so finish copying background to
                     // end of line, then swap and start again
-                    copy_glyph_range(x, sx, glyphs1, glyphs2);
+                    copy_glyph_range(x, sx, glyphs1, &mut pos, glyphs2);
                     mem::swap(glyphs1, glyphs2);
                     x = 0;
+                    pos = 0;
                     glyphs2.clear();
                 }
                 if x < g.x {
                     // Copy enough background glyphs to get to correct position
-                    copy_glyph_range(x, g.x, glyphs1, glyphs2);
+                    copy_glyph_range(x, g.x, glyphs1, &mut pos, glyphs2);
                 }
-                glyphs2.push_back(g);
+                glyphs2.push(g);
                 x = g.x + g.sx;
             }
             if x < sx {
                 // Copy remainder of background to end of line
-                copy_glyph_range(x, sx, glyphs1, glyphs2);
+                copy_glyph_range(x, sx, glyphs1, &mut pos, glyphs2);
             }
 
-            // @@@ TODO: Switch back to a plain Vec, because VecDeque
-            // makes gvec[] below inefficient
-
             // Convert `glyphs2` back to the Row representation
             mem::swap(&mut spare.data, &mut self.data);
             let data = &spare.data[..];
@@ -630,17 +1586,32 @@ impl Row {
                     self.add_slice(&data[gl.off as usize..gl.off as usize + gl.len as usize]);
                 }
             }
+            self.normal = true;
+        }
+    }
+
+    /// Report all the glyphs of a normalized row to the callback
+    fn glyphs(&self, sx: u16, csx: u16, mut cb: impl FnMut(Glyph, &[u8])) {
+        let mut scan = GlyphScan::new(Scan(&self.data[..], csx), sx, self.data.len());
+        loop {
+            let g = scan.next();
+            if g.x >= sx {
+                break;
+            }
+            cb(g, &self.data[..]);
         }
     }
 
     /// Calculate the differences between the two rows, and report all
     /// differences to the given callback.
-    fn difference(&self, new: &Row, sx: u16, mut cb: impl FnMut(Glyph, &[u8])) {
+    fn difference(&self, new: &Row, sx: u16, csx: u16, mut cb: impl FnMut(Glyph, &[u8])) {
         if self.data[..] == new.data[..] {
             return;
         }
-        let mut s0 = GlyphScan::new(Scan(&self.data[..]), sx, self.data.len());
-        let mut s1 = GlyphScan::new(Scan(&new.data[..]), sx, new.data.len());
+        let mut s0 = GlyphScan::new(Scan(&self.data[..], csx), sx, self.data.len());
+        let mut s1 = GlyphScan::new(Scan(&new.data[..], csx), sx, new.data.len());
+        s0.split_padding = true;
+        s1.split_padding = true;
         let mut g0 = s0.next();
         let mut g1 = s1.next();
         while g0.x < sx || g1.x < sx {
@@ -660,9 +1631,12 @@ impl Row {
 /// Merge one line of data read from `p` on top of the contents of the
 /// `from` glyphs, giving the `to` glyphs.  This is like splicing
 /// pieces of film or tape.  Some splices come from `from`, others
-/// from `p`.  The result is a new complete line.
-fn copy_glyph_range(x0: u16, x1: u16, from: &mut VecDeque<Glyph>, to: &mut VecDeque<Glyph>) {
-    while let Some(mut g) = from.pop_front() {
+/// from `p`.  The result is a new complete line.  Glyphs are read
+/// from `from` starting at index `pos`, which is advanced past those
+/// used up.
+fn copy_glyph_range(x0: u16, x1: u16, from: &mut [Glyph], pos: &mut usize, to: &mut Vec<Glyph>) {
+    while let Some(mut g) = from.get(*pos).copied() {
+        *pos += 1;
         if g.x + g.sx <= x0 {
             continue;
         }
@@ -678,10 +1652,11 @@ fn copy_glyph_range(x0: u16, x1: u16, from: &mut VecDeque<Glyph>, to: &mut VecDe
         if g.x + g.sx > x1 {
             // Cut off end of glyph; put glyph back because we might
             // need the same instance again for later
-            from.push_front(g);
+            *pos -= 1;
+            from[*pos] = g;
             g.sx = x1 - g.x;
         }
-        to.push_back(g);
+        to.push(g);
         if g.x + g.sx >= x1 {
             break;
         }
@@ -689,15 +1664,17 @@ fn copy_glyph_range(x0: u16, x1: u16, from: &mut VecDeque<Glyph>, to: &mut VecDe
 }
 
 /// Measured item whilst scanning across string
-enum Meas {
+pub(crate) enum Meas {
     Glyph(u16),
     Attr(u16),
     End,
 }
 
-/// Used to scan across a display string, measuring items
+/// Used to scan across a display string, measuring items.  The
+/// second field is the cell width, which all glyph widths are
+/// multiplied by.
 #[derive(Copy, Clone)]
-struct Scan<'a>(&'a [u8]);
+pub(crate) struct Scan<'a>(pub(crate) &'a [u8], pub(crate) u16);
 
 impl<'a> Scan<'a> {
     /// Grabs enough UTF-8 bytes to form one visible character
@@ -705,11 +1682,12 @@ impl<'a> Scan<'a> {
     /// available, and returns its size in x-units.  This must agree
     /// with the behaviour of the actual terminal or display device.
     /// This stops at any command byte (>= F8).
-    fn measure(&mut self) -> Meas {
+    pub(crate) fn measure(&mut self) -> Meas {
         // For now, this just assumes that one UTF-8 codepoint has a
-        // width of 1
+        // width of 1 cell, or 2 for East Asian wide characters.
+        // Combining marks and other zero-width characters are kept
+        // with the glyph before them.
         //
-        // TODO: Handle double-width CJK characters for monospace
         // TODO: Allow extending to variable-width fonts and ligatures
         // TODO: Maybe make measurement be controlled by a type parameter
         //
@@ -718,48 +1696,71 @@ impl<'a> Scan<'a> {
         match self.0.first() {
             None => return Meas::End,
             Some(v) if *v >= 0xF8 => return Meas::End, // Command, not UTF-8
-            Some(v) if *v < 0xC0 => (),
-            Some(v) if *v < 0xE0 => {
-                if self.0.len() <= 2 && (self.0[1] & 0xC0) == 0x80 {
-                    self.0 = &self.0[2..];
-                    return Meas::Glyph(1);
+            _ => (),
+        }
+        let wid = match self.codepoint() {
+            Some((v, len)) => {
+                self.0 = &self.0[len..];
+                if (0xE000..0xF900).contains(&v) {
+                    // Private-use region E000-F8FF is used for
+                    // zero-width colour-changes
+                    return Meas::Attr((v - 0xE000) as u16);
                 }
+                // A zero-width character with nothing before it to
+                // combine with is given a width of 1
+                char::from_u32(v).map_or(1, char_width).max(1) as u16
             }
-            Some(v) if *v < 0xF0 => {
-                if self.0.len() <= 3 && (self.0[1] & 0xC0) == 0x80 && (self.0[2] & 0xC0) == 0x80 {
-                    let mut v = ((u32::from(self.0[0]) & 0x0F) << 12)
-                        | ((u32::from(self.0[1]) & 0x3F) << 6);
-                    if v >= 0xE000 && v < 0xF900 {
-                        // Private-use region E000-F8FF is used for
-                        // zero-width colour-changes
-                        v |= u32::from(self.0[2]) & 0x3F;
-                        self.0 = &self.0[3..];
-                        return Meas::Attr((v - 0xE000) as u16);
-                    }
-                    self.0 = &self.0[3..];
-                    return Meas::Glyph(1);
+            None => {
+                // This handles invalid bytes which are assumed to be
+                // translated to the replacement character.  A
+                // truncated multi-byte sequence is taken whole, as it
+                // becomes a single replacement character.
+                let len = match self.0[0] {
+                    0xC0..=0xDF => 2,
+                    0xE0..=0xEF => 3,
+                    0xF0..=0xF7 => 4,
+                    _ => 1,
+                };
+                let mut skip = 1;
+                while skip < len && matches!(self.0.get(skip), Some(b) if b & 0xC0 == 0x80) {
+                    skip += 1;
                 }
+                self.0 = &self.0[skip..];
+                1
             }
-            _ => {
-                if self.0.len() <= 4
-                    && (self.0[1] & 0xC0) == 0x80
-                    && (self.0[2] & 0xC0) == 0x80
-                    && (self.0[3] & 0xC0) == 0x80
-                {
-                    self.0 = &self.0[4..];
-                    return Meas::Glyph(1);
-                }
+        };
+        while let Some((v, len)) = self.codepoint() {
+            match char::from_u32(v) {
+                Some(c) if v >= 0x300 && char_width(c) == 0 => self.0 = &self.0[len..],
+                _ => break,
+            }
+        }
+        Meas::Glyph(wid * self.1)
+    }
+
+    // Decode the UTF-8 codepoint at the start of the data, if it is
+    // valid, returning it and its length in bytes
+    fn codepoint(&self) -> Option<(u32, usize)> {
+        let len = match *self.0.first()? {
+            v if v < 0x80 => return Some((u32::from(v), 1)),
+            v if v < 0xC0 => return None,
+            v if v < 0xE0 => 2,
+            v if v < 0xF0 => 3,
+            v if v < 0xF8 => 4,
+            _ => return None,
+        };
+        let mut v = u32::from(self.0[0]) & (0x7F >> len);
+        for i in 1..len {
+            match self.0.get(i) {
+                Some(b) if b & 0xC0 == 0x80 => v = (v << 6) | u32::from(b & 0x3F),
+                _ => return None,
             }
         }
-        // This handles both 1-byte valid characters, and also invalid
-        // bytes which are assumed to be translated to the replacement
-        // character
-        self.0 = &self.0[1..];
-        Meas::Glyph(1)
+        Some((v, len))
     }
 
     /// Measure the rest of the string
-    fn measure_rest(&mut self) -> u16 {
+    pub(crate) fn measure_rest(&mut self) -> u16 {
         let mut x = 0;
         loop {
             match self.measure() {
@@ -846,6 +1847,8 @@ struct GlyphScan<'a> {
     x: u16,
     xend: u16,
     hfb: u16,
+    // Return padding as single cells, for comparison
+    split_padding: bool,
 }
 
 impl<'a> GlyphScan<'a> {
@@ -857,6 +1860,7 @@ impl<'a> GlyphScan<'a> {
             x: 0,
             xend: 0,
             hfb: ERR_HFB,
+            split_padding: false,
         }
     }
 
@@ -885,8 +1889,10 @@ impl<'a> GlyphScan<'a> {
                 let start = self.p;
                 match self.p.measure() {
                     Meas::Glyph(inc) => {
+                        // A shifted glyph only takes up the part
+                        // which remains visible
                         let x0 = self.x;
-                        self.x += inc;
+                        self.x += inc - shift;
                         let shift0 = shift;
                         shift = 0;
                         if x0 < self.xend {
@@ -905,11 +1911,17 @@ impl<'a> GlyphScan<'a> {
                     Meas::End => {
                         if self.x < self.xend {
                             let x0 = self.x;
-                            self.x = self.xend;
-                            self.xend = 0;
+                            self.x = if self.split_padding {
+                                (x0 + self.p.1).min(self.xend)
+                            } else {
+                                self.xend
+                            };
+                            if self.x == self.xend {
+                                self.xend = 0;
+                            }
                             return Glyph {
                                 x: x0,
-                                sx: self.xend - self.x,
+                                sx: self.x - x0,
                                 shift: 0,
                                 hfb: self.hfb,
                                 len: 0,
@@ -917,6 +1929,7 @@ impl<'a> GlyphScan<'a> {
                                 off: 0,
                             };
                         }
+                        self.x = self.x.min(self.xend);
                         self.xend = 0;
                     }
                 }
@@ -924,3 +1937,563 @@ impl<'a> GlyphScan<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Features, FrameReset};
+
+    fn termout() -> TermOut {
+        let mut t = TermOut::new(Features::default());
+        t.set_size(24, 80);
+        t
+    }
+
+    // Take the flushed output, with ESC shown as `^` for readability
+    fn output(t: &mut TermOut) -> String {
+        t.flush();
+        let v = String::from_utf8_lossy(t.data_to_flush()).replace('\x1b', "^");
+        t.drain_flush();
+        v
+    }
+
+    #[test]
+    fn render_region_to() {
+        let mut t = termout();
+        let mut p = Page::new(3, 10, 88);
+        let prev = p.clone();
+        p.full().write(1, 2, 12, "hello");
+        p.render_region_to(&prev, (0, 0, 3, 10), &mut t);
+        assert_eq!(output(&mut t), "^[2;3H^[0;34;41mhello");
+
+        // Only differing glyphs are output
+        let prev = p.clone();
+        p.full().write(1, 3, 12, "ipp");
+        p.full().write(2, 0, 88, "x\u{e00c}y");
+        p.render_region_to(&prev, (0, 0, 3, 10), &mut t);
+        assert_eq!(
+            output(&mut t),
+            "^[2;4H^[0;34;41mipp^[3;1H^[0;39;49mx^[0;34;41my"
+        );
+
+        // Changes outside the region are not output
+        let prev = p.clone();
+        p.full().region(0, 0, 3, 5).clear(11);
+        p.render_region_to(&prev, (0, 0, 3, 4), &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;34;44m    ^[2;1H    ^[3;1H    ");
+    }
+
+    #[test]
+    fn set_substitutions() {
+        let mut t = termout();
+        let mut p = Page::new(1, 6, 88);
+        let prev = p.clone();
+        p.set_substitutions(&[('─', '-'), ('漢', '字')]);
+        p.full().write(0, 0, 88, "─漢x");
+        p.render_region_to(&prev, (0, 0, 1, 6), &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;39;49m-字x");
+
+        // Fallbacks take the same width, so later glyphs stay in place
+        let prev = p.clone();
+        p.full().write(0, 3, 88, "y");
+        p.render_region_to(&prev, (0, 0, 1, 6), &mut t);
+        assert_eq!(output(&mut t), "^[1;4H^[0;39;49my");
+
+        // The page itself is unchanged
+        assert_eq!(p.to_string_plain(), "─漢y  ");
+    }
+
+    #[test]
+    fn render_high_hfb() {
+        // `H` digits above 1 are still rendered in the right colours
+        let mut t = termout();
+        let mut p = Page::new(1, 20, 88);
+        let prev = p.clone();
+        p.full().write(0, 0, 312, "ab");
+        p.full().region(0, 2, 1, 3).clear(288);
+        p.full().write(0, 5, 240, "c");
+        p.render_region_to(&prev, (0, 0, 1, 20), &mut t);
+        assert_eq!(
+            output(&mut t),
+            "^[1;1H^[0;1;34;41mab^[0;39;49m   ^[0;32;40mc"
+        );
+    }
+
+    #[test]
+    fn unchanged_frame_outputs_nothing() {
+        let mut t = termout();
+        let mut p = Page::new(3, 3, 88);
+        let prev = p.clone();
+        p.full().write(0, 0, 88, "ab");
+        p.set_cursor(Some((2, 2)));
+        p.render_region_to(&prev, (0, 0, 3, 3), &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;39;49mab^[3;3H");
+        let prev = p.clone();
+        p.render_region_to(&prev, (0, 0, 3, 3), &mut t);
+        assert_eq!(output(&mut t), "");
+
+        // Moving the cursor elsewhere means it must be moved back
+        t.at(0, 0);
+        p.render_region_to(&prev, (0, 0, 3, 3), &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[3;3H");
+    }
+
+    #[test]
+    fn inset() {
+        let mut p = Page::new(4, 8, 88);
+        let mut full = p.full();
+        let mut r = full.inset(1, 2, 1, 1);
+        assert_eq!((r.sy(), r.sx()), (2, 5));
+        r.write(0, 0, 88, "abcdefgh");
+        r.write(1, 0, 88, "ijklmnop");
+        assert_eq!(
+            p.to_string_plain(),
+            "        \n abcde  \n ijklm  \n        "
+        );
+
+        // Negative margins are still clipped to the parent region
+        let mut p = Page::new(4, 8, 88);
+        let mut full = p.full();
+        let mut outer = full.region(1, 1, 2, 6);
+        let mut r = outer.inset(-1, -1, -1, -1);
+        assert_eq!((r.sy(), r.sx()), (4, 8));
+        r.write(0, 0, 88, "abcdefgh");
+        r.write(1, 0, 88, "ijklmnop");
+        assert_eq!(
+            p.to_string_plain(),
+            "        \n jklmno \n        \n        "
+        );
+
+        let mut p = Page::new(4, 8, 88);
+        let mut full = p.full();
+        let r = full.inset(3, 5, 3, 5);
+        assert_eq!((r.sy(), r.sx()), (0, 0));
+    }
+
+    #[test]
+    fn sparkline() {
+        let mut p = Page::new(1, 10, 88);
+        let data = [0.0, 5.0, 10.0, 10.0, 10.0, 0.0, 0.0];
+        assert_eq!(p.full().sparkline(0, 0, 10, &data, 88), 7);
+        assert_eq!(p.to_string_plain(), "▁▅███▁▁   ");
+
+        // Points are averaged to fit, a single value is flat, and
+        // non-finite values are blank
+        assert_eq!(p.full().sparkline(0, 0, 2, &[0.0, 2.0, 4.0, 6.0], 88), 2);
+        assert_eq!(p.full().sparkline(0, 3, 3, &[3.0], 88), 4);
+        assert_eq!(p.full().sparkline(0, 5, 3, &[], 88), 5);
+        assert_eq!(p.full().sparkline(0, 7, 3, &[1.0, f64::NAN, 2.0], 88), 10);
+        assert_eq!(p.to_string_plain(), "▁██▁█▁▁▁ █");
+    }
+
+    #[test]
+    fn layout_constraints() {
+        use Constraint::*;
+        assert_eq!(
+            layout(100, &[Percent(30), Fixed(10), Fill(1)]),
+            [(0, 30), (30, 10), (40, 60)]
+        );
+        // The fill parts exactly use up the space left
+        assert_eq!(
+            layout(10, &[Fill(1), Fill(1), Fill(1)]),
+            [(0, 3), (3, 3), (6, 4)]
+        );
+        // Parts that don't fit are shrunk
+        assert_eq!(layout(5, &[Fixed(4), Fixed(4)]), [(0, 4), (4, 1)]);
+    }
+
+    // Simple deterministic generator for the randomised tests
+    struct Rng(u32);
+
+    impl Rng {
+        fn below(&mut self, n: u32) -> u32 {
+            self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+            (self.0 >> 8) % n
+        }
+    }
+
+    // Make many overlapping writes to a long line, at random
+    // positions, some of them going backwards, recording the expected
+    // cells in `model`
+    fn random_writes(p: &mut Page, model: &mut [(char, u16)], rng: &mut Rng, count: usize) {
+        let sx = model.len() as u32;
+        for _ in 0..count {
+            let x = rng.below(sx + 40) as i32 - 20;
+            let len = rng.below(40) as i32;
+            let hfb = 10 + rng.below(5) as u16;
+            let ch = char::from(b'a' + rng.below(26) as u8);
+            let text: String = std::iter::repeat(ch).take(len as usize).collect();
+            p.full().write(0, x, hfb, &text);
+            for cx in x.max(0)..(x + len).min(sx as i32) {
+                model[cx as usize] = (ch, hfb);
+            }
+        }
+    }
+
+    #[test]
+    fn long_line_stress() {
+        const SX: i32 = 3000;
+        let mut rng = Rng(1);
+        let mut p = Page::new(1, SX, 88);
+        let mut model = vec![(' ', 88); SX as usize];
+        // Every glyph differs from this, so all are reported
+        let blank = Page::new(1, SX, 0);
+        for _ in 0..20 {
+            random_writes(&mut p, &mut model, &mut rng, 300);
+            let mut cells = Vec::new();
+            p.for_each_change(&blank, |_, x, hfb, text| {
+                assert_eq!(x as usize, cells.len());
+                cells.push((text.chars().next().unwrap(), hfb));
+            });
+            assert!(cells == model);
+        }
+    }
+
+    // Benchmark for normalizing very long lines.  Run with `cargo
+    // test --release --all-features -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn long_line_bench() {
+        const SX: i32 = 30000;
+        let mut rng = Rng(1);
+        let mut p = Page::new(1, SX, 88);
+        let mut model = vec![(' ', 88); SX as usize];
+        let start = std::time::Instant::now();
+        for _ in 0..100 {
+            random_writes(&mut p, &mut model, &mut rng, 1000);
+            p.normalize();
+        }
+        println!(
+            "100 x 1000 writes to a {} column line: {:?}",
+            SX,
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn normalize_after_invisible_write() {
+        // Writes that leave nothing visible must not add spans
+        for (x, s) in [(0, ""), (-1, "t"), (-2, "tt"), (-2, "\u{e00c}tt"), (3, "")] {
+            let mut p = Page::new(1, 10, 88);
+            p.full().write(0, x, 11, s);
+            p.normalize();
+            let mut n = 0;
+            p.for_each_change(&Page::new(1, 10, 88), |_, _, _, _| n += 1);
+            assert_eq!(n, 0, "x={} {:?}", x, s);
+        }
+    }
+
+    #[test]
+    fn hfb_chars() {
+        assert_eq!(hfb_char(0), '\u{E000}');
+        assert_eq!(hfb_char(6399), '\u{F8FF}');
+        assert_eq!(hfb_char(u16::MAX), '\u{F8FF}');
+        let mut s = String::from("a");
+        push_hfb(&mut s, 12);
+        s.push('b');
+        let mut p = Page::new(1, 4, 88);
+        p.full().write(0, 0, 11, &s);
+        assert_eq!(p.cell_at(0, 0), Some(("a".into(), 11, false)));
+        assert_eq!(p.cell_at(0, 1), Some(("b".into(), 12, false)));
+    }
+
+    #[test]
+    fn reserved() {
+        let mut t = termout();
+        let mut p = Page::new(2, 6, 88);
+        p.reserve((0, 2, 2, 2), b"IMG".to_vec(), false);
+        let prev = Page::new(2, 6, 88);
+        p.full().write(0, 0, 12, "abcdef");
+        p.render_region_to(&prev, (0, 0, 2, 6), &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;34;41mab^[1;5Hef^[1;3HIMG");
+        let prev = p.clone();
+        p.full().write(1, 1, 12, "xyz");
+        p.render_region_to(&prev, (0, 0, 2, 6), &mut t);
+        assert_eq!(output(&mut t), "^[2;2H^[0;34;41mx");
+        let prev = p.clone();
+        p.clear_reserved();
+        p.render_region_to(&prev, (0, 0, 2, 6), &mut t);
+        assert_eq!(
+            output(&mut t),
+            "^[1;1H^[0;34;41mabcdef^[2;1H^[0;39;49m ^[0;34;41mxyz^[0;39;49m  "
+        );
+    }
+
+    #[test]
+    fn reserved_always() {
+        let mut t = termout();
+        let mut p = Page::new(2, 6, 88);
+        p.reserve((1, 0, 1, 2), b"IMG".to_vec(), true);
+        let prev = p.clone();
+        p.render_region_to(&prev, (0, 0, 2, 6), &mut t);
+        assert_eq!(output(&mut t), "^[2;1HIMG");
+        p.render_region_to(&prev, (0, 0, 2, 6), &mut t);
+        assert_eq!(output(&mut t), "^[2;1HIMG");
+        // Not output if outside the rectangle rendered
+        p.render_region_to(&prev, (0, 0, 1, 6), &mut t);
+        assert_eq!(output(&mut t), "");
+    }
+
+    #[test]
+    fn write_cells() {
+        let mut p = Page::new(1, 10, 88);
+        let prev = p.clone();
+        let cells = [(12, 'a'), (12, 'b'), (13, 'c'), (13, '界')];
+        assert_eq!(p.full().write_cells(0, 1, &cells), 6);
+        let mut v = Vec::new();
+        p.for_each_change(&prev, |_, x, hfb, t| v.push(format!("{x},{hfb},{t}")));
+        assert_eq!(v, ["1,12,a", "2,12,b", "3,13,c", "4,13,界"]);
+
+        // Clipped to the region, but still measured
+        let mut p = Page::new(1, 10, 88);
+        assert_eq!(p.full().region(0, 0, 1, 3).write_cells(0, 1, &cells), 6);
+        let mut v = Vec::new();
+        p.for_each_change(&prev, |_, x, hfb, t| v.push(format!("{x},{hfb},{t}")));
+        assert_eq!(v, ["1,12,a", "2,12,b"]);
+    }
+
+    #[test]
+    fn write_vertical() {
+        // Text running off the bottom of the region is clipped, but
+        // the returned row is still past the end of the text
+        let mut p = Page::new(4, 3, 88);
+        let prev = p.clone();
+        let mut full = p.full();
+        let mut r = full.region(1, 0, 2, 3);
+        assert_eq!(r.write_vertical(0, 1, 12, "a\u{E00D}界c"), 3);
+        let mut v = Vec::new();
+        p.for_each_change(&prev, |y, x, hfb, t| v.push(format!("{y},{x},{hfb},{t}")));
+        assert_eq!(v, ["1,1,12,a", "2,1,13,界"]);
+    }
+
+    #[test]
+    fn frame_reset() {
+        let mut t = termout();
+        t.set_frame_reset(FrameReset::IfNeeded);
+        let mut p = Page::new(1, 4, 88);
+        let prev = p.clone();
+        p.full().write(0, 0, 12, "a");
+        p.full().write(0, 1, 88, "b");
+        p.render_region_to(&prev, (0, 0, 1, 4), &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;34;41ma^[0;39;49mb");
+        let prev = p.clone();
+        p.full().write(0, 2, 12, "c");
+        p.render_region_to(&prev, (0, 0, 1, 4), &mut t);
+        assert_eq!(output(&mut t), "^[0;34;41mc^[0m");
+    }
+
+    #[test]
+    fn progressive() {
+        let mut t = termout();
+        let mut shown = Page::new(3, 4, 88);
+        let mut p = shown.clone();
+        p.full().write(0, 0, 88, "aaaa");
+        p.full().write(1, 0, 88, "bbbb");
+        p.full().write(2, 0, 88, "cccc");
+        p.render_rows_to(&mut shown, 0, 3, &mut t);
+        output(&mut t);
+        // Two-phase update: top row first, bottom rows later
+        p.full().write(0, 0, 88, "xx");
+        p.full().write(2, 0, 88, "yy");
+        p.render_rows_to(&mut shown, 0, 1, &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;39;49mxx");
+        p.render_rows_to(&mut shown, 1, 2, &mut t);
+        assert_eq!(output(&mut t), "^[3;1H^[0;39;49myy");
+        p.render_rows_to(&mut shown, 0, 3, &mut t);
+        assert_eq!(output(&mut t), "");
+    }
+
+    #[test]
+    fn progressive_after_resize() {
+        // Rows not yet rendered are redrawn in full, whatever their
+        // contents, even spaces in the colour used as filler
+        let mut t = termout();
+        let mut shown = Page::new(1, 1, 88);
+        let mut p = Page::new(2, 3, ERR_HFB);
+        p.render_rows_to(&mut shown, 0, 1, &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;1;33;41m   ");
+        p.render_rows_to(&mut shown, 1, 1, &mut t);
+        assert_eq!(output(&mut t), "^[2;1H^[0;1;33;41m   ");
+        p.render_rows_to(&mut shown, 0, 2, &mut t);
+        assert_eq!(output(&mut t), "");
+    }
+
+    #[test]
+    fn last_cursor() {
+        let mut t = termout();
+        assert_eq!(t.last_cursor(), None);
+        let mut p = Page::new(3, 10, 88);
+        let prev = p.clone();
+        p.full().write(1, 2, 12, "hi");
+        p.set_cursor(Some((2, 5)));
+        p.render_region_to(&prev, (0, 0, 3, 10), &mut t);
+        assert_eq!(t.last_cursor(), Some((2, 5)));
+        t.at(-1, -1);
+        assert_eq!(t.last_cursor(), Some((23, 79)));
+    }
+
+    #[test]
+    fn row_hfb() {
+        let hfbs = [0, 1, 88, 4095, 6399];
+        let mut row = Row::new(8, 12);
+        row.normal = false;
+        for (x, &hfb) in hfbs.iter().enumerate() {
+            row.span(x as u16, 1, 0);
+            row.hfb(hfb);
+            row.add_slice(b"x");
+        }
+        let mut spare = Row::new(8, 0);
+        row.normalize(8, 1, &mut Vec::new(), &mut Vec::new(), &mut spare);
+        let mut found = Vec::new();
+        row.glyphs(8, 1, |g, _| found.push(g.hfb));
+        assert_eq!(found, [0, 1, 88, 4095, 6399, 12]);
+    }
+
+    #[test]
+    fn measure_utf8() {
+        let m = |b: &[u8]| Scan(b, 1).measure_rest();
+        assert_eq!(m("aé€😀b".as_bytes()), 6);
+        assert_eq!(m("€".as_bytes()), 1);
+        // Truncated sequences at the end count as one replacement
+        assert_eq!(m(b"ab\xE2\x82"), 3);
+        assert_eq!(m(b"ab\xF0\x9F\x98"), 3);
+        assert_eq!(m(b"\xE2\x82\xF8\x01"), 1);
+        assert_eq!(m(b"\xC3"), 1);
+        assert_eq!(m(b"\x82\x82"), 2);
+    }
+
+    #[test]
+    fn cjk() {
+        let mut t = termout();
+        let mut p = Page::new(2, 10, 88);
+        let prev = Page::new(2, 10, 88);
+        let mut r = p.full();
+        assert_eq!(r.write(0, 0, 88, "a漢字b"), 6);
+        assert_eq!(r.measure("漢字ｱ"), 5);
+        // Clipped on the left: partial glyph shows as a space
+        let mut r2 = r.region(1, 1, 1, 9);
+        assert_eq!(r2.write(0, -1, 88, "漢字c"), 4);
+        p.render_region_to(&prev, (0, 0, 2, 10), &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;39;49ma漢字b^[2;2H 字c");
+        // Overwrite right half of a wide glyph
+        let prev = p.clone();
+        p.full().write(0, 2, 88, "x");
+        p.render_region_to(&prev, (0, 0, 2, 10), &mut t);
+        assert_eq!(output(&mut t), "^[1;2H^[0;39;49m x");
+    }
+
+    #[test]
+    fn combining() {
+        let m = |b: &str| Scan(b.as_bytes(), 1).measure_rest();
+        assert_eq!(m("caf\u{e9}"), m("cafe\u{301}"));
+        assert_eq!(m("e\u{301}\u{302}x"), 2);
+        assert_eq!(m("\u{301}x"), 2);
+        assert_eq!(m("a\u{e00c}\u{301}"), 2);
+        let mut t = termout();
+        let mut p = Page::new(1, 10, 88);
+        let prev = p.clone();
+        assert_eq!(p.full().write(0, 0, 88, "cafe\u{301}!"), 5);
+        p.render_region_to(&prev, (0, 0, 1, 10), &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;39;49mcafe\u{301}!");
+        let prev = p.clone();
+        p.full().write(0, 3, 88, "e\u{302}");
+        p.render_region_to(&prev, (0, 0, 1, 10), &mut t);
+        assert_eq!(output(&mut t), "^[1;4H^[0;39;49me\u{302}");
+    }
+
+    #[test]
+    fn render_diff_unchanged() {
+        let mut t = termout();
+        t.set_frame_reset(FrameReset::Always);
+        let mut p = Page::new(2, 4, 88);
+        let prev = p.clone();
+        p.full().write(0, 0, 12, "ab");
+        p.render_diff(&prev, &mut t);
+        assert_eq!(output(&mut t), "^[1;1H^[0;34;41mab^[0m");
+        // No bytes at all, not even the frame's attribute reset
+        let prev = p.clone();
+        p.render_diff(&prev, &mut t);
+        assert_eq!(output(&mut t), "");
+        p.render_region_to(&prev, (1, 0, 1, 4), &mut t);
+        assert_eq!(output(&mut t), "");
+    }
+
+    #[test]
+    fn blit() {
+        let mut src = Page::new(3, 5, 12);
+        src.full().write(0, 0, 12, "┌───┐");
+        src.full().write(1, 0, 12, "│漢x│");
+        src.full().write(2, 0, 12, "└───┘");
+        let mut dst = Page::new(4, 8, 88);
+        dst.full().region(1, 2, 3, 5).blit_from(&src, 0, 0);
+        assert_eq!(
+            dst.to_string_plain(),
+            "        \n  ┌───┐ \n  │漢x│ \n  └───┘ "
+        );
+        // Colours are copied too
+        assert_eq!(dst.cell_at(1, 2).map(|c| c.1), Some(12));
+        assert_eq!(dst.cell_at(1, 7).map(|c| c.1), Some(88));
+        // Cut through the wide glyph, and clipped at the page edge
+        let mut dst = Page::new(2, 4, 88);
+        dst.full().region(0, 1, 2, 10).blit_from(&src, 1, 2);
+        assert_eq!(dst.to_string_plain(), "  x│\n ──┘");
+        // Scroll up by one line
+        let mut p = src.clone();
+        p.full().blit_from(&src, 1, 0);
+        assert_eq!(p.to_string_plain(), "│漢x│\n└───┘\n└───┘");
+    }
+
+    #[test]
+    fn cell_at() {
+        let mut p = Page::new(2, 6, 88);
+        p.full().write(0, 1, 12, "漢e\u{301}");
+        assert_eq!(p.cell_at(0, 0), Some((" ".into(), 88, false)));
+        assert_eq!(p.cell_at(0, 1), Some(("漢".into(), 12, false)));
+        assert_eq!(p.cell_at(0, 2), Some(("漢".into(), 12, true)));
+        assert_eq!(p.cell_at(0, 3), Some(("e\u{301}".into(), 12, false)));
+        assert_eq!(p.cell_at(0, 5), Some((" ".into(), 88, false)));
+        assert_eq!(p.cell_at(0, 6), None);
+        assert_eq!(p.cell_at(-1, 0), None);
+        // Overwriting half of the wide glyph leaves the other half
+        // as a plain space
+        p.full().write(0, 2, 99, "z");
+        assert_eq!(p.cell_at(0, 1), Some((" ".into(), 12, false)));
+        assert_eq!(p.cell_at(0, 2), Some(("z".into(), 99, false)));
+    }
+
+    #[test]
+    fn set_cell_width() {
+        let changes = |p: &mut Page, prev: &Page| {
+            let mut v = Vec::new();
+            p.for_each_change(prev, |_, x, hfb, t| v.push(format!("{x},{hfb},{t}")));
+            v
+        };
+
+        // Text that fitted its span overflows at the wider cells
+        let mut p = Page::new(1, 8, 88);
+        p.full().write(0, 0, 12, "abc");
+        p.full().write(0, 3, 13, "de");
+        let prev = p.clone();
+        p.set_cell_width(2);
+        assert_eq!(p.cell_sx(), 2);
+        assert_eq!(p.measure("a漢"), 6);
+        // Everything is redrawn, as the cells are a different size
+        assert_eq!(
+            changes(&mut p, &prev),
+            ["0,12,a", "2,12, ", "3,13,d", "5,88,  "]
+        );
+
+        // And underflows at narrower cells, leaving padding
+        let mut p = Page::new(1, 8, 88);
+        p.set_cell_width(2);
+        p.full().write(0, 0, 12, "a漢");
+        p.full().write(0, 6, 13, "c");
+        p.set_cell_width(1);
+        let prev = Page::new(1, 8, 88);
+        assert_eq!(
+            changes(&mut p, &prev),
+            ["0,12,a", "1,12,漢", "3,12, ", "4,12, ", "5,12, ", "6,13,c", "7,13, "]
+        );
+        assert_eq!(p.to_string_plain(), "a漢   c ");
+    }
+}
@@ -0,0 +1,228 @@
+// Decoding of replies sent by the terminal in response to queries
+//
+// These arrive mixed in with the keypresses in the input stream, so
+// whilst a query is outstanding, the input is checked for replies
+// before decoding keys.
+
+/// A reply from the terminal
+pub(crate) enum Reply {
+    /// Text area size in characters: rows, columns
+    WindowSize(i32, i32),
+    /// XTGETTCAP reply: capability name, and the value if the
+    /// capability was found (empty for a boolean capability).  The
+    /// name may be empty if the terminal didn't echo it back.
+    Tcap(String, Option<String>),
+    /// DECRQSS reply: the setting requested, or `None` if the request
+    /// was not recognised
+    Decrqss(Option<String>),
+    /// Primary device attributes (DA1) reply: the parameters, the
+    /// first being the conformance level and the rest the features
+    DeviceAttrs(Vec<i32>),
+    /// DECRQM reply for a private mode: the mode, and its state,
+    /// where 0 means that the mode is not recognised
+    Mode(i32, i32),
+}
+
+/// Result of trying to decode a reply
+pub(crate) enum Decode {
+    /// A complete reply, and the count of bytes it used
+    Reply(usize, Reply),
+    /// Could be the start of a reply, but more bytes are needed
+    Partial,
+    /// Not a reply, so decode as a key instead
+    NotReply,
+}
+
+/// Attempt to decode a reply from the start of `data`
+pub(crate) fn decode(data: &[u8]) -> Decode {
+    if data.len() < 2 {
+        return match data {
+            [] | [27] => Decode::Partial,
+            _ => Decode::NotReply,
+        };
+    }
+    if data[..2] == b"\x1BP"[..] {
+        return decode_dcs(data);
+    }
+    if data[..2] != b"\x1B["[..] {
+        return Decode::NotReply;
+    }
+
+    // Parameter bytes, then intermediate bytes, then final byte
+    let mut end = 2;
+    while end < data.len() && (0x30..=0x3F).contains(&data[end]) {
+        end += 1;
+    }
+    let params = &data[2..end];
+    while end < data.len() && (0x20..=0x2F).contains(&data[end]) {
+        end += 1;
+    }
+    let inter = &data[2 + params.len()..end];
+    let fin = match data.get(end) {
+        None => return Decode::Partial,
+        Some(&b) => b,
+    };
+    let count = end + 1;
+
+    // Private parameters, as used by DA1 and DECRQM replies
+    if let [b'?', rest @ ..] = params {
+        let nums: Option<Vec<i32>> = rest
+            .split(|&b| b == b';')
+            .map(|p| std::str::from_utf8(p).ok()?.parse::<i32>().ok())
+            .collect();
+        return match (nums, inter, fin) {
+            (Some(nums), b"", b'c') => Decode::Reply(count, Reply::DeviceAttrs(nums)),
+            (Some(nums), b"$", b'y') if nums.len() == 2 => {
+                Decode::Reply(count, Reply::Mode(nums[0], nums[1]))
+            }
+            _ => Decode::NotReply,
+        };
+    }
+    if !inter.is_empty() {
+        return Decode::NotReply;
+    }
+
+    let mut nums = params.split(|&b| b == b';').map(|p| {
+        std::str::from_utf8(p)
+            .ok()
+            .and_then(|p| p.parse::<i32>().ok())
+    });
+    match fin {
+        b't' => {
+            if let (Some(Some(8)), Some(Some(sy)), Some(Some(sx)), None) =
+                (nums.next(), nums.next(), nums.next(), nums.next())
+            {
+                return Decode::Reply(count, Reply::WindowSize(sy, sx));
+            }
+            Decode::NotReply
+        }
+        _ => Decode::NotReply,
+    }
+}
+
+// Decode a DCS reply, terminated by ST (`ESC \`)
+fn decode_dcs(data: &[u8]) -> Decode {
+    let end = match data.windows(2).position(|w| w == b"\x1B\\") {
+        Some(end) => end,
+        None => return Decode::Partial,
+    };
+    let body = &data[2..end];
+    let count = end + 2;
+    match body {
+        [b'1', b'$', b'r', rest @ ..] => {
+            let setting = String::from_utf8_lossy(rest).into_owned();
+            return Decode::Reply(count, Reply::Decrqss(Some(setting)));
+        }
+        [b'0', b'$', b'r', ..] => return Decode::Reply(count, Reply::Decrqss(None)),
+        _ => (),
+    }
+    let (found, rest) = match body {
+        [b'1', b'+', b'r', rest @ ..] => (true, rest),
+        [b'0', b'+', b'r', rest @ ..] => (false, rest),
+        _ => return Decode::NotReply,
+    };
+    let mut it = rest.splitn(2, |&b| b == b'=');
+    let name = it.next().and_then(unhex);
+    let value = it.next().map(unhex);
+    match (name, value) {
+        (Some(name), None) => {
+            let value = if found { Some(String::new()) } else { None };
+            Decode::Reply(count, Reply::Tcap(name, value))
+        }
+        (Some(name), Some(Some(value))) if found => {
+            Decode::Reply(count, Reply::Tcap(name, Some(value)))
+        }
+        _ => Decode::NotReply,
+    }
+}
+
+/// Get the cursor style number from a DECRQSS reply to a DECSCUSR
+/// request, e.g. "2 q" gives 2
+pub(crate) fn cursor_style(setting: &str) -> Option<u8> {
+    match setting.strip_suffix(" q")? {
+        "" => Some(0),
+        num => num.parse().ok().filter(|n| *n <= 6),
+    }
+}
+
+/// Encode a string as uppercase hex, as used by XTGETTCAP
+pub(crate) fn hex(text: &str) -> String {
+    text.bytes().map(|b| format!("{:02X}", b)).collect()
+}
+
+// Decode a hex string, as used by XTGETTCAP
+fn unhex(data: &[u8]) -> Option<String> {
+    if data.len() & 1 != 0 {
+        return None;
+    }
+    let bytes = data
+        .chunks(2)
+        .map(|p| u8::from_str_radix(std::str::from_utf8(p).ok()?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_style_reply() {
+        match decode(b"\x1BP1$r4 q\x1B\\x") {
+            Decode::Reply(10, Reply::Decrqss(Some(s))) => assert_eq!(cursor_style(&s), Some(4)),
+            _ => panic!("expecting DECRQSS reply"),
+        }
+        assert!(matches!(
+            decode(b"\x1BP0$r\x1B\\"),
+            Decode::Reply(7, Reply::Decrqss(None))
+        ));
+        assert!(matches!(decode(b"\x1BP1$r2 q"), Decode::Partial));
+        assert_eq!(cursor_style(" q"), Some(0));
+        assert_eq!(cursor_style("9 q"), None);
+        assert_eq!(cursor_style("0m"), None);
+    }
+
+    #[test]
+    fn device_attrs_reply() {
+        match decode(b"\x1B[?62;4;22cx") {
+            Decode::Reply(11, Reply::DeviceAttrs(v)) => assert_eq!(v, [62, 4, 22]),
+            _ => panic!("expecting DA1 reply"),
+        }
+        assert!(matches!(decode(b"\x1B[?62;4"), Decode::Partial));
+        assert!(matches!(decode(b"\x1B[?62;x4c"), Decode::NotReply));
+    }
+
+    #[test]
+    fn mode_reply() {
+        assert!(matches!(
+            decode(b"\x1B[?2026;2$y"),
+            Decode::Reply(11, Reply::Mode(2026, 2))
+        ));
+        assert!(matches!(
+            decode(b"\x1B[?2004;0$yx"),
+            Decode::Reply(11, Reply::Mode(2004, 0))
+        ));
+        assert!(matches!(decode(b"\x1B[?2026;2$"), Decode::Partial));
+        assert!(matches!(decode(b"\x1B[?2026$y"), Decode::NotReply));
+        assert!(matches!(decode(b"\x1B[?2026;1;1$y"), Decode::NotReply));
+    }
+
+    #[test]
+    fn intermediates() {
+        // Intermediates must match the final byte
+        assert!(matches!(decode(b"\x1B[?2026;2y"), Decode::NotReply));
+        assert!(matches!(decode(b"\x1B[?62$c"), Decode::NotReply));
+        assert!(matches!(decode(b"\x1B[?2026;2 $y"), Decode::NotReply));
+        // Only private replies take intermediates
+        assert!(matches!(decode(b"\x1B[8;24;80$t"), Decode::NotReply));
+        assert!(matches!(
+            decode(b"\x1B[8;24;80t"),
+            Decode::Reply(10, Reply::WindowSize(24, 80))
+        ));
+        // Keys are not replies
+        assert!(matches!(decode(b"\x1B[A"), Decode::NotReply));
+        assert!(matches!(decode(b"\x1B[1;5A"), Decode::NotReply));
+        assert!(matches!(decode(b"a"), Decode::NotReply));
+        assert!(matches!(decode(b"\x1B"), Decode::Partial));
+    }
+}
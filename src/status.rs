@@ -0,0 +1,248 @@
+use crate::{Page, Region, TermOut};
+
+/// Status bar at the bottom of the screen, below a scroll region
+///
+/// This is for the kind of app that writes directly to the
+/// [`TermOut`], for example a pager, but which also wants a status
+/// area of a few lines at the bottom of the screen.  A scroll region
+/// is set up covering all the lines above the status bar, so that
+/// output scrolling there leaves the status bar alone.  The status
+/// bar is drawn using a [`Region`] and only the changes are sent to
+/// the terminal on each render.
+///
+/// Call [`StatusBar::resize`] on every `resize` message from the
+/// [`Terminal`], before drawing anything else, since setting the
+/// scroll region moves the cursor to the top-left.  The scroll
+/// region is reset automatically on cleanup.
+///
+/// [`Region`]: struct.Region.html
+/// [`StatusBar::resize`]: struct.StatusBar.html#method.resize
+/// [`TermOut`]: struct.TermOut.html
+/// [`Terminal`]: struct.Terminal.html
+pub struct StatusBar {
+    lines: i32,
+    hfb: u16,
+    size: (i32, i32),
+    page: Page,
+    prev: Page,
+}
+
+impl StatusBar {
+    /// Create a status bar of the given number of lines, initially
+    /// cleared to spaces of the given `hfb` colour
+    pub fn new(lines: i32, hfb: u16) -> Self {
+        Self {
+            lines: lines.max(0),
+            hfb,
+            size: (0, 0),
+            page: Page::new(0, 0, hfb),
+            prev: Page::new(0, 0, hfb),
+        }
+    }
+
+    /// Get the number of lines in the status bar
+    pub fn lines(&self) -> i32 {
+        self.lines
+    }
+
+    /// Get the number of lines in the scroll region above the status
+    /// bar, according to the terminal size at the last resize
+    pub fn scroll_lines(&self) -> i32 {
+        (self.size.0 - self.lines).max(0)
+    }
+
+    /// Adapt to the current terminal size.  This sets up the scroll
+    /// region, and arranges for the whole status bar to be redrawn
+    /// on the next render.  The cursor is left at the bottom-left of
+    /// the scroll region.  The status bar contents are cleared, so
+    /// need drawing again.
+    pub fn resize(&mut self, out: &mut TermOut) {
+        let (sy, sx) = out.size();
+        self.size = (sy, sx);
+        self.page = Page::new(sy, sx, self.hfb);
+        self.prev = Page::new(0, 0, self.hfb);
+        let scroll = self.scroll_lines();
+        if scroll > 1 {
+            out.scroll_region(0, scroll - 1).at(scroll - 1, 0);
+        }
+    }
+
+    /// Get a [`Region`] covering the status bar, for drawing on
+    ///
+    /// [`Region`]: struct.Region.html
+    pub fn region(&mut self) -> Region<'_> {
+        let (y, sy, sx) = (self.scroll_lines(), self.lines, self.size.1);
+        self.page.region(y, 0, sy, sx)
+    }
+
+    /// Add to `out` the output required to bring the status bar on
+    /// the terminal up to date.  The cursor position is saved and
+    /// restored around the update, so this may be called at any
+    /// time without disturbing output going to the scroll region.
+    pub fn render(&mut self, out: &mut TermOut) {
+        let rect = (self.scroll_lines(), 0, self.lines, self.size.1);
+        let mark = out.mark();
+        out.save_cursor();
+        let start = out.mark();
+        self.page.render_region_to(&self.prev, rect, out);
+        if out.mark() == start {
+            // Nothing changed, so drop the cursor save as well
+            out.rewind(mark);
+        } else {
+            out.restore_cursor();
+            self.prev = self.page.clone();
+        }
+    }
+}
+
+/// Status line on the bottom row of a region, for example an editor's
+/// modeline
+///
+/// The line has left, centre and right segments.  When the region is
+/// too narrow to show all of them, the left segment takes priority,
+/// then the right segment, and the centre segment gets whatever space
+/// is left between them.  A segment that doesn't fit is truncated
+/// with an ellipsis, or dropped completely if there is no space at
+/// all.  Segments are kept at least one cell apart.  The centre
+/// segment is centred on the line if possible, otherwise it is moved
+/// to the side to avoid the other segments.
+pub struct StatusLine {
+    hfb: u16,
+    left: String,
+    centre: String,
+    right: String,
+}
+
+impl StatusLine {
+    /// Create an empty status line with the given `hfb` colour.  The
+    /// segment text may have embedded colour changes.
+    pub fn new(hfb: u16) -> Self {
+        Self {
+            hfb,
+            left: String::new(),
+            centre: String::new(),
+            right: String::new(),
+        }
+    }
+
+    /// Set the text of the left segment
+    pub fn set_left(&mut self, text: &str) -> &mut Self {
+        text.clone_into(&mut self.left);
+        self
+    }
+
+    /// Set the text of the centre segment
+    pub fn set_centre(&mut self, text: &str) -> &mut Self {
+        text.clone_into(&mut self.centre);
+        self
+    }
+
+    /// Set the text of the right segment
+    pub fn set_right(&mut self, text: &str) -> &mut Self {
+        text.clone_into(&mut self.right);
+        self
+    }
+
+    /// Draw the status line on the bottom row of `region`, and return
+    /// a sub-region covering the rest of it, for the content
+    pub fn draw<'r>(&self, region: &'r mut Region<'_>) -> Region<'r> {
+        let (sy, sx) = (region.sy(), region.sx());
+        if sy > 0 {
+            let mut line = region.region(sy - 1, 0, 1, sx);
+            line.clear(self.hfb);
+            let gap = line.measure(" ");
+
+            let lw = line.measure(&self.left).min(sx);
+            self.put(&mut line, 0, lw, &self.left);
+            let a = if lw > 0 { lw + gap } else { 0 };
+
+            let rw = line.measure(&self.right).min((sx - a).max(0));
+            self.put(&mut line, sx - rw, rw, &self.right);
+            let b = if rw > 0 { sx - rw - gap } else { sx };
+
+            let full = line.measure(&self.centre);
+            let cw = full.min(b - a);
+            if cw > 0 {
+                let x = ((sx - full) / 2).max(a).min(b - cw);
+                self.put(&mut line, x, cw, &self.centre);
+            }
+        }
+        region.region(0, 0, (sy - 1).max(0), sx)
+    }
+
+    // Write a segment into `width` pixels at `x`, truncating it with
+    // an ellipsis if it doesn't fit
+    fn put(&self, line: &mut Region<'_>, x: i32, width: i32, text: &str) {
+        if width <= 0 {
+            return;
+        }
+        if line.measure(text) <= width {
+            line.write(0, x, self.hfb, text);
+        } else {
+            let ew = line.measure("…");
+            if width > ew {
+                line.region(0, x, 1, width - ew).write(0, 0, self.hfb, text);
+            }
+            line.region(0, x, 1, width)
+                .write(0, (width - ew).max(0), self.hfb, "…");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Features;
+
+    // Take the flushed output, with ESC shown as `^` for readability
+    fn output(t: &mut TermOut) -> String {
+        t.flush();
+        let v = String::from_utf8_lossy(t.data_to_flush()).replace('\x1b', "^");
+        t.drain_flush();
+        v
+    }
+
+    #[test]
+    fn status_bar() {
+        let mut t = TermOut::new(Features::default());
+        t.set_size(5, 10);
+        let mut sb = StatusBar::new(1, 88);
+        sb.resize(&mut t);
+        sb.region().write(0, 0, 12, "ok");
+        sb.render(&mut t);
+        assert_eq!(
+            output(&mut t),
+            "^[1;4r^[4;1H^7^[5;1H^[0;34;41mok^[0;39;49m        ^8"
+        );
+        sb.render(&mut t);
+        assert_eq!(output(&mut t), "");
+        sb.region().write(0, 1, 12, "k");
+        sb.render(&mut t);
+        assert_eq!(output(&mut t), "");
+    }
+
+    #[test]
+    fn status_line() {
+        let mut sl = StatusLine::new(12);
+        sl.set_left("file.rs").set_centre("mid").set_right("1:1");
+        let line = |sl: &StatusLine, w| {
+            let mut p = Page::new(2, w, 88);
+            let prev = p.clone();
+            let mut full = p.full();
+            let mut content = sl.draw(&mut full);
+            assert_eq!((content.sy(), content.sx()), (1, w));
+            content.write(0, 0, 88, "x");
+            let mut v = String::new();
+            p.for_each_change(&prev, |y, _, _, t| {
+                if y == 1 {
+                    v.push_str(t)
+                }
+            });
+            v
+        };
+        assert_eq!(line(&sl, 20), "file.rs mid      1:1");
+        assert_eq!(line(&sl, 13), "file.rs … 1:1");
+        assert_eq!(line(&sl, 10), "file.rs 1…");
+        assert_eq!(line(&sl, 5), "file…");
+    }
+}
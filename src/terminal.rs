@@ -1,26 +1,86 @@
-use crate::os_glue::Glue;
-use crate::{Features, Key, TermOut};
-use stakker::{fwd, timer_max, Fwd, MaxTimerKey, Share, CX};
+use crate::cleanup;
+use crate::key::{find_paste_end, PASTE_END};
+use crate::os_glue::{Glue, TtySource};
+use crate::reply::{self, Decode, Reply};
+use crate::termout::RestoreKey;
+use crate::{Features, Key, SizeSource, TermOut, TermState};
+use stakker::{fwd, ret, timer_max, Fwd, MaxTimerKey, Ret, Share, CX};
 use std::error::Error;
-use std::mem;
-use std::panic::PanicInfo;
-use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Filter for raw input bytes, as passed to
+/// [`Terminal::set_input_filter`]
+///
+/// [`Terminal::set_input_filter`]: struct.Terminal.html#method.set_input_filter
+pub type InputFilter = Box<dyn FnMut(&mut Vec<u8>)>;
+
+// Limits applied to sizes taken from `LINES` and `COLUMNS`
+const MIN_ENV_SIZE: i32 = 2;
+const MAX_ENV_SIZE: i32 = 9999;
+
+// An outstanding XTGETTCAP query, with the results so far: `None`
+// if not yet answered, or `Some(value)` once answered
+struct TcapQuery {
+    names: Vec<String>,
+    results: Vec<Option<Option<String>>>,
+    ret: Ret<Vec<(String, Option<String>)>>,
+}
+
+/// Handle for a mode set with [`Terminal::set_mode`]
+///
+/// [`Terminal::set_mode`]: struct.Terminal.html#method.set_mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ModeHandle(u64);
 
 /// Actor that manages the connection to the terminal
 pub struct Terminal {
     resize: Fwd<Option<Share<TermOut>>>,
     input: Fwd<Key>,
+    timed_input: Option<Fwd<(Key, Instant)>>,
+    raw_input: Option<Fwd<Vec<u8>>>,
     termout: Share<TermOut>,
     glue: Glue,
     disable_output: bool,
     paused: bool,
+    // State to restore on continuing after TSTP
+    suspended: Option<TermState>,
     inbuf: Vec<u8>,
+    paste: Option<Vec<u8>>,
+    input_filter: Option<InputFilter>,
     check_enable: bool,
+    file_drop: bool,
+    min_size: (i32, i32),
+    too_small: bool,
+    resize_throttle: bool,
+    awaiting_flush: bool,
+    resize_pending: bool,
+    resize_debounce: Duration,
+    debounce_timer: MaxTimerKey,
+    size_query: bool,
+    size_timer: MaxTimerKey,
+    style_query: bool,
+    style_timer: MaxTimerKey,
+    // Sequence to restore the cursor style found at startup, or empty
+    cursor_style: Vec<u8>,
+    tcap: Option<TcapQuery>,
+    tcap_timer: MaxTimerKey,
+    // Features before the outstanding feature query, if any
+    feature_query: Option<Features>,
+    feature_timer: MaxTimerKey,
+    answerback: Option<(usize, Vec<u8>, Ret<Vec<u8>>)>,
+    answerback_timer: MaxTimerKey,
     force_timer: MaxTimerKey,
+    esc_timeout: Duration,
     check_timer: MaxTimerKey,
+    idle: Option<(Duration, Fwd<()>)>,
+    drained: Option<Fwd<()>>,
+    idle_timer: MaxTimerKey,
     cleanup: Vec<u8>,
-    panic_hook: Arc<Box<dyn Fn(&PanicInfo<'_>) + 'static + Sync + Send>>,
+    restore: Vec<u8>,
+    mode_seq: u64,
+    title_depth: u32,
+    // ID in the registry of cleanups to run on panic
+    panic_id: u64,
 }
 
 impl Terminal {
@@ -34,7 +94,12 @@ impl Terminal {
     /// `resize` to let the app know that there is no output available
     /// right now.
     ///
-    /// Input keys received are sent to `input` once decoded.
+    /// Input keys received are sent to `input` once decoded.  Text
+    /// pasted between bracketed paste markers is collected up and
+    /// sent as a single [`Key::Paste`].
+    ///
+    /// The terminal is asked for its current cursor style, and if it
+    /// replies, that style is restored on cleanup.
     ///
     /// In case of an error that can't be handled, cleans up the
     /// terminal state and terminates the actor with
@@ -55,12 +120,47 @@ impl Terminal {
     /// needed by the application, then it must be set up before the
     /// call to [`Terminal::init`].
     ///
+    /// Several terminals may exist at once in the same process.  They
+    /// share a single panic handler, which runs the cleanup of each
+    /// of them, most recently created first.  The saved panic
+    /// handler is put back when the last terminal is dropped.
+    ///
+    /// [`Key::Paste`]: enum.Key.html#variant.Paste
     /// [`TermOut`]: struct.TermOut.html
     pub fn init(cx: CX![], resize: Fwd<Option<Share<TermOut>>>, input: Fwd<Key>) -> Option<Self> {
-        // TODO: Query TERM/terminfo/environment for features to put in Features
-        let features = Features { colour_256: false };
+        Self::init_aux(cx, resize, input, TtySource::Stdio)
+    }
+
+    /// Set up the terminal as for [`Terminal::init`], but open
+    /// `/dev/tty` for input and output instead of using stdin and
+    /// stdout.  This allows the app to drive the terminal even when
+    /// stdin or stdout is redirected, e.g. to read data from a pipe
+    /// on stdin, or to write results to stdout on exit.  If
+    /// `/dev/tty` can't be opened, e.g. if there is no controlling
+    /// terminal, then if `fallback` is true, stdin and stdout are
+    /// used instead, and [`Terminal::tty_fallback`] reports why.
+    /// Otherwise the actor fails with the error.
+    ///
+    /// [`Terminal::init`]: struct.Terminal.html#method.init
+    /// [`Terminal::tty_fallback`]: struct.Terminal.html#method.tty_fallback
+    pub fn init_tty(
+        cx: CX![],
+        resize: Fwd<Option<Share<TermOut>>>,
+        input: Fwd<Key>,
+        fallback: bool,
+    ) -> Option<Self> {
+        Self::init_aux(cx, resize, input, TtySource::DevTty { fallback })
+    }
+
+    fn init_aux(
+        cx: CX![],
+        resize: Fwd<Option<Share<TermOut>>>,
+        input: Fwd<Key>,
+        source: TtySource,
+    ) -> Option<Self> {
+        let features = Features::from_env();
         let term = cx.this().clone();
-        let glue = match Glue::new(cx, term) {
+        let glue = match Glue::new(cx, term, source) {
             Ok(v) => v,
             Err(e) => {
                 cx.fail(e);
@@ -71,30 +171,547 @@ impl Terminal {
         let mut this = Self {
             resize,
             input,
+            timed_input: None,
+            raw_input: None,
             termout,
             glue,
             disable_output: false,
             paused: false,
+            suspended: None,
             inbuf: Vec::new(),
+            paste: None,
+            input_filter: None,
             check_enable: false,
+            file_drop: false,
+            min_size: (0, 0),
+            too_small: false,
+            resize_throttle: false,
+            awaiting_flush: false,
+            resize_pending: false,
+            resize_debounce: Duration::ZERO,
+            debounce_timer: MaxTimerKey::default(),
+            size_query: false,
+            size_timer: MaxTimerKey::default(),
+            style_query: false,
+            style_timer: MaxTimerKey::default(),
+            cursor_style: Vec::new(),
+            tcap: None,
+            tcap_timer: MaxTimerKey::default(),
+            feature_query: None,
+            feature_timer: MaxTimerKey::default(),
+            answerback: None,
+            answerback_timer: MaxTimerKey::default(),
             force_timer: MaxTimerKey::default(),
+            esc_timeout: Duration::from_millis(100),
             check_timer: MaxTimerKey::default(),
+            idle: None,
+            drained: None,
+            idle_timer: MaxTimerKey::default(),
             cleanup: b"\x1Bc".to_vec(),
-            panic_hook: Arc::new(std::panic::take_hook()),
+            restore: Vec::new(),
+            mode_seq: 0,
+            title_depth: 0,
+            panic_id: cleanup::register(),
         };
-        this.handle_resize(cx);
+        this.resize_now(cx);
+        this.query_cursor_style(cx);
         this.update_panic_hook();
         Some(this)
     }
 
+    // Ask the terminal for the current cursor style using DECRQSS,
+    // so that it can be restored after the cleanup string on exit.
+    // If there is no reply, the cursor style is left alone.
+    fn query_cursor_style(&mut self, cx: CX![]) {
+        if self.disable_output || self.paused {
+            return;
+        }
+        if let Err(e) = self.glue.write(b"\x1BP$q q\x1B\\") {
+            self.disable_output = true;
+            self.failure(cx, e);
+            return;
+        }
+        self.style_query = true;
+        timer_max!(
+            &mut self.style_timer,
+            cx.now() + Duration::from_millis(500),
+            [cx],
+            style_query_done()
+        );
+    }
+
+    // Stop looking for a reply to the cursor style query
+    fn style_query_done(&mut self, cx: CX![]) {
+        self.style_query = false;
+        cx.timer_max_del(self.style_timer);
+    }
+
     /// Enable or disable generation of the [`Key::Check`] keypress,
     /// which occurs in a gap in typing, 300ms after the last key
     /// pressed.  This may be used to do validation if that's too
-    /// expensive to do on every keypress.
+    /// expensive to do on every keypress.  Disabling cancels any
+    /// pending check.
     ///
     /// [`Key::Check`]: enum.Key.html#variant.Check
-    pub fn check(&mut self, _cx: CX![], enable: bool) {
+    pub fn check(&mut self, cx: CX![], enable: bool) {
         self.check_enable = enable;
+        if !enable {
+            cx.timer_max_del(self.check_timer);
+        }
+    }
+
+    /// Enable or disable detection of files dropped onto the
+    /// terminal window.  When enabled, a bracketed paste consisting
+    /// entirely of absolute paths or `file://` URIs is sent as a
+    /// [`Key::FileDrop`] instead of a [`Key::Paste`].  This is a
+    /// heuristic, since terminals report drops by pasting the paths
+    /// as text, so a user pasting a list of paths will also get a
+    /// [`Key::FileDrop`].  See [`Key::paste_paths`].
+    ///
+    /// [`Key::FileDrop`]: enum.Key.html#variant.FileDrop
+    /// [`Key::Paste`]: enum.Key.html#variant.Paste
+    /// [`Key::paste_paths`]: enum.Key.html#method.paste_paths
+    pub fn file_drop(&mut self, _cx: CX![], enable: bool) {
+        self.file_drop = enable;
+    }
+
+    /// Set how long to wait for the rest of a partial escape
+    /// sequence before decoding what has arrived so far, e.g. a lone
+    /// Esc.  The default is 100ms.  A longer timeout may be needed
+    /// on a high-latency link where sequences get split up, but
+    /// makes the Esc key slower to respond.
+    pub fn set_esc_timeout(&mut self, _cx: CX![], timeout: Duration) {
+        self.esc_timeout = timeout;
+    }
+
+    /// Set a minimum terminal size that the application requires.
+    /// Whilst the terminal is smaller than this, the app is sent a
+    /// `resize` message with `None` as if output were paused, and
+    /// instead a centred "too small" message is displayed.  Any
+    /// output that the app flushes during this time is discarded.
+    /// Once the terminal is big enough again, a normal `resize`
+    /// message is sent to trigger a full redraw.  Use `(0, 0)` to
+    /// remove the requirement.
+    pub fn require_min_size(&mut self, cx: CX![], rows: i32, cols: i32) {
+        self.min_size = (rows, cols);
+        if !self.paused {
+            self.resize_now(cx);
+        }
+    }
+
+    /// Enable or disable throttling of `resize` messages.  When
+    /// enabled, after a `resize` message has been sent, no further
+    /// `resize` messages are sent until the app has redrawn and
+    /// called [`Terminal::flush`].  If the window size changed in
+    /// the meantime, a single `resize` message is then sent with the
+    /// latest size.  So when the user drags the window edge faster
+    /// than the app can redraw, intermediate sizes are skipped, but
+    /// the final size always gets a redraw.  This relies on the app
+    /// calling [`Terminal::flush`] after handling every `resize`
+    /// message.
+    ///
+    /// [`Terminal::flush`]: struct.Terminal.html#method.flush
+    pub fn throttle_resize(&mut self, cx: CX![], enable: bool) {
+        self.resize_throttle = enable;
+        if !enable {
+            self.awaiting_flush = false;
+            if self.resize_pending {
+                self.resize_pending = false;
+                self.resize_now(cx);
+            }
+        }
+    }
+
+    /// Set a debounce period for `resize` messages, or
+    /// `Duration::ZERO` to disable debouncing, which is the default.
+    /// When enabled, a window size change isn't passed on until no
+    /// further changes have been seen for this period, e.g. 50ms, so
+    /// whilst the user drags the window edge, the app only gets a
+    /// `resize` message once the dragging stops.  The `resize`
+    /// message sent on startup is not delayed.  This may be combined
+    /// with [`Terminal::throttle_resize`].
+    ///
+    /// [`Terminal::throttle_resize`]: struct.Terminal.html#method.throttle_resize
+    pub fn debounce_resize(&mut self, cx: CX![], period: Duration) {
+        self.resize_debounce = period;
+        if period == Duration::ZERO && cx.timer_max_active(self.debounce_timer) {
+            cx.timer_max_del(self.debounce_timer);
+            self.resize_settled(cx);
+        }
+    }
+
+    /// Send a message to `fwd` each time output that was queued
+    /// because the terminal couldn't accept it immediately has all
+    /// been sent, or pass `None` to cancel.  An app that skips
+    /// redraws whilst [`TermOut::backlog`] is non-zero can use this
+    /// to know when to catch up.
+    ///
+    /// [`TermOut::backlog`]: struct.TermOut.html#method.backlog
+    pub fn output_drained(&mut self, _cx: CX![], fwd: Option<Fwd<()>>) {
+        self.drained = fwd;
+    }
+
+    /// Send a message to `fwd` once there has been no input at all
+    /// for the period `dur`, for example to start a screensaver or to
+    /// log out automatically, or pass `None` to cancel.  The message
+    /// is sent just once per idle period.  The timer starts again
+    /// from when this call is made, and from the arrival of each new
+    /// input.  Unlike [`Key::Check`], this doesn't depend on any key
+    /// having been pressed.
+    ///
+    /// [`Key::Check`]: enum.Key.html#variant.Check
+    pub fn idle_timeout(&mut self, cx: CX![], dur: Duration, fwd: Option<Fwd<()>>) {
+        cx.timer_max_del(self.idle_timer);
+        self.idle = fwd.map(|fwd| (dur, fwd));
+        self.restart_idle(cx);
+    }
+
+    // Start or restart the idle timer, if enabled
+    fn restart_idle(&mut self, cx: CX![]) {
+        if let Some((dur, _)) = self.idle {
+            timer_max!(&mut self.idle_timer, cx.now() + dur, [cx], idle_expired());
+        }
+    }
+
+    fn idle_expired(&mut self, _cx: CX![]) {
+        if let Some((_, ref fwd)) = self.idle {
+            fwd!([fwd]);
+        }
+    }
+
+    /// Forward input keys along with a timestamp to `input` instead
+    /// of to the `input` passed to [`Terminal::init`], or `None` to
+    /// go back to forwarding plain keys.  The timestamp is `cx.now()`
+    /// at the time the input bytes were processed, which is as close
+    /// as we can get, but it is not the true time of the keystroke.
+    /// Keys that arrive together in one read from the TTY (e.g. a
+    /// paste or typing whilst the process was busy) all get the same
+    /// timestamp.
+    ///
+    /// [`Terminal::init`]: struct.Terminal.html#method.init
+    pub fn timestamped_input(&mut self, _cx: CX![], input: Option<Fwd<(Key, Instant)>>) {
+        self.timed_input = input;
+    }
+
+    /// Forward the raw input bytes to `input` as they arrive, without
+    /// decoding them into keys, or `None` to go back to decoding
+    /// keys.  This is for apps that do their own parsing, or that
+    /// relay the input elsewhere.  The input filter (see
+    /// [`Terminal::set_input_filter`]) is still applied.  Whilst this
+    /// is enabled, replies to any queries sent to the terminal are
+    /// also passed through as raw bytes instead of being processed.
+    /// Any bytes already waiting to be decoded when this is enabled
+    /// are forwarded immediately.
+    ///
+    /// [`Terminal::set_input_filter`]: struct.Terminal.html#method.set_input_filter
+    pub fn raw_input(&mut self, cx: CX![], input: Option<Fwd<Vec<u8>>>) {
+        self.raw_input = input;
+        self.forward_raw(cx);
+    }
+
+    /// Install a filter which sees the raw input bytes before they
+    /// are decoded into keys.  The filter may modify, drop or insert
+    /// bytes as it wishes, for example to remap a sequence from a
+    /// broken terminal, or to expand a macro byte into a longer
+    /// sequence.  This replaces any filter previously installed.
+    ///
+    /// The filter is called once for each batch of bytes read from
+    /// the TTY, and it sees only the freshly-read bytes.  Its output
+    /// is then appended to any partial sequence left over from
+    /// earlier reads before decoding.  So the filter cannot rely on
+    /// an escape sequence arriving complete in a single batch.  The
+    /// Esc force-timeout runs on the filtered bytes, and the filter
+    /// is not called again when that timeout forces out a partial
+    /// sequence.
+    pub fn set_input_filter(&mut self, _cx: CX![], filter: InputFilter) {
+        self.input_filter = Some(filter);
+    }
+
+    /// Inject input bytes as if they had been read from the terminal,
+    /// for example from a script, a recording being replayed, or a
+    /// remote-control socket.  They go through the same path as real
+    /// input, including the input filter, paste handling and the
+    /// timeout for partial sequences, and are forwarded as keys (or
+    /// as raw bytes if [`Terminal::raw_input`] is active).  The bytes
+    /// are appended after any real input still waiting to be
+    /// decoded, so a partial escape sequence split between the two
+    /// will be joined up.
+    ///
+    /// [`Terminal::raw_input`]: struct.Terminal.html#method.raw_input
+    pub fn inject_input(&mut self, cx: CX![], data: Vec<u8>) {
+        self.take_input(cx, data);
+    }
+
+    /// Return a copy of the input bytes which have been received but
+    /// not yet decoded into keys, for diagnosing input problems.
+    /// Usually this is empty, or contains the start of an escape
+    /// sequence which is still waiting for more bytes to arrive.
+    /// The contents are transient: they will be decoded or forced
+    /// out as keys within a short time.
+    pub fn pending_input(&self, _cx: CX![], ret: Ret<Vec<u8>>) {
+        ret!([ret], self.inbuf.clone());
+    }
+
+    /// Report whether [`Terminal::init_tty`] had to fall back to
+    /// stdin and stdout.  If so, returns the error from opening
+    /// `/dev/tty`, else `None`.  This is always `None` for a terminal
+    /// set up with [`Terminal::init`].
+    ///
+    /// [`Terminal::init_tty`]: struct.Terminal.html#method.init_tty
+    /// [`Terminal::init`]: struct.Terminal.html#method.init
+    pub fn tty_fallback(&self, _cx: CX![], ret: Ret<Option<std::io::Error>>) {
+        ret!([ret], self.glue.tty_error());
+    }
+
+    /// Set a terminal mode that needs undoing on cleanup.  The
+    /// `enable` sequence is added to the [`TermOut`] buffer, to go
+    /// out with the next flush, and the `disable` sequence is sent
+    /// automatically on cleanup, before the cleanup string.  Returns
+    /// a handle which may be passed to [`Terminal::unset_mode`] to
+    /// disable the mode again before then.  This is a general way to
+    /// handle any mode that the crate doesn't support directly.
+    ///
+    /// [`TermOut`]: struct.TermOut.html
+    /// [`Terminal::unset_mode`]: struct.Terminal.html#method.unset_mode
+    pub fn set_mode(&mut self, cx: CX![], enable: Vec<u8>, disable: Vec<u8>, ret: Ret<ModeHandle>) {
+        self.mode_seq += 1;
+        let handle = ModeHandle(self.mode_seq);
+        let ob = self.termout.rw(cx);
+        ob.bytes(&enable);
+        ob.set_restore(RestoreKey::Mode(handle.0), &disable);
+        ob.set_mode_seq(RestoreKey::Mode(handle.0), &enable);
+        ret!([ret], handle);
+    }
+
+    /// Disable a mode set with [`Terminal::set_mode`].  The `disable`
+    /// sequence is added to the [`TermOut`] buffer, to go out with
+    /// the next flush, and is no longer sent on cleanup.  Does
+    /// nothing if the mode has already been unset.
+    ///
+    /// [`TermOut`]: struct.TermOut.html
+    /// [`Terminal::set_mode`]: struct.Terminal.html#method.set_mode
+    pub fn unset_mode(&mut self, cx: CX![], handle: ModeHandle) {
+        let ob = self.termout.rw(cx);
+        if let Some(disable) = ob.take_restore(RestoreKey::Mode(handle.0)) {
+            ob.bytes(&disable);
+        }
+    }
+
+    /// Save the window and icon titles on the terminal's title stack
+    /// using XTWINOPS, so that the titles set afterwards with
+    /// [`TermOut::set_title`] or [`TermOut::set_icon_title`] can be
+    /// undone with [`Terminal::pop_title`].  Any titles still pushed
+    /// are popped automatically on cleanup, including on panic or
+    /// pause, so that the user's shell gets its own titles back.
+    /// Calls may be nested.  Terminals without a title stack ignore
+    /// these sequences.
+    ///
+    /// [`Terminal::pop_title`]: struct.Terminal.html#method.pop_title
+    /// [`TermOut::set_icon_title`]: struct.TermOut.html#method.set_icon_title
+    /// [`TermOut::set_title`]: struct.TermOut.html#method.set_title
+    pub fn push_title(&mut self, cx: CX![]) {
+        self.title_depth += 1;
+        self.update_title_restore(cx);
+        self.termout.rw(cx).bytes(b"\x1B[22;0t");
+    }
+
+    /// Restore the window and icon titles saved by the most recent
+    /// [`Terminal::push_title`].  Does nothing if there are none
+    /// still pushed.
+    ///
+    /// [`Terminal::push_title`]: struct.Terminal.html#method.push_title
+    pub fn pop_title(&mut self, cx: CX![]) {
+        if self.title_depth > 0 {
+            self.title_depth -= 1;
+            self.update_title_restore(cx);
+            self.termout.rw(cx).bytes(b"\x1B[23;0t");
+        }
+    }
+
+    // Update the sequences which undo and redo the pushes on the
+    // title stack for cleanup and resume
+    fn update_title_restore(&mut self, cx: CX![]) {
+        let n = self.title_depth as usize;
+        let ob = self.termout.rw(cx);
+        ob.take_restore(RestoreKey::Title);
+        if n > 0 {
+            ob.set_restore(RestoreKey::Title, &b"\x1B[23;0t".repeat(n));
+            ob.set_mode_seq(RestoreKey::Title, &b"\x1B[22;0t".repeat(n));
+        }
+    }
+
+    /// Query the terminal for the given termcap or terminfo
+    /// capabilities using XTGETTCAP, for example `Tc` or `RGB` for
+    /// 24-bit colour, or `Smulx` for styled underlines.  This is the
+    /// most authoritative source of capability information, but only
+    /// some terminals support it.  Returns the value of each
+    /// capability in the same order, or `None` if the terminal
+    /// doesn't have that capability, doesn't support the query, or
+    /// doesn't reply within 500ms.  Boolean capabilities give an
+    /// empty string if present.  If the `colors`, `RGB` or `Tc`
+    /// capabilities are returned, [`Features`] is updated to match.
+    /// Since many 24-bit terminals report 256 for `colors`, a lower
+    /// `colors` value only turns off 24-bit colour if `RGB` or `Tc`
+    /// was also asked for, and the terminal said it has neither.
+    ///
+    /// [`Features`]: struct.Features.html
+    pub fn query_tcap(
+        &mut self,
+        cx: CX![],
+        names: Vec<String>,
+        ret: Ret<Vec<(String, Option<String>)>>,
+    ) {
+        self.tcap_done(cx);
+        let results = vec![None; names.len()];
+        self.tcap = Some(TcapQuery {
+            names,
+            results,
+            ret,
+        });
+        if self.disable_output || self.paused || self.tcap.as_ref().unwrap().names.is_empty() {
+            self.tcap_done(cx);
+            return;
+        }
+        let mut query = Vec::new();
+        for name in &self.tcap.as_ref().unwrap().names {
+            query.extend_from_slice(format!("\x1BP+q{}\x1B\\", reply::hex(name)).as_bytes());
+        }
+        if let Err(e) = self.glue.write(&query) {
+            self.disable_output = true;
+            self.tcap_done(cx);
+            self.failure(cx, e);
+            return;
+        }
+        timer_max!(
+            &mut self.tcap_timer,
+            cx.now() + Duration::from_millis(500),
+            [cx],
+            tcap_done()
+        );
+    }
+
+    // Return the results of any outstanding XTGETTCAP query, with
+    // `None` for capabilities not answered
+    fn tcap_done(&mut self, cx: CX![]) {
+        if let Some(q) = self.tcap.take() {
+            cx.timer_max_del(self.tcap_timer);
+            // Terminals with `RGB` or `Tc` often still give 256 for
+            // `colors`, so `colors` can only turn on 24-bit colour,
+            // unless `RGB` or `Tc` were asked for and the terminal
+            // said that it doesn't have them
+            let is_rgb = |name: &str| name == "RGB" || name == "Tc";
+            let mut rgb = false;
+            let mut rgb_absent = false;
+            for (name, value) in q.names.iter().zip(&q.results) {
+                if is_rgb(name) {
+                    match value {
+                        Some(Some(_)) => rgb = true,
+                        Some(None) => rgb_absent = true,
+                        None => (),
+                    }
+                }
+            }
+            let results: Vec<_> = q
+                .names
+                .into_iter()
+                .zip(q.results)
+                .map(|(name, value)| (name, value.flatten()))
+                .collect();
+            let features = &mut self.termout.rw(cx).features;
+            for (name, value) in &results {
+                let num = value.as_ref().and_then(|v| v.parse::<i32>().ok());
+                if let ("colors", Some(colours)) = (&name[..], num) {
+                    features.colour_256 = colours >= 256;
+                    if colours >= 0x100_0000 {
+                        features.truecolor = true;
+                    } else if rgb_absent && !rgb {
+                        features.truecolor = false;
+                    }
+                }
+            }
+            if rgb {
+                features.truecolor = true;
+            }
+            features.colour_256 |= features.truecolor;
+            ret!([q.ret], results);
+        }
+    }
+
+    /// Query the terminal for the features it supports, to improve on
+    /// the guesses made from the environment on startup.  The
+    /// terminal is sent DECRQM requests for synchronized output (mode
+    /// 2026) and bracketed paste (mode 2004), followed by a primary
+    /// device attributes (DA1) request, which gives sixel support.
+    /// Almost all terminals reply to DA1, and replies come back in
+    /// order, so the DA1 reply marks the end of the detection.  If no
+    /// reply arrives within 500ms, the detection ends anyway, and
+    /// anything not answered is left as it was.
+    ///
+    /// This is asynchronous.  [`Features`] are updated as the replies
+    /// arrive, shortly after this call, and if anything changed, a
+    /// `resize` message is sent once detection is complete, so that
+    /// the app can check [`TermOut::features`] again and redraw.
+    /// The replies are picked out of the input, so are never seen as
+    /// keypresses unless they arrive after the timeout.
+    ///
+    /// [`Features`]: struct.Features.html
+    /// [`TermOut::features`]: struct.TermOut.html#method.features
+    pub fn query_features(&mut self, cx: CX![]) {
+        if self.disable_output || self.paused || self.feature_query.is_some() {
+            return;
+        }
+        if let Err(e) = self.glue.write(b"\x1B[?2026$p\x1B[?2004$p\x1B[c") {
+            self.disable_output = true;
+            self.failure(cx, e);
+            return;
+        }
+        self.feature_query = Some(self.termout.rw(cx).features.clone());
+        timer_max!(
+            &mut self.feature_timer,
+            cx.now() + Duration::from_millis(500),
+            [cx],
+            feature_query_done()
+        );
+    }
+
+    // End the feature query, and let the app know if anything changed
+    fn feature_query_done(&mut self, cx: CX![]) {
+        if let Some(before) = self.feature_query.take() {
+            cx.timer_max_del(self.feature_timer);
+            if self.termout.rw(cx).features != before && !self.paused && !self.too_small {
+                fwd!([self.resize], Some(self.termout.clone()));
+                self.awaiting_flush = self.resize_throttle;
+            }
+        }
+    }
+
+    /// Take a snapshot of the known terminal state: the modes set
+    /// through this crate (e.g. with [`Terminal::set_mode`]), the
+    /// attributes last selected and the cursor position.  This is
+    /// for an app that hands over the terminal to another full-screen
+    /// program, e.g. `$EDITOR`, which will clobber the state.  Save
+    /// the state, [`Terminal::pause`], run the program, then
+    /// [`Terminal::resume`] and pass the snapshot to
+    /// [`Terminal::restore_state`].
+    ///
+    /// [`Terminal::pause`]: struct.Terminal.html#method.pause
+    /// [`Terminal::restore_state`]: struct.Terminal.html#method.restore_state
+    /// [`Terminal::resume`]: struct.Terminal.html#method.resume
+    /// [`Terminal::set_mode`]: struct.Terminal.html#method.set_mode
+    pub fn save_state(&mut self, cx: CX![], ret: Ret<TermState>) {
+        ret!([ret], self.termout.rw(cx).save_state());
+    }
+
+    /// Add to the [`TermOut`] buffer the sequences to get back to the
+    /// state saved with [`Terminal::save_state`], re-enabling the
+    /// modes, selecting the attributes and moving the cursor back.
+    /// This goes out with the next flush.  Screen contents are not
+    /// part of the state, so the app still needs to redraw.
+    ///
+    /// [`TermOut`]: struct.TermOut.html
+    /// [`Terminal::save_state`]: struct.Terminal.html#method.save_state
+    pub fn restore_state(&mut self, cx: CX![], state: TermState) {
+        self.termout.rw(cx).restore_state(&state);
     }
 
     /// Ring the bell (i.e. beep) immediately.  Doesn't wait for the
@@ -109,6 +726,50 @@ impl Terminal {
         }
     }
 
+    /// Send ENQ to the terminal immediately and return whatever it
+    /// sends back as its answerback string.  This may help identify
+    /// some serial and older hardware terminals.  Most terminal
+    /// emulators send nothing, or an empty string.  Since the
+    /// answerback has no terminator, all input arriving within
+    /// 200ms is taken as the answerback, so any keys typed in that
+    /// time are lost.  An empty vector is returned if output is
+    /// paused or disabled.
+    ///
+    /// Note that an answerback string arriving unexpectedly can't be
+    /// distinguished from typed keys, and ENQ itself is a valid
+    /// keypress (`Ctrl-E`), so input is only captured when a request
+    /// is outstanding.
+    pub fn enquire(&mut self, cx: CX![], ret: Ret<Vec<u8>>) {
+        self.answerback_done(cx);
+        if self.disable_output || self.paused {
+            ret!([ret], Vec::new());
+            return;
+        }
+        if let Err(e) = self.glue.write(b"\x05") {
+            self.disable_output = true;
+            ret!([ret], Vec::new());
+            self.failure(cx, e);
+            return;
+        }
+        self.answerback = Some((self.inbuf.len(), Vec::new(), ret));
+        timer_max!(
+            &mut self.answerback_timer,
+            cx.now() + Duration::from_millis(200),
+            [cx],
+            answerback_done()
+        );
+    }
+
+    // Return the captured answerback, if a request is outstanding,
+    // and then process any input held back meanwhile
+    fn answerback_done(&mut self, cx: CX![]) {
+        if let Some((_, data, ret)) = self.answerback.take() {
+            cx.timer_max_del(self.answerback_timer);
+            ret!([ret], data);
+            self.process_input(cx);
+        }
+    }
+
     /// Pause terminal input and output handling.  Sends the cleanup
     /// sequence to the terminal, and switches to cooked mode.  Sends
     /// a `resize` message with `None` to tell the app that output is
@@ -124,8 +785,12 @@ impl Terminal {
             fwd!([self.resize], None);
             self.glue.input(false);
             self.termout.rw(cx).discard();
-            self.termout.rw(cx).bytes(&self.cleanup[..]);
+            let cleanup = self.full_cleanup();
+            self.termout.rw(cx).bytes(&cleanup[..]);
             self.termout.rw(cx).flush();
+            self.too_small = false;
+            self.awaiting_flush = false;
+            self.resize_pending = false;
             self.flush(cx);
             self.paused = true;
             self.update_panic_hook();
@@ -139,11 +804,33 @@ impl Terminal {
             self.paused = false;
             self.glue.input(true);
             self.termout.rw(cx).discard();
-            self.handle_resize(cx);
+            self.resize_now(cx);
             self.update_panic_hook();
         }
     }
 
+    /// Stop the process as a shell's job control would, cleaning up
+    /// the terminal first as for [`Terminal::pause`] so that the
+    /// user's shell is usable meanwhile.  When the process is
+    /// continued, e.g. with `fg`, the terminal is resumed, the modes
+    /// and other state are put back as for [`Terminal::restore_state`]
+    /// and a `resize` message is sent to trigger a full redraw.
+    ///
+    /// In raw mode, Ctrl-Z arrives as a key rather than generating a
+    /// TSTP signal, so an app that wants the usual behaviour should
+    /// call this on `C-Z`.  A TSTP signal from elsewhere is handled
+    /// the same way.
+    ///
+    /// [`Terminal::pause`]: struct.Terminal.html#method.pause
+    /// [`Terminal::restore_state`]: struct.Terminal.html#method.restore_state
+    pub fn suspend(&mut self, cx: CX![]) {
+        if !self.paused {
+            self.suspended = Some(self.termout.rw(cx).save_state());
+            self.pause(cx);
+        }
+        self.glue.stop();
+    }
+
     // Handle an unrecoverable failure.  Try to clean up before
     // terminating the actor.
     fn failure(&mut self, cx: CX![], e: impl Error + 'static) {
@@ -153,44 +840,242 @@ impl Terminal {
 
     /// Flush to the terminal all the data that's ready for sending
     /// from the TermOut buffer.  Use [`TermOut::flush`] first to mark
-    /// the point up to which data should be flushed.
+    /// the point up to which data should be flushed.  This never
+    /// blocks.  Any data that the terminal can't accept immediately,
+    /// e.g. because of XOFF or a slow link, is queued and sent as
+    /// soon as possible.  See [`TermOut::backlog`] and
+    /// [`Terminal::output_drained`] to throttle output when that
+    /// happens.
     ///
+    /// [`TermOut::backlog`]: struct.TermOut.html#method.backlog
     /// [`TermOut::flush`]: struct.TermOut.html#method.flush
+    /// [`Terminal::output_drained`]: struct.Terminal.html#method.output_drained
     pub fn flush(&mut self, cx: CX![]) {
         if self.termout.rw(cx).new_cleanup.is_some() {
             // Don't replace unless we're sure there's a new value
-            if let Some(cleanup) = mem::replace(&mut self.termout.rw(cx).new_cleanup, None) {
+            if let Some(cleanup) = self.termout.rw(cx).new_cleanup.take() {
                 self.cleanup = cleanup;
                 self.update_panic_hook();
             }
         }
+        if self.termout.rw(cx).restore_changed {
+            let ob = self.termout.rw(cx);
+            ob.restore_changed = false;
+            self.restore = ob.restore_seq();
+            self.update_panic_hook();
+        }
+
+        if self.paused || self.too_small {
+            // Just drop the output whilst paused or too small.
+            // We'll trigger a full refresh on resuming
+            self.termout.rw(cx).drain_flush();
+        } else {
+            self.write_out(cx);
+        }
+
+        if self.awaiting_flush {
+            self.awaiting_flush = false;
+            if self.resize_pending {
+                self.resize_pending = false;
+                self.resize_now(cx);
+            }
+        }
+    }
 
+    // Write out the data that's ready for flushing
+    fn write_out(&mut self, cx: CX![]) {
         if !self.disable_output {
-            if self.paused {
-                // Just drop the output whilst paused.  We'll trigger
-                // a full refresh on resuming
-                self.termout.rw(cx).drain_flush();
-            } else {
-                let ob = self.termout.rw(cx);
-                let result = self.glue.write(ob.data_to_flush());
-                ob.drain_flush();
-                if let Err(e) = result {
-                    self.disable_output = true;
-                    self.failure(cx, e);
+            let ob = self.termout.rw(cx);
+            let result = self.glue.write(&ob.frame_to_flush());
+            ob.drain_flush();
+            ob.backlog = self.glue.backlog();
+            if let Err(e) = result {
+                self.disable_output = true;
+                self.failure(cx, e);
+            }
+        }
+    }
+
+    /// Handle the TTY output becoming writable whilst output is
+    /// queued
+    pub(crate) fn handle_data_out(&mut self, cx: CX![]) {
+        if !self.disable_output {
+            let result = self.glue.drain();
+            let backlog = self.glue.backlog();
+            let ob = self.termout.rw(cx);
+            let drained = ob.backlog > 0 && backlog == 0;
+            ob.backlog = backlog;
+            if let Err(e) = result {
+                self.disable_output = true;
+                self.failure(cx, e);
+            } else if drained {
+                if let Some(ref fwd) = self.drained {
+                    fwd!([fwd]);
                 }
             }
         }
     }
 
+    // Replace the display with a message saying that the terminal
+    // is too small
+    fn show_too_small(&mut self, cx: CX![]) {
+        let (min_sy, min_sx) = self.min_size;
+        let msg = format!("Terminal too small (need {}x{})", min_sy, min_sx);
+        let ob = self.termout.rw(cx);
+        let (sy, sx) = ob.size();
+        ob.discard();
+        ob.attr_reset().clear();
+        if sy > 0 && sx > 0 {
+            let len = (msg.len() as i32).min(sx);
+            ob.at(sy / 2, (sx - len) / 2).out(&msg[..len as usize]);
+        }
+        ob.flush();
+        self.write_out(cx);
+    }
+
     /// Handle a resize event from the TTY.  Gets new size, and
-    /// notifies upstream.
+    /// notifies upstream, unless a resize is being held back by
+    /// debouncing or throttling.
     pub(crate) fn handle_resize(&mut self, cx: CX![]) {
-        match self.glue.get_size() {
-            Ok((sy, sx)) => {
-                self.termout.rw(cx).set_size(sy, sx);
-                fwd!([self.resize], Some(self.termout.clone()));
+        if self.resize_debounce > Duration::ZERO {
+            timer_max!(
+                &mut self.debounce_timer,
+                cx.now() + self.resize_debounce,
+                [cx],
+                resize_settled()
+            );
+        } else {
+            self.resize_settled(cx);
+        }
+    }
+
+    // Handle a resize once any debounce period has passed
+    fn resize_settled(&mut self, cx: CX![]) {
+        if self.awaiting_flush {
+            self.resize_pending = true;
+        } else {
+            self.resize_now(cx);
+        }
+    }
+
+    /// Handle a TSTP signal, e.g. from `kill -TSTP`
+    pub(crate) fn handle_suspend(&mut self, cx: CX![]) {
+        self.suspend(cx);
+    }
+
+    /// Handle a CONT signal.  If the terminal was cleaned up by
+    /// [`Terminal::suspend`] then it is resumed and the saved state
+    /// restored.  Otherwise the process was stopped some other way,
+    /// e.g. with STOP, and the shell may have changed the TTY modes
+    /// meanwhile, so raw mode is set up again.  Either way a `resize`
+    /// message is sent to trigger a full redraw.
+    ///
+    /// [`Terminal::suspend`]: struct.Terminal.html#method.suspend
+    pub(crate) fn handle_continue(&mut self, cx: CX![]) {
+        if let Some(state) = self.suspended.take() {
+            self.resume(cx);
+            self.termout.rw(cx).restore_state(&state);
+        } else if !self.paused {
+            self.glue.input(false);
+            self.glue.input(true);
+            self.termout.rw(cx).discard();
+            self.resize_now(cx);
+            self.update_panic_hook();
+        }
+    }
+
+    // Get the new size and notify upstream
+    fn resize_now(&mut self, cx: CX![]) {
+        // This resize covers any that is waiting for the debounce
+        // period to pass, e.g. on continuing after a stop
+        cx.timer_max_del(self.debounce_timer);
+
+        let os_size = self.glue.get_size().ok();
+        let (sy, sx, source) = match Self::size_from(os_size, |name| std::env::var(name).ok()) {
+            Some(size) => size,
+            None => self.fallback_size(cx),
+        };
+        self.set_size(cx, sy, sx, source);
+    }
+
+    // Choose the size from the one the OS gave, if any, or else from
+    // `LINES` and `COLUMNS` looked up through `var`.  Returns `None`
+    // if neither is usable.  See `SizeSource` for the order of
+    // precedence.
+    fn size_from(
+        os_size: Option<(i32, i32)>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Option<(i32, i32, SizeSource)> {
+        // Some ptys report success but give a zero size
+        if let Some((sy, sx)) = os_size {
+            if sy > 0 && sx > 0 {
+                return Some((sy, sx, SizeSource::Ioctl));
             }
-            Err(e) => self.failure(cx, e),
+        }
+        let env = |name| {
+            var(name)
+                .and_then(|v| v.trim().parse::<i32>().ok())
+                .filter(|v| *v > 0)
+                .map(|v| v.clamp(MIN_ENV_SIZE, MAX_ENV_SIZE))
+        };
+        match (env("LINES"), env("COLUMNS")) {
+            (Some(sy), Some(sx)) => Some((sy, sx, SizeSource::Env)),
+            _ => None,
+        }
+    }
+
+    // Get the size from the terminal when neither the OS nor the
+    // environment can supply it
+    fn fallback_size(&mut self, cx: CX![]) -> (i32, i32, SizeSource) {
+        if !self.size_query && !self.disable_output && !self.paused {
+            // Ask the terminal.  The reply is picked out of the input
+            // by `do_data_in`.
+            if let Err(e) = self.glue.write(b"\x1B[18t") {
+                self.disable_output = true;
+                self.failure(cx, e);
+            } else {
+                self.size_query = true;
+                timer_max!(
+                    &mut self.size_timer,
+                    cx.now() + Duration::from_millis(500),
+                    [cx],
+                    size_query_done()
+                );
+            }
+        }
+        let ob = self.termout.rw(cx);
+        match ob.size_source {
+            SizeSource::Query => (ob.sy(), ob.sx(), SizeSource::Query),
+            _ => (24, 80, SizeSource::Default),
+        }
+    }
+
+    // Stop looking for a reply to the window size query
+    fn size_query_done(&mut self, cx: CX![]) {
+        self.size_query = false;
+        cx.timer_max_del(self.size_timer);
+    }
+
+    // Update the size and notify upstream
+    fn set_size(&mut self, cx: CX![], sy: i32, sx: i32, source: SizeSource) {
+        let ob = self.termout.rw(cx);
+        ob.set_size(sy, sx);
+        ob.size_source = source;
+        if sy < self.min_size.0 || sx < self.min_size.1 {
+            if !self.too_small {
+                self.too_small = true;
+                fwd!([self.resize], None);
+            }
+            if !self.paused {
+                self.show_too_small(cx);
+            }
+        } else {
+            if self.too_small {
+                self.too_small = false;
+                self.termout.rw(cx).discard();
+            }
+            fwd!([self.resize], Some(self.termout.clone()));
+            self.awaiting_flush = self.resize_throttle;
         }
     }
 
@@ -201,79 +1086,739 @@ impl Terminal {
 
     /// Handle new bytes from the TTY input
     pub(crate) fn handle_data_in(&mut self, cx: CX![]) {
-        self.glue.read_data(&mut self.inbuf);
-        self.do_data_in(cx, false);
+        if self.input_filter.is_some() {
+            let mut data = Vec::new();
+            self.glue.read_data(&mut data);
+            self.take_input(cx, data);
+        } else {
+            self.glue.read_data(&mut self.inbuf);
+            self.process_input(cx);
+        }
+    }
+
+    // Add freshly-arrived input bytes to the buffer and process them
+    fn take_input(&mut self, cx: CX![], mut data: Vec<u8>) {
+        if let Some(ref mut filter) = self.input_filter {
+            filter(&mut data);
+        }
+        self.inbuf.extend_from_slice(&data);
+        self.process_input(cx);
+    }
+
+    // Forward or decode the input bytes waiting
+    fn process_input(&mut self, cx: CX![]) {
+        if !self.inbuf.is_empty() {
+            self.restart_idle(cx);
+        }
+        if let Some((start, ref mut data, _)) = self.answerback {
+            // Capture everything that arrived after the ENQ was sent
+            data.extend(self.inbuf.drain(start.min(self.inbuf.len())..));
+            return;
+        }
+        if self.raw_input.is_some() {
+            self.forward_raw(cx);
+        } else {
+            self.do_data_in(cx, false);
+        }
+    }
+
+    // If in raw mode, forward all the input bytes waiting
+    fn forward_raw(&mut self, cx: CX![]) {
+        if let Some(ref fwd) = self.raw_input {
+            if !self.inbuf.is_empty() {
+                fwd!([fwd], std::mem::take(&mut self.inbuf));
+            }
+            cx.timer_max_del(self.force_timer);
+        }
     }
 
     fn do_data_in(&mut self, cx: CX![], force: bool) {
         let mut pos = 0;
         let len = self.inbuf.len();
         if len != 0 {
-            if !force {
-                // Note that this is too fast to catch M-Esc passed
-                // through screen, as that seems to apply a 300ms
-                // pause between the two Esc chars.  For everything
-                // else including real terminals it should be okay.
-                timer_max!(
-                    &mut self.force_timer,
-                    cx.now() + Duration::from_millis(100),
-                    [cx],
-                    do_data_in(true)
-                );
-            }
             while pos < len {
-                match Key::decode(&self.inbuf[pos..len], force) {
-                    None => break,
-                    Some((count, key)) => {
-                        pos += count;
-                        fwd!([self.input], key);
-                        if self.check_enable {
-                            let check_expiry = cx.now() + Duration::from_millis(300);
-                            timer_max!(&mut self.check_timer, check_expiry, [cx], check_key());
+                if self.paste.is_none()
+                    && (self.size_query
+                        || self.style_query
+                        || self.tcap.is_some()
+                        || self.feature_query.is_some())
+                {
+                    match reply::decode(&self.inbuf[pos..len]) {
+                        Decode::Reply(count, reply) => {
+                            pos += count;
+                            self.handle_reply(cx, reply);
+                            continue;
+                        }
+                        Decode::Partial if !force => break,
+                        _ => (),
+                    }
+                }
+                let key = if let Some(ref mut paste) = self.paste {
+                    // Inside a paste everything up to the end marker
+                    // is text, even if it looks like escape sequences
+                    let data = &self.inbuf[pos..len];
+                    match find_paste_end(data) {
+                        Ok(i) => {
+                            paste.extend_from_slice(&data[..i]);
+                            pos += i + PASTE_END.len();
+                        }
+                        Err(n) => {
+                            // Hold back anything that might be the
+                            // start of an end marker split across
+                            // reads, and resume the search from there
+                            paste.extend_from_slice(&data[..n]);
+                            pos += n;
+                            break;
+                        }
+                    }
+                    let paste = self.paste.take().unwrap_or_default();
+                    Key::Paste(String::from_utf8_lossy(&paste).into_owned())
+                } else {
+                    match Key::decode(&self.inbuf[pos..len], force) {
+                        None => break,
+                        Some((count, Key::PasteStart)) => {
+                            pos += count;
+                            self.paste = Some(Vec::new());
+                            continue;
+                        }
+                        Some((count, key)) => {
+                            pos += count;
+                            key
                         }
                     }
+                };
+                let key = match key {
+                    Key::Paste(text) if self.file_drop => match Key::paste_paths(&text) {
+                        Some(paths) => Key::FileDrop(paths),
+                        None => Key::Paste(text),
+                    },
+                    key => key,
+                };
+                self.forward_key(cx, key);
+                if self.check_enable {
+                    let check_expiry = cx.now() + Duration::from_millis(300);
+                    timer_max!(&mut self.check_timer, check_expiry, [cx], check_key());
                 }
             }
         }
         self.inbuf.drain(..pos);
+
+        if self.inbuf.is_empty() {
+            cx.timer_max_del(self.force_timer);
+        } else if !force && self.paste.is_none() && !cx.timer_max_active(self.force_timer) {
+            // A partial sequence is waiting for more bytes.  The
+            // timeout runs from when it was first seen, and isn't
+            // extended by further bytes dribbling in, so that a lone
+            // Esc always resolves in good time.
+            //
+            // Note that the default of 100ms is too fast to catch
+            // M-Esc passed through screen, as that seems to apply a
+            // 300ms pause between the two Esc chars.  For everything
+            // else including real terminals it should be okay.
+            //
+            // Within a paste, the held-back bytes are part of the
+            // paste whatever they turn out to be, so there is no
+            // need to force them.
+            timer_max!(
+                &mut self.force_timer,
+                cx.now() + self.esc_timeout,
+                [cx],
+                do_data_in(true)
+            );
+        }
+    }
+
+    // Handle a reply to a query sent to the terminal
+    fn handle_reply(&mut self, cx: CX![], reply: Reply) {
+        match reply {
+            Reply::WindowSize(sy, sx) => {
+                if self.size_query {
+                    self.size_query_done(cx);
+                    if sy > 0 && sx > 0 {
+                        self.set_size(cx, sy, sx, SizeSource::Query);
+                    }
+                }
+            }
+            Reply::Decrqss(setting) => {
+                if self.style_query {
+                    self.style_query_done(cx);
+                    if let Some(n) = setting.as_deref().and_then(reply::cursor_style) {
+                        self.cursor_style = format!("\x1B[{} q", n).into_bytes();
+                        self.update_panic_hook();
+                    }
+                }
+            }
+            Reply::Mode(mode, state) => {
+                if self.feature_query.is_some() {
+                    // States 1 and 2 are set and reset, and 3 is
+                    // permanently set.  0 means the mode isn't
+                    // recognised, and 4 that it is permanently reset,
+                    // so it can't be used.
+                    let supported = matches!(state, 1..=3);
+                    let features = &mut self.termout.rw(cx).features;
+                    match mode {
+                        2026 => features.sync_output = supported,
+                        2004 => features.bracketed_paste = supported,
+                        _ => (),
+                    }
+                }
+            }
+            Reply::DeviceAttrs(attrs) => {
+                if self.feature_query.is_some() {
+                    self.termout.rw(cx).features.sixel = attrs.iter().skip(1).any(|&a| a == 4);
+                    self.feature_query_done(cx);
+                }
+            }
+            Reply::Tcap(name, value) => {
+                if let Some(ref mut q) = self.tcap {
+                    // Match by name, or else take the oldest
+                    // unanswered query if the name wasn't given
+                    let unanswered = |i: &usize| q.results[*i].is_none();
+                    let i = (0..q.names.len())
+                        .filter(unanswered)
+                        .find(|i| name.is_empty() || q.names[*i] == name);
+                    if let Some(i) = i {
+                        q.results[i] = Some(value);
+                    }
+                    if q.results.iter().all(Option::is_some) {
+                        self.tcap_done(cx);
+                    }
+                }
+            }
+        }
     }
 
-    fn check_key(&mut self, _cx: CX![]) {
+    fn check_key(&mut self, cx: CX![]) {
         if self.check_enable {
-            fwd!([self.input], Key::Check);
+            self.forward_key(cx, Key::Check);
         }
     }
 
-    // Install a panic hook that (if necessary) outputs the current
-    // cleanup string, restores cooked mode and then does the default
-    // panic action (e.g. dump out backtrace).  This should be called
+    // Pass a key on to the app
+    fn forward_key(&mut self, cx: CX![], key: Key) {
+        match self.timed_input {
+            Some(ref fwd) => fwd!([fwd], (key, cx.now())),
+            None => fwd!([self.input], key),
+        }
+    }
+
+    // Get the sequences to restore modes changed by the app followed
+    // by the cleanup string, and then the original cursor style if
+    // known, since the cleanup string may have reset it
+    fn full_cleanup(&self) -> Vec<u8> {
+        let mut cleanup = self.restore.clone();
+        cleanup.extend_from_slice(&self.cleanup);
+        cleanup.extend_from_slice(&self.cursor_style);
+        cleanup
+    }
+
+    // Register a panic cleanup that (if necessary) outputs the
+    // current cleanup string and restores cooked mode, before the
+    // default panic action (e.g. dump out backtrace).  This should be called
     // every time we switch to/from raw mode, and every time the
     // cleanup string is changed.
     fn update_panic_hook(&mut self) {
-        // Discard old hook
-        let _ = std::panic::take_hook();
-
-        let defhook = self.panic_hook.clone();
         if self.paused {
-            std::panic::set_hook(Box::new(move |info| defhook(info)));
+            cleanup::set(self.panic_id, None);
         } else {
             let cleanup_fn = self.glue.cleanup_fn();
-            let cleanup = self.cleanup.clone();
-
-            std::panic::set_hook(Box::new(move |info| {
-                cleanup_fn(&cleanup[..]);
-                defhook(info);
-            }));
+            let cleanup = if self.disable_output {
+                Vec::new()
+            } else {
+                self.full_cleanup()
+            };
+            cleanup::set(
+                self.panic_id,
+                Some(Box::new(move || cleanup_fn(&cleanup[..]))),
+            );
         }
     }
 }
 
 impl Drop for Terminal {
     fn drop(&mut self) {
-        // Drop panic hook and clean up terminal
-        let _ = std::panic::take_hook();
+        // Drop panic cleanup and clean up terminal
+        cleanup::unregister(self.panic_id);
         if !self.paused {
-            self.glue.cleanup_fn()(&self.cleanup[..]);
+            if self.disable_output {
+                // Output failed earlier, so don't try writing again,
+                // but still get out of raw mode
+                self.glue.restore_termios();
+            } else {
+                self.glue.cleanup_fn()(&self.full_cleanup()[..]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os_glue::TtySource;
+    use stakker::{actor, fwd_do, ret_nop, ret_some_do, ActorOwn, Stakker};
+    use stakker_mio::mio::{Events, Poll};
+    use stakker_mio::MioPoll;
+    use std::cell::Cell;
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::rc::Rc;
+
+    // A terminal running on a pty in virtual time, recording the
+    // messages sent to the app
+    struct Harness {
+        stakker: Stakker,
+        now: Instant,
+        master: File,
+        term: ActorOwn<Terminal>,
+        keys: Rc<RefCell<Vec<Key>>>,
+        resizes: Rc<RefCell<Vec<Option<Share<TermOut>>>>>,
+    }
+
+    impl Harness {
+        // Start a terminal on a pty of the given size, and discard
+        // the startup queries it sends
+        fn new(sy: u16, sx: u16) -> Self {
+            let now = Instant::now();
+            let mut stakker = Stakker::new(now);
+            let poll = Poll::new().expect("Poll::new failed");
+            MioPoll::new(&mut stakker, poll, Events::with_capacity(64), 0)
+                .expect("MioPoll::new failed");
+            let ws = libc::winsize {
+                ws_row: sy,
+                ws_col: sx,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            let (mut master, mut slave) = (0, 0);
+            let (name, termp) = (std::ptr::null_mut(), std::ptr::null());
+            let rv = unsafe { libc::openpty(&mut master, &mut slave, name, termp, &ws) };
+            assert_eq!(rv, 0, "openpty failed");
+            unsafe { libc::fcntl(master, libc::F_SETFL, libc::O_NONBLOCK) };
+            let master = unsafe { File::from_raw_fd(master) };
+
+            let keys = Rc::new(RefCell::new(Vec::new()));
+            let resizes = Rc::new(RefCell::new(Vec::new()));
+            let k = keys.clone();
+            let input = fwd_do!(move |key| k.borrow_mut().push(key));
+            let r = resizes.clone();
+            let resize = fwd_do!(move |termout| r.borrow_mut().push(termout));
+            let source = TtySource::Pty(slave);
+            let term = actor!(
+                stakker,
+                Terminal::init_aux(resize, input, source),
+                ret_nop!()
+            );
+            let mut this = Self {
+                stakker,
+                now,
+                master,
+                term,
+                keys,
+                resizes,
+            };
+            this.advance(0);
+            this.output();
+            this
+        }
+
+        // Move virtual time on and run everything that is due
+        fn advance(&mut self, ms: u64) {
+            self.now += Duration::from_millis(ms);
+            self.stakker.run(self.now, false);
+        }
+
+        // Pass bytes to the terminal as if they had been typed
+        fn input(&mut self, data: &[u8]) {
+            let data = data.to_vec();
+            self.term
+                .query(&mut self.stakker, |this, cx| this.take_input(cx, data));
+            self.advance(0);
+        }
+
+        // Change the pty size and deliver the resize event, as if
+        // SIGWINCH had arrived
+        fn set_size(&mut self, sy: u16, sx: u16) {
+            let ws = libc::winsize {
+                ws_row: sy,
+                ws_col: sx,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            let fd = self.master.as_raw_fd();
+            assert_eq!(0, unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &ws) });
+            self.term
+                .query(&mut self.stakker, |this, cx| this.handle_resize(cx));
+            self.advance(0);
+        }
+
+        // Access the terminal's `TermOut`
+        fn termout<R>(&mut self, f: impl FnOnce(&mut TermOut) -> R) -> R {
+            let termout = self
+                .term
+                .query(&mut self.stakker, |this, _| this.termout.clone());
+            f(termout.unwrap().rw(&mut self.stakker))
+        }
+
+        // Take the keys forwarded to the app so far
+        fn keys(&mut self) -> Vec<Key> {
+            self.keys.borrow_mut().drain(..).collect()
+        }
+
+        // Take the `resize` messages sent to the app so far, as sizes
+        fn resizes(&mut self) -> Vec<Option<(i32, i32)>> {
+            let resizes: Vec<_> = self.resizes.borrow_mut().drain(..).collect();
+            let core = &self.stakker;
+            resizes
+                .into_iter()
+                .map(|r| r.map(|t| t.ro(core).size()))
+                .collect()
+        }
+
+        // Take the output sent to the pty so far, with ESC shown as
+        // `^` for readability
+        fn output(&mut self) -> String {
+            let mut data = Vec::new();
+            let _ = self.master.read_to_end(&mut data);
+            String::from_utf8_lossy(&data).replace('\x1b', "^")
+        }
+    }
+
+    #[test]
+    fn esc_timeout_from_first_byte() {
+        let mut h = Harness::new(24, 80);
+        assert_eq!(h.resizes(), [Some((24, 80))]);
+        h.input(b"\x1B");
+        h.advance(60);
+        h.input(b"[");
+        assert_eq!(h.keys(), []);
+        // Forced out 100ms after the Esc, not after the last byte
+        h.advance(50);
+        assert_eq!(h.keys(), [Key::Meta('[')]);
+        h.advance(200);
+        assert_eq!(h.keys(), []);
+    }
+
+    #[test]
+    fn input_filter() {
+        let mut h = Harness::new(24, 80);
+        let filter: InputFilter = Box::new(|data| {
+            for b in data.iter_mut() {
+                if *b == b'x' {
+                    *b = b'y';
+                }
+            }
+            data.retain(|b| *b != b'z');
+        });
+        h.term
+            .query(&mut h.stakker, |this, cx| this.set_input_filter(cx, filter));
+        h.input(b"axz");
+        assert_eq!(h.keys(), [Key::Pr('a'), Key::Pr('y')]);
+    }
+
+    #[test]
+    fn throttle_resize() {
+        let mut h = Harness::new(24, 80);
+        h.resizes();
+        h.term
+            .query(&mut h.stakker, |this, cx| this.throttle_resize(cx, true));
+        h.set_size(30, 100);
+        assert_eq!(h.resizes(), [Some((30, 100))]);
+
+        // Held back until the app flushes, then only the latest
+        h.set_size(31, 101);
+        h.set_size(32, 102);
+        assert_eq!(h.resizes(), []);
+        h.term.query(&mut h.stakker, |this, cx| this.flush(cx));
+        assert_eq!(h.resizes(), [Some((32, 102))]);
+        h.term.query(&mut h.stakker, |this, cx| this.flush(cx));
+        assert_eq!(h.resizes(), []);
+
+        // Disabling lets a held-back resize through
+        h.set_size(33, 103);
+        assert_eq!(h.resizes(), [Some((33, 103))]);
+        h.set_size(34, 104);
+        assert_eq!(h.resizes(), []);
+        h.term
+            .query(&mut h.stakker, |this, cx| this.throttle_resize(cx, false));
+        assert_eq!(h.resizes(), [Some((34, 104))]);
+    }
+
+    #[test]
+    fn resize_generation() {
+        let mut h = Harness::new(24, 80);
+        let g0 = h.termout(|t| t.generation());
+        assert!(g0 > 0);
+        h.set_size(30, 100);
+        h.set_size(30, 100);
+        assert_eq!(h.termout(|t| t.generation()), g0 + 2);
+    }
+
+    #[test]
+    fn size_query_timeout() {
+        // A pty with no size makes the terminal ask for it
+        let mut h = Harness::new(0, 0);
+        let pending = |h: &mut Harness| h.term.query(&mut h.stakker, |this, _| this.size_query);
+        assert_eq!(pending(&mut h), Some(true));
+        h.advance(600);
+        assert_eq!(pending(&mut h), Some(false));
+
+        // So the next resize asks again, and the reply is used
+        h.resizes();
+        h.set_size(0, 0);
+        assert!(h.output().contains("^[18t"));
+        h.input(b"\x1B[8;30;100t");
+        assert_eq!(h.keys(), []);
+        assert_eq!(h.resizes().last(), Some(&Some((30, 100))));
+    }
+
+    #[test]
+    fn set_mode() {
+        let mut h = Harness::new(24, 80);
+        let base = h
+            .term
+            .query(&mut h.stakker, |this, _| this.restore.clone())
+            .unwrap();
+        let set = |h: &mut Harness, enable: &[u8], disable: &[u8]| {
+            let handle = Rc::new(Cell::new(None));
+            let (enable, disable, h2) = (enable.to_vec(), disable.to_vec(), handle.clone());
+            let ret = ret_some_do!(move |v| h2.set(Some(v)));
+            h.term.query(&mut h.stakker, |this, cx| {
+                this.set_mode(cx, enable, disable, ret)
+            });
+            h.advance(0);
+            handle.get().unwrap()
+        };
+        let m1 = set(&mut h, b"<1", b">1");
+        let _m2 = set(&mut h, b"<2", b">2");
+        h.term
+            .query(&mut h.stakker, |this, cx| this.unset_mode(cx, m1));
+        h.termout(|t| t.flush());
+        let restore = h.term.query(&mut h.stakker, |this, cx| {
+            this.flush(cx);
+            this.restore.clone()
+        });
+        let out = String::from_utf8(restore.unwrap()).unwrap();
+        assert!(out.contains(">2"));
+        assert!(!out.contains(">1"));
+        assert_eq!(out.len(), base.len() + 2);
+        assert!(h.output().ends_with("<1<2>1"));
+    }
+
+    #[test]
+    fn paste_is_not_decoded() {
+        let mut h = Harness::new(24, 80);
+        h.input(b"\x1B[200~a\x1B[Ab\x1B[201~c");
+        assert_eq!(h.keys(), [Key::Paste("a\x1B[Ab".into()), Key::Pr('c')]);
+
+        // Not even as a reply to an outstanding query
+        h.term
+            .query(&mut h.stakker, |this, _| this.size_query = true);
+        h.input(b"\x1B[200~\x1B[8;30;100t\x1B[201~");
+        assert_eq!(h.keys(), [Key::Paste("\x1B[8;30;100t".into())]);
+    }
+
+    #[test]
+    fn paste_split_across_reads() {
+        let mut h = Harness::new(24, 80);
+        let text: String = (0..100_026)
+            .map(|i| match i % 50 {
+                0 => '\x1B',
+                1 => '[',
+                49 => '\n',
+                _ => char::from(b'a' + (i % 26) as u8),
+            })
+            .collect();
+        let mut data = b"x\x1B[200~".to_vec();
+        data.extend_from_slice(text.as_bytes());
+        data.extend_from_slice(b"\x1B[201~y");
+        // Split both markers across reads
+        h.input(&data[..4]);
+        for chunk in data[4..].chunks(32) {
+            h.input(chunk);
+            // Time passing between reads doesn't end the paste
+            h.advance(200);
         }
+        assert_eq!(h.keys(), [Key::Pr('x'), Key::Paste(text), Key::Pr('y')]);
+    }
+
+    type TcapResults = Rc<RefCell<Option<Vec<(String, Option<String>)>>>>;
+
+    // Run `query_tcap` and return where the results will be put
+    fn query_tcap(h: &mut Harness, names: &[&str]) -> TcapResults {
+        let results = Rc::new(RefCell::new(None));
+        let r = results.clone();
+        let ret = ret_some_do!(move |v| *r.borrow_mut() = Some(v));
+        let names = names.iter().map(|n| n.to_string()).collect();
+        h.term
+            .query(&mut h.stakker, |this, cx| this.query_tcap(cx, names, ret));
+        h.advance(0);
+        results
+    }
+
+    #[test]
+    fn tcap_tc() {
+        let mut h = Harness::new(24, 80);
+        h.termout(|t| t.features = Features::default());
+        let results = query_tcap(&mut h, &["Tc", "colors"]);
+        assert_eq!(h.output(), "^P+q5463^\\^P+q636F6C6F7273^\\");
+        h.input(b"\x1BP1+r5463\x1B\\\x1BP1+r636F6C6F7273=323536\x1B\\");
+        assert_eq!(h.keys(), []);
+        let expect = [
+            ("Tc".into(), Some("".into())),
+            ("colors".into(), Some("256".into())),
+        ];
+        assert_eq!(results.borrow().as_deref(), Some(&expect[..]));
+        let features = h.termout(|t| t.features.clone());
+        assert!(features.colour_256 && features.truecolor);
+    }
+
+    #[test]
+    fn cursor_style_query() {
+        let mut h = Harness::new(24, 80);
+        h.input(b"\x1BP1$r4 q\x1B\\");
+        assert_eq!(h.keys(), []);
+        let style = h
+            .term
+            .query(&mut h.stakker, |this, _| this.cursor_style.clone());
+        assert_eq!(style.as_deref(), Some(&b"\x1B[4 q"[..]));
+
+        // Ignored if too late, after the timeout
+        drop(h);
+        let mut h = Harness::new(24, 80);
+        h.advance(600);
+        h.input(b"\x1BP1$r2 q\x1B\\");
+        assert_ne!(h.keys(), []);
+        let style = h
+            .term
+            .query(&mut h.stakker, |this, _| this.cursor_style.clone());
+        assert_eq!(style, Some(Vec::new()));
+    }
+
+    #[test]
+    fn query_features() {
+        let mut h = Harness::new(24, 80);
+        h.term
+            .query(&mut h.stakker, |this, cx| this.query_features(cx));
+        // Permanently reset means bracketed paste can't be used
+        h.input(b"\x1B[?2026;2$y\x1B[?2004;4$y\x1B[?62;4c");
+        assert_eq!(h.keys(), []);
+        let features = h.term.query(&mut h.stakker, |this, cx| {
+            let f = &this.termout.rw(cx).features;
+            (f.sync_output, f.bracketed_paste, f.sixel)
+        });
+        assert_eq!(features, Some((true, false, true)));
+    }
+
+    #[test]
+    fn tcap_colors() {
+        // `colors` alone doesn't turn off 24-bit colour detected
+        // from the environment
+        let mut h = Harness::new(24, 80);
+        h.termout(|t| t.features.truecolor = true);
+        let _results = query_tcap(&mut h, &["colors"]);
+        h.input(b"\x1BP1+r636F6C6F7273=323536\x1B\\");
+        let features = h.termout(|t| t.features.clone());
+        assert!(features.colour_256 && features.truecolor);
+
+        // But it does if the terminal says that it doesn't have `RGB`
+        drop(h);
+        let mut h = Harness::new(24, 80);
+        h.termout(|t| t.features.truecolor = true);
+        let _results = query_tcap(&mut h, &["RGB", "colors"]);
+        h.input(b"\x1BP0+r524742\x1B\\\x1BP1+r636F6C6F7273=323536\x1B\\");
+        let features = h.termout(|t| t.features.clone());
+        assert!(features.colour_256 && !features.truecolor);
+
+        // And `colors` can turn it on
+        drop(h);
+        let mut h = Harness::new(24, 80);
+        h.termout(|t| t.features = Features::default());
+        let _results = query_tcap(&mut h, &["colors"]);
+        h.input(b"\x1BP1+r636F6C6F7273=3136373737323136\x1B\\");
+        let features = h.termout(|t| t.features.clone());
+        assert!(features.colour_256 && features.truecolor);
+    }
+
+    #[test]
+    fn continue_cancels_debounce() {
+        let mut h = Harness::new(24, 80);
+        h.resizes();
+        h.term.query(&mut h.stakker, |this, cx| {
+            this.debounce_resize(cx, Duration::from_millis(50))
+        });
+        h.set_size(30, 100);
+        h.advance(20);
+        assert_eq!(h.resizes(), []);
+        h.term
+            .query(&mut h.stakker, |this, cx| this.handle_continue(cx));
+        assert_eq!(h.resizes(), [Some((30, 100))]);
+        // The debounced resize has already been handled
+        h.advance(100);
+        assert_eq!(h.resizes(), []);
+    }
+
+    #[test]
+    fn size_from() {
+        let vars = |lines: &'static str, columns: &'static str| {
+            move |name: &str| match name {
+                "LINES" if !lines.is_empty() => Some(lines.to_string()),
+                "COLUMNS" if !columns.is_empty() => Some(columns.to_string()),
+                _ => None,
+            }
+        };
+        let size = Terminal::size_from;
+        assert_eq!(
+            size(Some((30, 100)), vars("40", "120")),
+            Some((30, 100, SizeSource::Ioctl))
+        );
+
+        // A zero size from the OS falls back to the environment
+        assert_eq!(
+            size(Some((0, 0)), vars("40", "120")),
+            Some((40, 120, SizeSource::Env))
+        );
+        assert_eq!(
+            size(Some((30, 0)), vars(" 40 ", "120")),
+            Some((40, 120, SizeSource::Env))
+        );
+        assert_eq!(
+            size(None, vars("1", "99999")),
+            Some((2, 9999, SizeSource::Env))
+        );
+
+        // Both variables must be positive integers
+        assert_eq!(size(Some((0, 0)), vars("40", "")), None);
+        assert_eq!(size(None, vars("0", "120")), None);
+        assert_eq!(size(None, vars("40", "wide")), None);
+    }
+
+    #[test]
+    fn drop_after_output_failure() {
+        let lflag = |master: &File| {
+            let mut tios = unsafe { std::mem::zeroed::<libc::termios>() };
+            assert_eq!(0, unsafe { libc::tcgetattr(master.as_raw_fd(), &mut tios) });
+            tios.c_lflag
+        };
+        let mut h = Harness::new(24, 80);
+        assert_eq!(lflag(&h.master) & libc::ICANON, 0);
+        h.term
+            .query(&mut h.stakker, |this, _| this.disable_output = true);
+
+        // Raw mode is still turned off, but nothing is written
+        let Harness {
+            mut stakker,
+            mut master,
+            term,
+            now,
+            ..
+        } = h;
+        drop(term);
+        stakker.run(now, false);
+        assert_ne!(lflag(&master) & libc::ICANON, 0);
+        let mut data = Vec::new();
+        let _ = master.read_to_end(&mut data);
+        assert_eq!(data, b"");
     }
 }
@@ -0,0 +1,162 @@
+// Minimal reader for compiled terminfo files
+//
+// This only extracts the few numeric capabilities that we need for
+// feature detection.  Anything unexpected in the file results in
+// `None`, and the caller falls back to guessing from the
+// environment.
+
+use std::path::PathBuf;
+
+// Index of the `colors` capability in the numbers section
+const NUM_COLORS: usize = 13;
+
+/// Get the `colors` capability for the given terminal type, if the
+/// terminfo entry can be found and contains it.  Environment
+/// variables are looked up through `var`.
+pub(crate) fn colours(term: &str, var: &dyn Fn(&str) -> Option<String>) -> Option<i32> {
+    number(&load(term, var)?, NUM_COLORS)
+}
+
+// Search the standard locations for the compiled entry for `term`
+fn load(term: &str, var: &dyn Fn(&str) -> Option<String>) -> Option<Vec<u8>> {
+    let first = term.chars().next()?;
+    if term.contains('/') || term.starts_with('.') {
+        return None;
+    }
+
+    let mut dirs = Vec::new();
+    if let Some(dir) = var("TERMINFO") {
+        dirs.push(PathBuf::from(dir));
+    }
+    if let Some(home) = var("HOME") {
+        dirs.push(PathBuf::from(home).join(".terminfo"));
+    }
+    if let Some(list) = var("TERMINFO_DIRS") {
+        for dir in list.split(':') {
+            if !dir.is_empty() {
+                dirs.push(PathBuf::from(dir));
+            }
+        }
+    }
+    for dir in &["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo"] {
+        dirs.push(PathBuf::from(dir));
+    }
+
+    // Most systems use the first letter for the subdirectory, but
+    // macOS uses the first byte in hex
+    let subdirs = [first.to_string(), format!("{:02x}", term.as_bytes()[0])];
+    for dir in dirs {
+        for sub in &subdirs {
+            if let Ok(data) = std::fs::read(dir.join(sub).join(term)) {
+                return Some(data);
+            }
+        }
+    }
+    None
+}
+
+// Get numeric capability `index` from compiled terminfo data.  Both
+// the legacy 16-bit format and the newer 32-bit format are handled.
+fn number(data: &[u8], index: usize) -> Option<i32> {
+    let hdr = |i: usize| -> Option<usize> {
+        let v = i16::from_le_bytes([*data.get(i * 2)?, *data.get(i * 2 + 1)?]);
+        if v < 0 {
+            None
+        } else {
+            Some(v as usize)
+        }
+    };
+    let wid = match hdr(0)? {
+        0o432 => 2,
+        0o1036 => 4,
+        _ => return None,
+    };
+    let names_size = hdr(1)?;
+    let bool_count = hdr(2)?;
+    let num_count = hdr(3)?;
+    if index >= num_count {
+        return None;
+    }
+    let mut off = 12 + names_size + bool_count;
+    off += off & 1;
+    off += index * wid;
+    let val = match wid {
+        2 => i32::from(i16::from_le_bytes([*data.get(off)?, *data.get(off + 1)?])),
+        _ => i32::from_le_bytes([
+            *data.get(off)?,
+            *data.get(off + 1)?,
+            *data.get(off + 2)?,
+            *data.get(off + 3)?,
+        ]),
+    };
+    // Negative values mean absent or cancelled
+    if val < 0 {
+        None
+    } else {
+        Some(val)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Build a compiled entry with just `colors` set, or absent if
+    // negative.  The header and names come to an odd length, to check
+    // the padding before the numbers.
+    pub(crate) fn entry(wide: bool, colours: i32) -> Vec<u8> {
+        let names = b"test|Test terminal\0";
+        let magic = if wide { 0o1036 } else { 0o432 };
+        let mut data = Vec::new();
+        for v in [magic, names.len() as i16, 2, 15, 0, 0] {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        data.extend_from_slice(names);
+        data.extend_from_slice(&[1, 0, 0]);
+        for i in 0..15 {
+            let v = if i == NUM_COLORS { colours } else { -1 };
+            if wide {
+                data.extend_from_slice(&v.to_le_bytes());
+            } else {
+                data.extend_from_slice(&(v as i16).to_le_bytes());
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn number_formats() {
+        assert_eq!(number(&entry(false, 256), NUM_COLORS), Some(256));
+        assert_eq!(
+            number(&entry(true, 0x100_0000), NUM_COLORS),
+            Some(0x100_0000)
+        );
+        assert_eq!(number(&entry(false, -1), NUM_COLORS), None);
+        assert_eq!(number(&entry(false, 8), 15), None);
+        let data = entry(true, 256);
+        assert_eq!(number(&data[..data.len() - 8], NUM_COLORS), None);
+        let mut data = entry(false, 256);
+        data[0] = 0;
+        assert_eq!(number(&data, NUM_COLORS), None);
+    }
+
+    #[test]
+    fn colours_from_dir() {
+        let dir = std::env::temp_dir().join(format!("stakker_tui_terminfo_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("t")).unwrap();
+        std::fs::create_dir_all(dir.join("75")).unwrap();
+        std::fs::write(dir.join("t").join("test-256"), entry(false, 256)).unwrap();
+        std::fs::write(dir.join("75").join("usertest"), entry(true, 8)).unwrap();
+        let dir2 = dir.clone();
+        let var = move |name: &str| match name {
+            "TERMINFO" => Some(dir2.to_string_lossy().into_owned()),
+            _ => None,
+        };
+        assert_eq!(colours("test-256", &var), Some(256));
+        assert_eq!(colours("usertest", &var), Some(8));
+        assert_eq!(colours("test-missing-entry", &var), None);
+        assert_eq!(colours("t/../test-256", &var), None);
+        assert_eq!(colours("", &var), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
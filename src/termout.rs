@@ -1,3 +1,6 @@
+use crate::width::char_width;
+use crate::{Attr, Colour, Palette};
+use std::borrow::Cow;
 use std::io::{Result, Write};
 
 /// Output buffer for the terminal
@@ -25,19 +28,85 @@ use std::io::{Result, Write};
 pub struct TermOut {
     buf: Vec<u8>,
     flush_to: usize,
-    features: Features,
+    // Count of bytes removed from the front of `buf` so far, so that
+    // a `Mark` can be checked against the current buffer
+    drained: u64,
+    pub(crate) features: Features,
     size: (i32, i32),
+    narrow_width: i32,
+    pub(crate) size_source: SizeSource,
+    generation: u64,
     pub(crate) new_cleanup: Option<Vec<u8>>,
+    // Cursor position as last set by `at` or by page rendering, if
+    // known
+    pub(crate) cursor: Option<(i32, i32)>,
+    // Sequences to restore modes changed by the app, keyed by the
+    // mode, to be sent before the cleanup string
+    restore: Vec<(RestoreKey, Vec<u8>)>,
+    pub(crate) restore_changed: bool,
+    // Bytes flushed but still queued waiting for the terminal
+    pub(crate) backlog: usize,
+    // Sequences to set up again the modes changed by the app, in the
+    // order they were changed, for `TermState`
+    modes: Vec<(RestoreKey, Vec<u8>)>,
+    // The attribute sequence last output, if known
+    attr_seq: Vec<u8>,
+    // Is the attribute last selected known to be the default?
+    pub(crate) attr_default: bool,
+    // Cursor position and attributes saved by `save_cursor`, with
+    // no modes
+    saved_cursor: Option<TermState>,
+    frame_reset: FrameReset,
+    // Is the cursor visible, as far as we know?
+    pub(crate) cursor_visible: bool,
+    // Hide the cursor whilst each flush is written?
+    pub(crate) hide_on_flush: bool,
+    // Maps colours the terminal doesn't support onto ones it does
+    downgrade: Box<dyn Fn(Colour, Palette) -> Colour>,
+}
+
+// Identifies a terminal mode that needs restoring on cleanup
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RestoreKey {
+    CursorBlink,
+    AltScreen,
+    Mode(u64),
+    ScrollRegion,
+    Title,
 }
 
 impl TermOut {
+    /// Maximum number of bytes of text accepted by
+    /// [`TermOut::clipboard_set`].  This is 75000 bytes, which
+    /// encodes to 100000 bytes of base64, the default limit in
+    /// several terminals.
+    ///
+    /// [`TermOut::clipboard_set`]: struct.TermOut.html#method.clipboard_set
+    pub const CLIPBOARD_MAX: usize = 75000;
+
     pub(crate) fn new(features: Features) -> Self {
         Self {
             buf: Vec::new(),
             flush_to: 0,
+            drained: 0,
+            backlog: 0,
             features,
             new_cleanup: None,
             size: (0, 0),
+            narrow_width: 60,
+            size_source: SizeSource::Ioctl,
+            generation: 0,
+            cursor: None,
+            restore: Vec::new(),
+            restore_changed: false,
+            modes: Vec::new(),
+            attr_seq: Vec::new(),
+            attr_default: false,
+            saved_cursor: None,
+            frame_reset: FrameReset::Never,
+            cursor_visible: true,
+            hide_on_flush: false,
+            downgrade: Box::new(Colour::downgrade),
         }
     }
 
@@ -53,6 +122,18 @@ impl TermOut {
         self.size
     }
 
+    /// Get the number of bytes flushed which the terminal hasn't yet
+    /// accepted, e.g. because of XOFF or a slow link.  Whilst this is
+    /// non-zero, an app that redraws frequently may prefer to skip
+    /// redraws rather than queue up more output.  See
+    /// [`Terminal::output_drained`].
+    ///
+    /// [`Terminal::output_drained`]: struct.Terminal.html#method.output_drained
+    #[inline]
+    pub fn backlog(&self) -> usize {
+        self.backlog
+    }
+
     /// Get current terminal size-Y, i.e. rows
     #[inline]
     pub fn sy(&self) -> i32 {
@@ -65,6 +146,59 @@ impl TermOut {
         self.size.1
     }
 
+    /// Test whether the terminal is narrower than the threshold set
+    /// with [`TermOut::set_narrow_width`], which defaults to 60
+    /// columns.  An app may use this to switch to a more compact
+    /// layout, e.g. for a phone SSH client or a split pane.  This is
+    /// based on the current size, so check it again after each
+    /// `resize` message.
+    ///
+    /// [`TermOut::set_narrow_width`]: struct.TermOut.html#method.set_narrow_width
+    #[inline]
+    pub fn is_narrow(&self) -> bool {
+        self.size.1 < self.narrow_width
+    }
+
+    /// Set the width in columns below which [`TermOut::is_narrow`]
+    /// reports the terminal as narrow
+    ///
+    /// [`TermOut::is_narrow`]: struct.TermOut.html#method.is_narrow
+    #[inline]
+    pub fn set_narrow_width(&mut self, cols: i32) {
+        self.narrow_width = cols;
+    }
+
+    /// Get the aspect ratio of the terminal as columns per row, or
+    /// 0.0 if there are no rows.  Note that character cells are
+    /// typically about twice as tall as they are wide, so a ratio of
+    /// around 2.0 is visually square, and anything less than that
+    /// is taller than it is wide (portrait).
+    #[inline]
+    pub fn aspect_ratio(&self) -> f64 {
+        match self.size {
+            (0, _) => 0.0,
+            (sy, sx) => f64::from(sx) / f64::from(sy),
+        }
+    }
+
+    /// Get where the current terminal size came from.  Normally this
+    /// is from the OS, but if that fails, other sources are tried.
+    #[inline]
+    pub fn size_source(&self) -> SizeSource {
+        self.size_source
+    }
+
+    /// Get the resize generation.  This increases by one every time
+    /// the terminal size is updated, just before the `resize`
+    /// message is sent.  An app that does layout work asynchronously
+    /// can record this value when it starts, and discard the results
+    /// if it has changed by the time they are ready, since they were
+    /// calculated for a superseded size.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     /// Mark all the data from the start of the buffer to the current
     /// end of the buffer as ready for flushing.  However the data
     /// won't be flushed until the [`Terminal`] actor receives a
@@ -78,6 +212,57 @@ impl TermOut {
         self.flush_to = self.buf.len();
     }
 
+    /// Get a mark for the current end of the buffer, which may later
+    /// be passed to [`TermOut::rewind`] to discard anything added
+    /// after this point.
+    ///
+    /// [`TermOut::rewind`]: struct.TermOut.html#method.rewind
+    #[inline]
+    pub fn mark(&self) -> Mark {
+        Mark {
+            pos: self.drained + self.buf.len() as u64,
+            cursor: self.cursor,
+            cursor_visible: self.cursor_visible,
+            saved_cursor: self.saved_cursor.clone(),
+            attr_seq: self.attr_seq.clone(),
+            attr_default: self.attr_default,
+            modes: self.modes.clone(),
+            restore: self.restore.clone(),
+        }
+    }
+
+    /// Discard everything added to the buffer since the given mark
+    /// was taken, for example a temporary overlay that the app has
+    /// decided not to show after all.  This is only possible if none
+    /// of that data has been marked ready for flushing with
+    /// [`TermOut::flush`], and if the buffer hasn't already been
+    /// rewound to before the mark.  The state tracked alongside the
+    /// buffer is also put back as it was at the mark: the cursor
+    /// position and visibility, the attributes last selected, the
+    /// modes set and the sequences to restore them on cleanup.
+    /// Returns `true` if the buffer was rewound, or `false` if it was
+    /// left unchanged.
+    ///
+    /// [`TermOut::flush`]: struct.TermOut.html#method.flush
+    pub fn rewind(&mut self, mark: Mark) -> bool {
+        let end = self.drained + self.buf.len() as u64;
+        if mark.pos < self.drained + self.flush_to as u64 || mark.pos > end {
+            return false;
+        }
+        self.buf.truncate((mark.pos - self.drained) as usize);
+        self.cursor = mark.cursor;
+        self.cursor_visible = mark.cursor_visible;
+        self.saved_cursor = mark.saved_cursor;
+        self.attr_seq = mark.attr_seq;
+        self.attr_default = mark.attr_default;
+        self.modes = mark.modes;
+        if self.restore != mark.restore {
+            self.restore = mark.restore;
+            self.restore_changed = true;
+        }
+        true
+    }
+
     /// Add a chunk of UTF-8 string data to the output buffer.
     ///
     /// See also the `Write` implementation, which allows use of
@@ -98,6 +283,42 @@ impl TermOut {
         self
     }
 
+    /// Add as much of `text` as fits within `width` cells, and
+    /// return the number of cells used.  If the text has to be
+    /// truncated and `ellipsis` is set, then the last cell is used
+    /// for `…` to show that the text has been cut short.  Wide
+    /// characters (e.g. CJK) take up two cells, and a wide character
+    /// that would straddle the limit is left out.  Combining marks
+    /// are kept with the preceding character.  Nothing is output to
+    /// pad out the remaining cells.
+    pub fn out_fit(&mut self, text: &str, width: i32, ellipsis: bool) -> i32 {
+        if width <= 0 {
+            return 0;
+        }
+        let full: i32 = text.chars().map(char_width).sum();
+        if full <= width {
+            self.out(text);
+            return full;
+        }
+        let limit = if ellipsis { width - 1 } else { width };
+        let mut used = 0;
+        let mut end = 0;
+        for (i, c) in text.char_indices() {
+            let w = char_width(c);
+            if used + w > limit {
+                break;
+            }
+            used += w;
+            end = i + c.len_utf8();
+        }
+        self.out(&text[..end]);
+        if ellipsis {
+            self.out("…");
+            used += 1;
+        }
+        used
+    }
+
     /// Add a single byte to the output buffer.
     #[inline]
     pub fn byt(&mut self, v1: u8) -> &mut Self {
@@ -126,8 +347,20 @@ impl TermOut {
         self.esc('[')
     }
 
-    /// Add a 1-3 digit decimal number (0..=999) to the output buffer,
-    /// as used in control sequences.  If number is out of range, then
+    /// Add `ESC ]`, which is the OSC sequence
+    #[inline]
+    pub fn osc(&mut self) -> &mut Self {
+        self.esc(']')
+    }
+
+    /// Add `ESC \`, which is the ST (string terminator) sequence
+    #[inline]
+    pub fn st(&mut self) -> &mut Self {
+        self.esc('\\')
+    }
+
+    /// Add a decimal number (0..=65535) to the output buffer, as
+    /// used in control sequences.  If number is out of range, then
     /// nearest valid number is used.
     pub fn num(&mut self, v: i32) -> &mut Self {
         if v <= 0 {
@@ -136,29 +369,40 @@ impl TermOut {
             self.byt(v as u8 + b'0');
         } else if v <= 99 {
             self.byt((v / 10) as u8 + b'0').byt((v % 10) as u8 + b'0');
-        } else if v <= 999 {
-            self.byt((v / 100) as u8 + b'0')
-                .byt((v / 10 % 10) as u8 + b'0')
-                .byt((v % 10) as u8 + b'0');
         } else {
-            self.asc('9').asc('9').asc('9');
+            self.count(v.min(65535) as usize);
         }
         self
     }
 
+    /// Get the cursor position as last output, either by
+    /// [`TermOut::at`] or by page rendering, or `None` if it is not
+    /// known.  It becomes unknown after anything that might move the
+    /// cursor unpredictably, such as a tab or a resize.  Note that
+    /// text added with [`TermOut::out`] or [`TermOut::bytes`] is not
+    /// tracked, so after adding text directly, the app should finish
+    /// with [`TermOut::at`] to keep this accurate.
+    ///
+    /// [`TermOut::at`]: struct.TermOut.html#method.at
+    /// [`TermOut::bytes`]: struct.TermOut.html#method.bytes
+    /// [`TermOut::out`]: struct.TermOut.html#method.out
+    #[inline]
+    pub fn last_cursor(&self) -> Option<(i32, i32)> {
+        self.cursor
+    }
+
     /// Add ANSI sequence to move cursor to the given coordinates.
     /// Note that coordinates are row-first, with (0,0) as top-left.
     /// Coordinates are taken modulo the screen dimensions, so for
     /// example -1,-1 is bottom-right, and (0, -10) is 10 from the
-    /// right on the top line.
+    /// right on the top line.  If the size is not yet known, a zero
+    /// dimension is treated as 1.
     #[inline]
     pub fn at(&mut self, y: i32, x: i32) -> &mut Self {
-        let (sy, sx) = self.size;
-        self.csi()
-            .num(y.rem_euclid(sy) + 1)
-            .asc(';')
-            .num(x.rem_euclid(sx) + 1)
-            .asc('H')
+        let (sy, sx) = (self.size.0.max(1), self.size.1.max(1));
+        let (y, x) = (y.rem_euclid(sy), x.rem_euclid(sx));
+        self.cursor = Some((y, x));
+        self.csi().num(y + 1).asc(';').num(x + 1).asc('H')
     }
 
     /// Add an attribute string.  The codes passed should be the
@@ -166,6 +410,8 @@ impl TermOut {
     /// "1;31;46".
     #[inline]
     pub fn attr(&mut self, codes: &str) -> &mut Self {
+        self.attr_default = false;
+        self.attr_seq = format!("\x1B[{}m", codes).into_bytes();
         self.csi().out(codes).asc('m')
     }
 
@@ -179,6 +425,8 @@ impl TermOut {
     #[inline]
     pub fn hfb(&mut self, hfb: u8) -> &mut Self {
         const FG: [i32; 10] = [30, 34, 31, 35, 32, 36, 33, 37, 39, 39];
+        self.attr_default = hfb < 100 && hfb / 10 % 10 >= 8 && hfb % 10 >= 8;
+        let start = self.buf.len();
         self.out("\x1B[0;");
         if hfb >= 100 {
             self.out("1;");
@@ -186,7 +434,249 @@ impl TermOut {
         self.num(FG[(hfb / 10 % 10) as usize])
             .asc(';')
             .num(10 + FG[(hfb % 10) as usize])
-            .asc('m')
+            .asc('m');
+        self.attr_seq = self.buf[start..].to_vec();
+        self
+    }
+
+    /// Add an attribute string to select exactly the given colours
+    /// and attributes, resetting any others.  This is the structured
+    /// alternative to [`TermOut::hfb`] and [`TermOut::attr`].
+    /// Colours that the terminal doesn't support are downgraded
+    /// using [`TermOut::downgrade`].
+    ///
+    /// [`TermOut::attr`]: struct.TermOut.html#method.attr
+    /// [`TermOut::downgrade`]: struct.TermOut.html#method.downgrade
+    /// [`TermOut::hfb`]: struct.TermOut.html#method.hfb
+    #[inline]
+    pub fn set_attr(&mut self, mut attr: Attr) -> &mut Self {
+        attr.fg = self.downgrade(attr.fg);
+        attr.bg = self.downgrade(attr.bg);
+        self.attr(&attr.sgr());
+        self.attr_default = attr == Attr::default();
+        self
+    }
+
+    /// Add an attribute sequence to set the foreground colour to the
+    /// given index in the 256-colour palette, leaving other
+    /// attributes alone.  If the terminal doesn't support 256
+    /// colours, the nearest basic colour is used instead, via
+    /// [`TermOut::downgrade`].
+    ///
+    /// [`TermOut::downgrade`]: struct.TermOut.html#method.downgrade
+    #[inline]
+    pub fn fg256(&mut self, idx: u8) -> &mut Self {
+        self.colour(Colour::Indexed(idx), 30)
+    }
+
+    /// Add an attribute sequence to set the background colour to the
+    /// given index in the 256-colour palette, leaving other
+    /// attributes alone.  Downgraded in the same way as
+    /// [`TermOut::fg256`].
+    ///
+    /// [`TermOut::fg256`]: struct.TermOut.html#method.fg256
+    #[inline]
+    pub fn bg256(&mut self, idx: u8) -> &mut Self {
+        self.colour(Colour::Indexed(idx), 40)
+    }
+
+    /// Add an attribute sequence to set the foreground colour to the
+    /// given 24-bit RGB colour, leaving other attributes alone.  If
+    /// the terminal doesn't support 24-bit colour, the nearest colour
+    /// in the 256-colour palette is used instead, or failing that
+    /// the nearest basic colour, via [`TermOut::downgrade`].
+    ///
+    /// [`TermOut::downgrade`]: struct.TermOut.html#method.downgrade
+    #[inline]
+    pub fn fg_rgb(&mut self, r: u8, g: u8, b: u8) -> &mut Self {
+        self.colour(Colour::Rgb(r, g, b), 30)
+    }
+
+    /// Add an attribute sequence to set the background colour to the
+    /// given 24-bit RGB colour, leaving other attributes alone.
+    /// Downgraded in the same way as [`TermOut::fg_rgb`].
+    ///
+    /// [`TermOut::fg_rgb`]: struct.TermOut.html#method.fg_rgb
+    #[inline]
+    pub fn bg_rgb(&mut self, r: u8, g: u8, b: u8) -> &mut Self {
+        self.colour(Colour::Rgb(r, g, b), 40)
+    }
+
+    // Add an attribute sequence to change just the foreground (`base`
+    // 30) or background (`base` 40) colour, downgrading it if
+    // necessary
+    fn colour(&mut self, colour: Colour, base: u8) -> &mut Self {
+        let mut codes = String::new();
+        self.downgrade(colour).sgr(&mut codes, base);
+        self.attr_change(&codes)
+    }
+
+    // Add an attribute sequence which changes some attributes and
+    // leaves the rest alone, keeping track of the resulting state
+    fn attr_change(&mut self, codes: &str) -> &mut Self {
+        let start = self.buf.len();
+        self.csi().out(codes).asc('m');
+        let seq = self.buf[start..].to_vec();
+        // Don't let a long series of changes grow this without limit,
+        // instead forget the state until the next full selection
+        if self.attr_seq.len() + seq.len() > 256 {
+            self.attr_seq.clear();
+        } else {
+            self.attr_seq.extend_from_slice(&seq);
+        }
+        self.attr_default = false;
+        self
+    }
+
+    /// Add an attribute sequence to switch on bold, leaving other
+    /// attributes alone.  Note that bold and dim are switched off
+    /// together, by [`TermOut::bold_off`] or [`TermOut::dim_off`].
+    ///
+    /// [`TermOut::bold_off`]: struct.TermOut.html#method.bold_off
+    /// [`TermOut::dim_off`]: struct.TermOut.html#method.dim_off
+    #[inline]
+    pub fn bold(&mut self) -> &mut Self {
+        self.attr_change("1")
+    }
+
+    /// Add an attribute sequence to switch off bold.  This uses SGR
+    /// 22, which switches off dim as well, since there is no code to
+    /// switch off just one of them.  If dim is wanted, switch it on
+    /// again afterwards.
+    #[inline]
+    pub fn bold_off(&mut self) -> &mut Self {
+        self.attr_change("22")
+    }
+
+    /// Add an attribute sequence to switch on dim (faint), leaving
+    /// other attributes alone.  Not all terminals support this.
+    #[inline]
+    pub fn dim(&mut self) -> &mut Self {
+        self.attr_change("2")
+    }
+
+    /// Add an attribute sequence to switch off dim.  This is the
+    /// same as [`TermOut::bold_off`], and switches off bold as well.
+    ///
+    /// [`TermOut::bold_off`]: struct.TermOut.html#method.bold_off
+    #[inline]
+    pub fn dim_off(&mut self) -> &mut Self {
+        self.attr_change("22")
+    }
+
+    /// Add an attribute sequence to switch on italic
+    #[inline]
+    pub fn italic(&mut self) -> &mut Self {
+        self.attr_change("3")
+    }
+
+    /// Add an attribute sequence to switch off italic
+    #[inline]
+    pub fn italic_off(&mut self) -> &mut Self {
+        self.attr_change("23")
+    }
+
+    /// Add an attribute sequence to switch on underline
+    #[inline]
+    pub fn underline(&mut self) -> &mut Self {
+        self.attr_change("4")
+    }
+
+    /// Add an attribute sequence to switch off underline
+    #[inline]
+    pub fn underline_off(&mut self) -> &mut Self {
+        self.attr_change("24")
+    }
+
+    /// Add an attribute sequence to switch on blink
+    #[inline]
+    pub fn blink(&mut self) -> &mut Self {
+        self.attr_change("5")
+    }
+
+    /// Add an attribute sequence to switch off blink
+    #[inline]
+    pub fn blink_off(&mut self) -> &mut Self {
+        self.attr_change("25")
+    }
+
+    /// Add an attribute sequence to switch on reverse video, which
+    /// swaps the foreground and background colours
+    #[inline]
+    pub fn reverse(&mut self) -> &mut Self {
+        self.attr_change("7")
+    }
+
+    /// Add an attribute sequence to switch off reverse video
+    #[inline]
+    pub fn reverse_off(&mut self) -> &mut Self {
+        self.attr_change("27")
+    }
+
+    /// Add an attribute sequence to switch on strikethrough.  Not all
+    /// terminals support this.
+    #[inline]
+    pub fn strike(&mut self) -> &mut Self {
+        self.attr_change("9")
+    }
+
+    /// Add an attribute sequence to switch off strikethrough
+    #[inline]
+    pub fn strike_off(&mut self) -> &mut Self {
+        self.attr_change("29")
+    }
+
+    /// Add an attribute sequence to switch on conceal, which hides
+    /// the text, e.g. for password entry.  Not all terminals support
+    /// this.
+    #[inline]
+    pub fn conceal(&mut self) -> &mut Self {
+        self.attr_change("8")
+    }
+
+    /// Add an attribute sequence to switch off conceal
+    #[inline]
+    pub fn conceal_off(&mut self) -> &mut Self {
+        self.attr_change("28")
+    }
+
+    /// Get the range of colours supported by the terminal, according
+    /// to the [`Features`]
+    ///
+    /// [`Features`]: struct.Features.html
+    pub fn palette(&self) -> Palette {
+        if self.features.truecolor {
+            Palette::Rgb
+        } else if self.features.colour_256 {
+            Palette::Indexed256
+        } else {
+            Palette::Basic
+        }
+    }
+
+    /// Replace the function used to downgrade colours that the
+    /// terminal doesn't support to ones that it does.  It is passed
+    /// the colour and the [`Palette`] that the terminal supports, and
+    /// should return a colour from that palette.  The default is
+    /// [`Colour::downgrade`], which picks the nearest colour in RGB
+    /// space.  An app might instead want a perceptual match, which
+    /// is slower but gives better results, or a fixed mapping to
+    /// suit its own colour scheme.  All colour downgrading by this
+    /// crate goes through this function.
+    ///
+    /// [`Colour::downgrade`]: enum.Colour.html#method.downgrade
+    /// [`Palette`]: enum.Palette.html
+    pub fn set_downgrade(&mut self, f: impl Fn(Colour, Palette) -> Colour + 'static) -> &mut Self {
+        self.downgrade = Box::new(f);
+        self
+    }
+
+    /// Downgrade a colour to one that the terminal supports, using
+    /// the function set with [`TermOut::set_downgrade`]
+    ///
+    /// [`TermOut::set_downgrade`]: struct.TermOut.html#method.set_downgrade
+    pub fn downgrade(&self, colour: Colour) -> Colour {
+        (self.downgrade)(colour, self.palette())
     }
 
     /// Add ANSI sequence to switch to underline cursor
@@ -201,15 +691,37 @@ impl TermOut {
         self.out("\x1B[34l")
     }
 
+    /// Add ANSI sequence to enable or disable cursor blinking (mode
+    /// 12).  This is independent of the cursor shape.  However note
+    /// that the DECSCUSR sequence (`ESC [ N SP q`) that some apps use
+    /// to select the cursor shape also selects whether it blinks, so
+    /// an app should use one approach or the other, not both.  On
+    /// cleanup the blink is set back to the opposite of the first
+    /// change made, on the assumption that this was the terminal's
+    /// original state.
+    #[inline]
+    pub fn cursor_blink(&mut self, enable: bool) -> &mut Self {
+        let (seq, undo) = if enable {
+            ("\x1B[?12h", "\x1B[?12l")
+        } else {
+            ("\x1B[?12l", "\x1B[?12h")
+        };
+        self.set_restore(RestoreKey::CursorBlink, undo.as_bytes());
+        self.set_mode_seq(RestoreKey::CursorBlink, seq.as_bytes());
+        self.out(seq)
+    }
+
     /// Add ANSI sequences to show cursor
     #[inline]
     pub fn show_cursor(&mut self) -> &mut Self {
+        self.cursor_visible = true;
         self.out("\x1B[?25h\x1B[?0c")
     }
 
     /// Add ANSI sequences to hide cursor
     #[inline]
     pub fn hide_cursor(&mut self) -> &mut Self {
+        self.cursor_visible = false;
         self.out("\x1B[?25l\x1B[?1c")
     }
 
@@ -240,18 +752,264 @@ impl TermOut {
         self
     }
 
+    /// Add ANSI sequence to erase N characters from the cursor
+    /// position rightwards (ECH), leaving the cursor where it is
+    #[inline]
+    pub fn erase_chars(&mut self, n: i32) -> &mut Self {
+        self.csi().num(n).asc('X')
+    }
+
+    /// Add the shortest output to clear N cells from the cursor
+    /// position rightwards, leaving the cursor after them as if
+    /// spaces had been written.  `attr` must be the attribute that
+    /// is currently selected.  If its background is the default
+    /// colour and it isn't reversed, then an erase
+    /// ([`TermOut::erase_chars`]) followed by a cursor move is used,
+    /// if that is shorter than writing N spaces.  Otherwise spaces
+    /// are always written, because terminals without
+    /// background-colour-erase would fill erased cells with the
+    /// default background instead of the current one.
+    ///
+    /// [`TermOut::erase_chars`]: struct.TermOut.html#method.erase_chars
+    pub fn clear_run(&mut self, n: i32, attr: Attr) -> &mut Self {
+        if n <= 0 {
+            return self;
+        }
+        let digits = n.to_string().len() as i32;
+        let erasable = attr.bg == Colour::Default && attr.flags & Attr::REVERSE == 0;
+        if erasable && 6 + 2 * digits < n {
+            self.erase_chars(n).csi().num(n).asc('C')
+        } else {
+            self.spaces(n)
+        }
+    }
+
+    /// Add ANSI sequence to save the cursor position (DECSC, `ESC
+    /// 7`), for restoring later with [`TermOut::restore_cursor`],
+    /// for example to briefly draw a status indicator elsewhere.
+    /// This form is used rather than CSI `s`, because CSI `s` means
+    /// something else when left and right margins are enabled, and
+    /// isn't supported by some terminals.  As well as the position,
+    /// DECSC saves the current attributes (colours, bold and so on),
+    /// the character set selection, the origin mode and the
+    /// pending-wrap state, and these are all put back on restore.
+    /// The position saved is absolute, not relative to any scroll
+    /// region.  There is only one save slot, so a second save
+    /// overwrites the first.
+    ///
+    /// [`TermOut::restore_cursor`]: struct.TermOut.html#method.restore_cursor
+    #[inline]
+    pub fn save_cursor(&mut self) -> &mut Self {
+        self.saved_cursor = Some(TermState {
+            modes: Vec::new(),
+            attr: self.attr_seq.clone(),
+            cursor: self.cursor,
+        });
+        self.esc('7')
+    }
+
+    /// Add ANSI sequence to restore the cursor position and
+    /// attributes saved by [`TermOut::save_cursor`] (DECRC, `ESC 8`)
+    ///
+    /// [`TermOut::save_cursor`]: struct.TermOut.html#method.save_cursor
+    #[inline]
+    pub fn restore_cursor(&mut self) -> &mut Self {
+        match self.saved_cursor.clone() {
+            Some(state) => {
+                self.cursor = state.cursor;
+                self.attr_default = state.attr == b"\x1B[0m";
+                self.attr_seq = state.attr;
+            }
+            None => {
+                self.cursor = None;
+                self.attr_seq.clear();
+                self.attr_default = false;
+            }
+        }
+        self.esc('8')
+    }
+
+    /// Add ANSI sequence to insert `n` blank lines at the cursor row
+    /// (IL), pushing the lines below it down within the scroll
+    /// region.  Lines pushed off the bottom of the region are lost.
+    /// The cursor moves to the start of the row.  `n` is clamped to
+    /// at least 1.
+    #[inline]
+    pub fn insert_lines(&mut self, n: i32) -> &mut Self {
+        self.cursor = self.cursor.map(|(y, _)| (y, 0));
+        self.csi().num(n.max(1)).asc('L')
+    }
+
+    /// Add ANSI sequence to delete `n` lines starting at the cursor
+    /// row (DL), pulling the lines below it up within the scroll
+    /// region, and adding blank lines at the bottom of the region.
+    /// The cursor moves to the start of the row.  `n` is clamped to
+    /// at least 1.
+    #[inline]
+    pub fn delete_lines(&mut self, n: i32) -> &mut Self {
+        self.cursor = self.cursor.map(|(y, _)| (y, 0));
+        self.csi().num(n.max(1)).asc('M')
+    }
+
+    /// Add ANSI sequence to insert `n` blank cells at the cursor
+    /// (ICH), pushing the rest of the line right.  Characters pushed
+    /// off the end of the line are lost.  The cursor doesn't move.
+    /// `n` is clamped to at least 1.
+    #[inline]
+    pub fn insert_chars(&mut self, n: i32) -> &mut Self {
+        self.csi().num(n.max(1)).asc('@')
+    }
+
+    /// Add ANSI sequence to delete `n` cells at the cursor (DCH),
+    /// pulling the rest of the line left, and adding blank cells at
+    /// the end of the line.  The cursor doesn't move.  `n` is
+    /// clamped to at least 1.
+    #[inline]
+    pub fn delete_chars(&mut self, n: i32) -> &mut Self {
+        self.csi().num(n.max(1)).asc('P')
+    }
+
+    /// Add ANSI sequence to set a tab stop at the current cursor
+    /// column (HTS).  Note that tab stops are global state of the
+    /// terminal, so if the app changes them, it should reset them in
+    /// the cleanup string (see [`TermOut::save_cleanup`]).
+    ///
+    /// [`TermOut::save_cleanup`]: struct.TermOut.html#method.save_cleanup
+    #[inline]
+    pub fn set_tab(&mut self) -> &mut Self {
+        self.esc('H')
+    }
+
+    /// Add ANSI sequence to clear the tab stop at the current cursor
+    /// column, if any.  See [`TermOut::set_tab`].
+    ///
+    /// [`TermOut::set_tab`]: struct.TermOut.html#method.set_tab
+    #[inline]
+    pub fn clear_tab(&mut self) -> &mut Self {
+        self.out("\x1B[0g")
+    }
+
+    /// Add ANSI sequence to clear all tab stops.  See
+    /// [`TermOut::set_tab`].
+    ///
+    /// [`TermOut::set_tab`]: struct.TermOut.html#method.set_tab
+    #[inline]
+    pub fn clear_all_tabs(&mut self) -> &mut Self {
+        self.out("\x1B[3g")
+    }
+
+    /// Add ANSI sequence to move the cursor forwards `n` tab stops
+    /// (CHT)
+    #[inline]
+    pub fn tab_forward(&mut self, n: i32) -> &mut Self {
+        self.cursor = None;
+        self.csi().num(n).asc('I')
+    }
+
+    /// Add ANSI sequence to move the cursor backwards `n` tab stops
+    /// (CBT)
+    #[inline]
+    pub fn tab_back(&mut self, n: i32) -> &mut Self {
+        self.cursor = None;
+        self.csi().num(n).asc('Z')
+    }
+
     /// Add ANSI sequence to reset attributes to the default
     #[inline]
     pub fn attr_reset(&mut self) -> &mut Self {
+        self.attr_default = true;
+        self.attr_seq = b"\x1B[0m".to_vec();
         self.out("\x1B[0m")
     }
 
-    /// Add ANSI sequence to do a full reset of the terminal
+    /// Set the policy for resetting attributes at the end of each
+    /// frame, i.e. in [`TermOut::end_frame`].  The default is
+    /// [`FrameReset::Never`].
+    ///
+    /// [`FrameReset::Never`]: enum.FrameReset.html#variant.Never
+    /// [`TermOut::end_frame`]: struct.TermOut.html#method.end_frame
+    #[inline]
+    pub fn set_frame_reset(&mut self, policy: FrameReset) {
+        self.frame_reset = policy;
+    }
+
+    /// Enable or disable hiding the cursor whilst each flush is
+    /// written, to reduce tearing on terminals that don't support
+    /// synchronized output.  When enabled, and the cursor is visible
+    /// according to [`TermOut::show_cursor`] and
+    /// [`TermOut::hide_cursor`], the data flushed by each
+    /// [`Terminal::flush`] is wrapped in sequences to hide the cursor
+    /// and show it again.  The default is disabled.
+    ///
+    /// Independently of this setting, the data marked by
+    /// [`TermOut::flush`] is handed to the OS in as few `write` calls
+    /// as possible, but there is no guarantee that a frame goes out
+    /// in one piece.  If the OS accepts only part of the data, or
+    /// earlier output is still queued waiting for the terminal, the
+    /// rest is written later as the terminal catches up.  The
+    /// terminal may also repaint in the middle of processing the
+    /// data.  Only synchronized output avoids tearing completely.
+    ///
+    /// [`Terminal::flush`]: struct.Terminal.html#method.flush
+    /// [`TermOut::flush`]: struct.TermOut.html#method.flush
+    /// [`TermOut::hide_cursor`]: struct.TermOut.html#method.hide_cursor
+    /// [`TermOut::show_cursor`]: struct.TermOut.html#method.show_cursor
+    #[inline]
+    pub fn set_hide_cursor_on_flush(&mut self, enable: bool) -> &mut Self {
+        self.hide_on_flush = enable;
+        self
+    }
+
+    /// Mark the end of a frame, resetting attributes according to the
+    /// policy set with [`TermOut::set_frame_reset`], so that
+    /// whatever is output next starts from the default attributes.
+    /// This is called automatically at the end of page rendering.
+    /// The attribute last selected is tracked through the methods of
+    /// this struct which set attributes, but attribute codes added
+    /// directly with [`TermOut::out`] or [`TermOut::bytes`] can't be
+    /// seen, so use [`FrameReset::Always`] if the app does that.
+    ///
+    /// [`FrameReset::Always`]: enum.FrameReset.html#variant.Always
+    /// [`TermOut::bytes`]: struct.TermOut.html#method.bytes
+    /// [`TermOut::out`]: struct.TermOut.html#method.out
+    /// [`TermOut::set_frame_reset`]: struct.TermOut.html#method.set_frame_reset
+    pub fn end_frame(&mut self) -> &mut Self {
+        match self.frame_reset {
+            FrameReset::Never => self,
+            FrameReset::Always => self.attr_reset(),
+            FrameReset::IfNeeded if self.attr_default => self,
+            FrameReset::IfNeeded => self.attr_reset(),
+        }
+    }
+
+    /// Add ANSI sequence to do a full reset of the terminal.  This
+    /// is a hard reset (RIS), which on many terminals clears the
+    /// screen and may also clear the scrollback, reset the palette
+    /// and cause a visible flash.  See also
+    /// [`TermOut::soft_reset`].
+    ///
+    /// [`TermOut::soft_reset`]: struct.TermOut.html#method.soft_reset
     #[inline]
     pub fn full_reset(&mut self) -> &mut Self {
         self.out("\x1Bc")
     }
 
+    /// Add ANSI sequences to do a soft reset of the terminal
+    /// (DECSTR), followed by showing the cursor and resetting
+    /// attributes.  This resets modes such as the scroll region,
+    /// origin mode, auto-wrap and the character sets, but unlike
+    /// [`TermOut::full_reset`] it leaves the screen contents,
+    /// scrollback and palette alone, so it is less jarring as a
+    /// cleanup sequence on exit.  However it does not switch back
+    /// from the alternate screen or disable mouse or paste modes, so
+    /// those need undoing separately.
+    ///
+    /// [`TermOut::full_reset`]: struct.TermOut.html#method.full_reset
+    #[inline]
+    pub fn soft_reset(&mut self) -> &mut Self {
+        self.out("\x1B[!p").show_cursor().attr_reset()
+    }
+
     /// Switch to UTF-8 mode.  Useful for those terminals that don't
     /// default to UTF-8.
     #[inline]
@@ -259,6 +1017,69 @@ impl TermOut {
         self.out("\x1B%G")
     }
 
+    /// Switch to the alternate screen (mode 1049), saving the cursor
+    /// position and leaving the normal screen and its scrollback
+    /// untouched underneath.  The alternate screen is cleared on
+    /// entry.  Like [`TermOut::cursor_blink`], this is undone
+    /// automatically on cleanup, i.e. on pause, exit or panic,
+    /// before the cleanup string is output.  So the cleanup string
+    /// saved with [`TermOut::save_cleanup`] runs on the normal
+    /// screen, and only needs to tidy that up.
+    ///
+    /// [`TermOut::cursor_blink`]: struct.TermOut.html#method.cursor_blink
+    /// [`TermOut::save_cleanup`]: struct.TermOut.html#method.save_cleanup
+    #[inline]
+    pub fn enter_alt_screen(&mut self) -> &mut Self {
+        self.set_restore(RestoreKey::AltScreen, b"\x1B[?1049l");
+        self.set_mode_seq(RestoreKey::AltScreen, b"\x1B[?1049h");
+        self.cursor = None;
+        self.out("\x1B[?1049h")
+    }
+
+    /// Switch back to the normal screen from the alternate screen,
+    /// restoring the cursor position saved on entry
+    #[inline]
+    pub fn leave_alt_screen(&mut self) -> &mut Self {
+        self.take_restore(RestoreKey::AltScreen);
+        self.cursor = None;
+        self.out("\x1B[?1049l")
+    }
+
+    /// Set the scroll region (DECSTBM) to the rows from `top` to
+    /// `bottom` inclusive, 0-based.  The rows are clamped to the
+    /// screen.  If that leaves a region of less than two rows, then
+    /// nothing is done and any existing scroll region stays.  Output
+    /// scrolling within the region leaves the rows outside it alone,
+    /// so for example [`TermOut::scroll_up`] with `bottom` set to the
+    /// last row scrolls just the region.  Note that the terminal
+    /// moves the cursor to the top-left corner of the screen.  The
+    /// scroll region is reset automatically on cleanup.
+    ///
+    /// [`TermOut::scroll_up`]: struct.TermOut.html#method.scroll_up
+    pub fn scroll_region(&mut self, top: i32, bottom: i32) -> &mut Self {
+        let sy = self.size.0;
+        let top = top.max(0);
+        let bottom = bottom.min(sy - 1);
+        if bottom <= top {
+            return self;
+        }
+        let start = self.buf.len();
+        self.csi().num(top + 1).asc(';').num(bottom + 1).asc('r');
+        let seq = self.buf[start..].to_vec();
+        self.set_restore(RestoreKey::ScrollRegion, b"\x1B[r");
+        self.set_mode_seq(RestoreKey::ScrollRegion, &seq);
+        self.cursor = Some((0, 0));
+        self
+    }
+
+    /// Reset the scroll region to the whole screen.  The terminal
+    /// moves the cursor to the top-left corner.
+    pub fn scroll_region_reset(&mut self) -> &mut Self {
+        self.take_restore(RestoreKey::ScrollRegion);
+        self.cursor = Some((0, 0));
+        self.out("\x1B[r")
+    }
+
     /// Move cursor to bottom line and do a linefeed.  This results in
     /// the screen scrolling one line, and the cursor being left at
     /// the bottom-left corner.
@@ -267,6 +1088,278 @@ impl TermOut {
         self.at(-1, 0).asc('\n')
     }
 
+    /// Add a complete styled line of text, for example for log
+    /// output appended to the scrollback.  Each segment is some text
+    /// and the HFB colour to display it in (see [`TermOut::hfb`]).
+    /// Attributes are only changed where the colour actually
+    /// changes, so adjacent segments of the same colour don't
+    /// generate extra sequences.  Any attribute codepoints embedded
+    /// in the text (U+E000 to U+F8FF, as used by the page code)
+    /// switch colour from that point to the end of the segment.
+    /// Attributes are reset at the end of the line, followed by
+    /// CR-LF, and then everything is marked for flushing.
+    ///
+    /// [`TermOut::hfb`]: struct.TermOut.html#method.hfb
+    pub fn log_line(&mut self, segments: &[(&str, u8)]) -> &mut Self {
+        let mut curr = None;
+        for (text, hfb) in segments {
+            let mut hfb = *hfb;
+            for ch in text.chars() {
+                if ('\u{E000}'..='\u{F8FF}').contains(&ch) {
+                    hfb = (ch as u32 - 0xE000).min(199) as u8;
+                    continue;
+                }
+                if curr != Some(hfb) {
+                    curr = Some(hfb);
+                    self.hfb(hfb);
+                }
+                self.out(ch.encode_utf8(&mut [0; 4]));
+            }
+        }
+        self.cursor = None;
+        self.attr_reset().out("\r\n");
+        self.flush();
+        self
+    }
+
+    /// Add an inline image using the iTerm2 image protocol.  `data`
+    /// is the contents of an image file in any format that the
+    /// terminal understands (e.g. PNG), which is passed through
+    /// base64-encoded.  The image is displayed at the current cursor
+    /// position, and the cursor is left after the image.  Check
+    /// [`Features::iterm_image`] to see whether the terminal is
+    /// likely to support this protocol.
+    ///
+    /// [`Features::iterm_image`]: struct.Features.html#structfield.iterm_image
+    pub fn iterm_image(&mut self, data: &[u8], opts: ImageOpts) -> &mut Self {
+        let start = self.buf.len();
+        self.osc().out("1337;File=inline=1;size=");
+        self.count(data.len());
+        if let Some(width) = opts.width {
+            self.out(";width=").num(width);
+        }
+        if let Some(height) = opts.height {
+            self.out(";height=").num(height);
+        }
+        if !opts.preserve_aspect {
+            self.out(";preserveAspectRatio=0");
+        }
+        self.asc(':').base64(data).st().passthrough_from(start)
+    }
+
+    /// Add a desktop notification using OSC 9.  This is supported
+    /// by iTerm2, kitty, WezTerm and others.  Control characters in
+    /// `message` are dropped.  See also [`TermOut::notify_titled`].
+    ///
+    /// [`TermOut::notify_titled`]: struct.TermOut.html#method.notify_titled
+    pub fn notify(&mut self, message: &str) -> &mut Self {
+        let start = self.buf.len();
+        self.osc()
+            .out("9;")
+            .osc_text(message, false)
+            .st()
+            .passthrough_from(start)
+    }
+
+    /// Add a desktop notification with a title and body, using the
+    /// OSC 777 form if [`Features::notify_777`] indicates that it is
+    /// supported, or else falling back to an OSC 9 notification of
+    /// the form "title: body".  Control characters are dropped, and
+    /// any `;` in the title is replaced with `,` as it would
+    /// otherwise be taken as the end of the title.
+    ///
+    /// [`Features::notify_777`]: struct.Features.html#structfield.notify_777
+    pub fn notify_titled(&mut self, title: &str, body: &str) -> &mut Self {
+        let start = self.buf.len();
+        if self.features.notify_777 {
+            self.osc()
+                .out("777;notify;")
+                .osc_text(title, true)
+                .asc(';')
+                .osc_text(body, false)
+                .st();
+        } else {
+            self.osc()
+                .out("9;")
+                .osc_text(title, false)
+                .out(": ")
+                .osc_text(body, false)
+                .st();
+        }
+        self.passthrough_from(start)
+    }
+
+    /// Start a hyperlink to the given URI using OSC 8.  Text output
+    /// after this becomes a clickable link, up to the following
+    /// [`TermOut::hyperlink_end`].  Any bytes in the URI outside of
+    /// printable ASCII are percent-encoded, so they can't terminate
+    /// the sequence early.  Terminals that don't support hyperlinks
+    /// should ignore the sequence and show the text as normal.
+    ///
+    /// [`TermOut::hyperlink_end`]: struct.TermOut.html#method.hyperlink_end
+    pub fn hyperlink(&mut self, uri: &str) -> &mut Self {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+        let start = self.buf.len();
+        self.osc().out("8;;");
+        for &b in uri.as_bytes() {
+            if (0x21..=0x7E).contains(&b) {
+                self.byt(b);
+            } else {
+                self.asc('%')
+                    .byt(HEX[usize::from(b >> 4)])
+                    .byt(HEX[usize::from(b & 15)]);
+            }
+        }
+        self.st().passthrough_from(start)
+    }
+
+    /// End a hyperlink started with [`TermOut::hyperlink`]
+    ///
+    /// [`TermOut::hyperlink`]: struct.TermOut.html#method.hyperlink
+    pub fn hyperlink_end(&mut self) -> &mut Self {
+        let start = self.buf.len();
+        self.osc().out("8;;").st().passthrough_from(start)
+    }
+
+    /// Set the window or tab title, and also the icon title, using
+    /// OSC 0.  Control characters in `title` are dropped.  This is
+    /// terminated with BEL rather than ST, since some older
+    /// terminals only recognise that form for titles.
+    pub fn set_title(&mut self, title: &str) -> &mut Self {
+        let start = self.buf.len();
+        self.osc()
+            .out("0;")
+            .osc_text(title, false)
+            .byt(7)
+            .passthrough_from(start)
+    }
+
+    /// Set just the icon title, using OSC 1, which some terminals
+    /// show for a minimised window or in a taskbar.  Control
+    /// characters are dropped as for [`TermOut::set_title`].
+    ///
+    /// [`TermOut::set_title`]: struct.TermOut.html#method.set_title
+    pub fn set_icon_title(&mut self, title: &str) -> &mut Self {
+        let start = self.buf.len();
+        self.osc()
+            .out("1;")
+            .osc_text(title, false)
+            .byt(7)
+            .passthrough_from(start)
+    }
+
+    /// Copy text to the system clipboard using OSC 52.  Text longer
+    /// than [`TermOut::CLIPBOARD_MAX`] bytes is truncated to that
+    /// length, backing off to a UTF-8 character boundary, since
+    /// terminals typically ignore the whole sequence if it exceeds
+    /// their limit.  Not all terminals support this, and some
+    /// require it to be enabled in their configuration, e.g. tmux's
+    /// `set-clipboard` option.
+    ///
+    /// [`TermOut::CLIPBOARD_MAX`]: struct.TermOut.html#associatedconstant.CLIPBOARD_MAX
+    pub fn clipboard_set(&mut self, data: &str) -> &mut Self {
+        let mut len = data.len().min(Self::CLIPBOARD_MAX);
+        while !data.is_char_boundary(len) {
+            len -= 1;
+        }
+        let start = self.buf.len();
+        self.osc()
+            .out("52;c;")
+            .base64(&data.as_bytes()[..len])
+            .st()
+            .passthrough_from(start)
+    }
+
+    /// Add a sequence wrapped in tmux's DCS passthrough, so that
+    /// tmux passes it on to the outer terminal instead of
+    /// interpreting it itself.  Any ESC bytes within `data` are
+    /// doubled as tmux requires.  Note that tmux 3.3 onwards only
+    /// passes these through if the `allow-passthrough` option is
+    /// enabled.  The OSC helpers [`TermOut::notify`],
+    /// [`TermOut::notify_titled`], [`TermOut::hyperlink`],
+    /// [`TermOut::hyperlink_end`], [`TermOut::set_title`],
+    /// [`TermOut::set_icon_title`] and [`TermOut::clipboard_set`]
+    /// already do this automatically when [`Features::tmux`] is set.
+    ///
+    /// [`Features::tmux`]: struct.Features.html#structfield.tmux
+    /// [`TermOut::clipboard_set`]: struct.TermOut.html#method.clipboard_set
+    /// [`TermOut::hyperlink`]: struct.TermOut.html#method.hyperlink
+    /// [`TermOut::hyperlink_end`]: struct.TermOut.html#method.hyperlink_end
+    /// [`TermOut::notify`]: struct.TermOut.html#method.notify
+    /// [`TermOut::notify_titled`]: struct.TermOut.html#method.notify_titled
+    /// [`TermOut::set_icon_title`]: struct.TermOut.html#method.set_icon_title
+    /// [`TermOut::set_title`]: struct.TermOut.html#method.set_title
+    pub fn tmux_passthrough(&mut self, data: &[u8]) -> &mut Self {
+        self.esc('P').out("tmux;");
+        for &b in data {
+            if b == 27 {
+                self.byt(27);
+            }
+            self.byt(b);
+        }
+        self.st()
+    }
+
+    // If running inside tmux, wrap the sequence added since buffer
+    // position `start` in a passthrough
+    fn passthrough_from(&mut self, start: usize) -> &mut Self {
+        if self.features.tmux {
+            let seq = self.buf.split_off(start);
+            self.tmux_passthrough(&seq);
+        }
+        self
+    }
+
+    // Add text for inclusion in an OSC sequence, dropping any control
+    // characters that might terminate the sequence early.  If
+    // `field` is set, then `;` is replaced by `,`.
+    fn osc_text(&mut self, text: &str, field: bool) -> &mut Self {
+        for c in text.chars() {
+            match c {
+                '\0'..='\x1F' | '\x7F'..='\u{9F}' => (),
+                ';' if field => {
+                    self.asc(',');
+                }
+                _ => {
+                    self.out(c.encode_utf8(&mut [0; 4]));
+                }
+            }
+        }
+        self
+    }
+
+    // Add an unlimited decimal count, e.g. a byte length
+    fn count(&mut self, v: usize) -> &mut Self {
+        if v >= 10 {
+            self.count(v / 10);
+        }
+        self.byt((v % 10) as u8 + b'0')
+    }
+
+    // Add data encoded as standard padded base64
+    fn base64(&mut self, data: &[u8]) -> &mut Self {
+        const ENC: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        for chunk in data.chunks(3) {
+            let b0 = u32::from(chunk[0]);
+            let b1 = u32::from(chunk.get(1).copied().unwrap_or(0));
+            let b2 = u32::from(chunk.get(2).copied().unwrap_or(0));
+            let v = (b0 << 16) | (b1 << 8) | b2;
+            self.byt(ENC[(v >> 18) as usize & 63]);
+            self.byt(ENC[(v >> 12) as usize & 63]);
+            if chunk.len() > 1 {
+                self.byt(ENC[(v >> 6) as usize & 63]);
+            } else {
+                self.asc('=');
+            }
+            if chunk.len() > 2 {
+                self.byt(ENC[v as usize & 63]);
+            } else {
+                self.asc('=');
+            }
+        }
+        self
+    }
+
     /// Save the current contents of the output buffer as the cleanup
     /// string, then clear the output buffer.  The cleanup string will
     /// be output to the terminal on error or when the terminal is
@@ -276,29 +1369,117 @@ impl TermOut {
     /// better to do something less drastic, for example reset just
     /// the state that was changed, put the cursor at the bottom of
     /// the screen and do a LF.  This will take effect on the next
-    /// flush.
+    /// flush.  Modes changed using methods such as
+    /// [`TermOut::cursor_blink`] are restored automatically before
+    /// the cleanup string is output, so don't need including here.
+    ///
+    /// [`TermOut::cursor_blink`]: struct.TermOut.html#method.cursor_blink
     pub fn save_cleanup(&mut self) {
+        self.drained += self.buf.len() as u64;
         self.new_cleanup = Some(self.buf.drain(..).collect());
+        self.flush_to = 0;
+    }
+
+    // Record the sequence to restore a mode on cleanup, unless one
+    // is already recorded for that mode
+    pub(crate) fn set_restore(&mut self, key: RestoreKey, seq: &[u8]) {
+        if !self.restore.iter().any(|(k, _)| *k == key) {
+            self.restore.push((key, seq.to_vec()));
+            self.restore_changed = true;
+        }
+    }
+
+    // Remove and return the sequence to restore a mode, if any
+    pub(crate) fn take_restore(&mut self, key: RestoreKey) -> Option<Vec<u8>> {
+        self.modes.retain(|(k, _)| *k != key);
+        let i = self.restore.iter().position(|(k, _)| *k == key)?;
+        self.restore_changed = true;
+        Some(self.restore.remove(i).1)
+    }
+
+    // Record the sequence that set up a mode in its current state,
+    // replacing any earlier one for the same mode
+    pub(crate) fn set_mode_seq(&mut self, key: RestoreKey, seq: &[u8]) {
+        self.modes.retain(|(k, _)| *k != key);
+        self.modes.push((key, seq.to_vec()));
+    }
+
+    // Take a snapshot of the known terminal state
+    pub(crate) fn save_state(&self) -> TermState {
+        TermState {
+            modes: self
+                .modes
+                .iter()
+                .flat_map(|(_, seq)| seq.iter().copied())
+                .collect(),
+            attr: self.attr_seq.clone(),
+            cursor: self.cursor,
+        }
+    }
+
+    // Output the sequences to get back to a saved state
+    pub(crate) fn restore_state(&mut self, state: &TermState) {
+        self.bytes(&state.modes);
+        if !state.attr.is_empty() {
+            self.bytes(&state.attr);
+        }
+        self.attr_seq = state.attr.clone();
+        self.attr_default = state.attr == b"\x1B[0m";
+        match state.cursor {
+            Some((y, x)) => {
+                self.at(y, x);
+            }
+            None => self.cursor = None,
+        }
+    }
+
+    // Get all the restore sequences, most recent change first
+    pub(crate) fn restore_seq(&self) -> Vec<u8> {
+        self.restore
+            .iter()
+            .rev()
+            .flat_map(|(_, seq)| seq.iter().copied())
+            .collect()
     }
 
     pub(crate) fn data_to_flush(&self) -> &[u8] {
         &self.buf[..self.flush_to]
     }
 
+    // Get the data to write for this flush, including the sequences
+    // to hide the cursor whilst it is written if enabled
+    pub(crate) fn frame_to_flush(&self) -> Cow<'_, [u8]> {
+        let data = self.data_to_flush();
+        if self.hide_on_flush && self.cursor_visible && !data.is_empty() {
+            let mut frame = Vec::with_capacity(data.len() + 12);
+            frame.extend_from_slice(b"\x1B[?25l");
+            frame.extend_from_slice(data);
+            frame.extend_from_slice(b"\x1B[?25h");
+            Cow::Owned(frame)
+        } else {
+            Cow::Borrowed(data)
+        }
+    }
+
     pub(crate) fn drain_flush(&mut self) {
         self.buf.drain(..self.flush_to);
+        self.drained += self.flush_to as u64;
         self.flush_to = 0;
     }
 
     // Discard all buffered contents
     pub(crate) fn discard(&mut self) {
+        self.drained += self.buf.len() as u64;
         self.buf.drain(..);
         self.flush_to = 0;
+        self.cursor = None;
     }
 
     // Set size
     pub(crate) fn set_size(&mut self, sy: i32, sx: i32) {
         self.size = (sy, sx);
+        self.generation += 1;
+        self.cursor = None;
     }
 }
 
@@ -317,8 +1498,513 @@ impl Write for TermOut {
     }
 }
 
+/// Snapshot of the known terminal state, from
+/// [`Terminal::save_state`]
+///
+/// This holds the modes set through this crate (e.g. with
+/// [`Terminal::set_mode`]), the attributes last selected and the
+/// cursor position, as far as they are known.
+///
+/// [`Terminal::save_state`]: struct.Terminal.html#method.save_state
+/// [`Terminal::set_mode`]: struct.Terminal.html#method.set_mode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TermState {
+    modes: Vec<u8>,
+    attr: Vec<u8>,
+    cursor: Option<(i32, i32)>,
+}
+
+/// Policy for resetting attributes at the end of a frame, for
+/// [`TermOut::set_frame_reset`]
+///
+/// [`TermOut::set_frame_reset`]: struct.TermOut.html#method.set_frame_reset
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameReset {
+    /// Leave the attributes as they are
+    Never,
+
+    /// Reset the attributes at the end of every frame
+    Always,
+
+    /// Reset the attributes only if the last attribute selected
+    /// wasn't already the default
+    IfNeeded,
+}
+
+/// A position in the [`TermOut`] buffer, from [`TermOut::mark`]
+///
+/// [`TermOut::mark`]: struct.TermOut.html#method.mark
+/// [`TermOut`]: struct.TermOut.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mark {
+    pos: u64,
+    // State that the data after the mark may have changed
+    cursor: Option<(i32, i32)>,
+    cursor_visible: bool,
+    saved_cursor: Option<TermState>,
+    attr_seq: Vec<u8>,
+    attr_default: bool,
+    modes: Vec<(RestoreKey, Vec<u8>)>,
+    restore: Vec<(RestoreKey, Vec<u8>)>,
+}
+
+/// Source of the terminal size
+///
+/// The size is obtained from the OS if possible.  If that fails, or
+/// reports a zero size (e.g. on some pseudo-terminals used in CI),
+/// the `LINES` and `COLUMNS` environment variables are used if both
+/// are set to positive integers.  These are clamped to the range
+/// 2-9999.  Otherwise the terminal is queried, and
+/// meanwhile a default size of 80x24 is assumed.  When the reply to
+/// the query arrives, the size is updated and a new `resize` message
+/// is sent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SizeSource {
+    /// From the OS, e.g. via the `TIOCGWINSZ` ioctl
+    Ioctl,
+
+    /// From the `LINES` and `COLUMNS` environment variables
+    Env,
+
+    /// From the terminal's reply to a `ESC [ 18 t` query
+    Query,
+
+    /// Default size of 80x24, as no other source was available
+    Default,
+}
+
 /// Features supported by the terminal
+///
+/// These are guessed from the environment on startup, and may be
+/// updated later by [`Terminal::query_features`] or
+/// [`Terminal::query_tcap`].
+///
+/// More fields may be added in future versions, so to create a value
+/// outside this crate, start from `Features::default()` or
+/// [`Features::from_vars`] and set the fields required.
+///
+/// [`Features::from_vars`]: struct.Features.html#method.from_vars
+/// [`Terminal::query_features`]: struct.Terminal.html#method.query_features
+/// [`Terminal::query_tcap`]: struct.Terminal.html#method.query_tcap
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct Features {
     /// Supports 256 colours?
     pub colour_256: bool,
+
+    /// Supports 24-bit colour?  If so, [`TermOut::palette`] gives
+    /// [`Palette::Rgb`] and 24-bit colours are sent without
+    /// downgrading.
+    ///
+    /// [`Palette::Rgb`]: enum.Palette.html#variant.Rgb
+    /// [`TermOut::palette`]: struct.TermOut.html#method.palette
+    pub truecolor: bool,
+
+    /// Supports the iTerm2 inline image protocol?  See
+    /// [`TermOut::iterm_image`].
+    ///
+    /// [`TermOut::iterm_image`]: struct.TermOut.html#method.iterm_image
+    pub iterm_image: bool,
+
+    /// Supports OSC 777 desktop notifications with a title?  See
+    /// [`TermOut::notify_titled`].
+    ///
+    /// [`TermOut::notify_titled`]: struct.TermOut.html#method.notify_titled
+    pub notify_777: bool,
+
+    /// Running inside tmux?  If so, sequences intended for the outer
+    /// terminal are wrapped using [`TermOut::tmux_passthrough`].
+    ///
+    /// [`TermOut::tmux_passthrough`]: struct.TermOut.html#method.tmux_passthrough
+    pub tmux: bool,
+
+    /// Supports sixel graphics?  Only detected by
+    /// [`Terminal::query_features`].
+    ///
+    /// [`Terminal::query_features`]: struct.Terminal.html#method.query_features
+    pub sixel: bool,
+
+    /// Supports synchronized output, mode 2026?  Only detected by
+    /// [`Terminal::query_features`].
+    ///
+    /// [`Terminal::query_features`]: struct.Terminal.html#method.query_features
+    pub sync_output: bool,
+
+    /// Supports bracketed paste, mode 2004?  Only detected by
+    /// [`Terminal::query_features`].
+    ///
+    /// [`Terminal::query_features`]: struct.Terminal.html#method.query_features
+    pub bracketed_paste: bool,
+}
+
+impl Features {
+    // Guess the features of the terminal from the environment
+    pub(crate) fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Guess the features of the terminal from environment variables
+    /// looked up through `var`, which returns `None` for a variable
+    /// that isn't set.  This is what [`Terminal::init`] does with
+    /// the process environment, but might be used with the
+    /// environment of a remote client, or with a fixed map to check
+    /// the detection.  `TERM`, `COLORTERM`, `TERM_PROGRAM`,
+    /// `LC_TERMINAL`, `VTE_VERSION` and `TMUX` are checked, along
+    /// with the `colors` capability of the terminfo entry for `TERM`
+    /// if one can be found, which is located using `TERMINFO`,
+    /// `TERMINFO_DIRS` and `HOME`.
+    ///
+    /// [`Terminal::init`]: struct.Terminal.html#method.init
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let term_program = var("TERM_PROGRAM").unwrap_or_default();
+        let lc_terminal = var("LC_TERMINAL").unwrap_or_default();
+        let term = var("TERM").unwrap_or_default();
+        let colorterm = var("COLORTERM").unwrap_or_default();
+        let iterm_image =
+            term_program == "iTerm.app" || term_program == "WezTerm" || lc_terminal == "iTerm2";
+        let notify_777 = term_program == "WezTerm"
+            || term_program == "ghostty"
+            || term.starts_with("foot")
+            || var("VTE_VERSION").is_some();
+        // A `colors` of 2^24 is what the "direct" terminfo entries use
+        let (colour_256, truecolor) = match crate::terminfo::colours(&term, &var) {
+            Some(colours) => (colours >= 256, colours >= 0x100_0000),
+            None => (
+                term.contains("256color"),
+                term.ends_with("-direct") || term.contains("truecolor"),
+            ),
+        };
+        let truecolor = truecolor || colorterm == "truecolor" || colorterm == "24bit";
+        let colour_256 = colour_256 || truecolor;
+        let tmux = var("TMUX").is_some() || term.starts_with("tmux");
+        Self {
+            colour_256,
+            truecolor,
+            iterm_image,
+            notify_777,
+            tmux,
+            sixel: false,
+            sync_output: false,
+            bracketed_paste: false,
+        }
+    }
+}
+
+/// Options for displaying an image with [`TermOut::iterm_image`]
+///
+/// [`TermOut::iterm_image`]: struct.TermOut.html#method.iterm_image
+#[derive(Clone, Debug)]
+pub struct ImageOpts {
+    /// Width in cells, or `None` to use the image's own size
+    pub width: Option<i32>,
+
+    /// Height in cells, or `None` to use the image's own size
+    pub height: Option<i32>,
+
+    /// Preserve the aspect ratio when scaling?  Default is `true`.
+    pub preserve_aspect: bool,
+}
+
+impl Default for ImageOpts {
+    fn default() -> Self {
+        Self {
+            width: None,
+            height: None,
+            preserve_aspect: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn termout() -> TermOut {
+        let mut t = TermOut::new(Features::default());
+        t.set_size(24, 80);
+        t
+    }
+
+    // Take the flushed output, with ESC shown as `^` for readability
+    fn output(t: &mut TermOut) -> String {
+        t.flush();
+        let v = String::from_utf8_lossy(t.data_to_flush()).replace('\x1b', "^");
+        t.drain_flush();
+        v
+    }
+
+    #[test]
+    fn log_line() {
+        let mut t = termout();
+        t.log_line(&[("a", 12), ("", 34), ("b", 12), ("c\u{E022}d", 13)]);
+        assert_eq!(output(&mut t), "^[0;34;41mab^[0;34;45mc^[0;35;42md^[0m\r\n");
+    }
+
+    #[test]
+    fn soft_reset() {
+        let mut t = termout();
+        t.soft_reset();
+        assert_eq!(output(&mut t), "^[!p^[?25h^[?0c^[0m");
+    }
+
+    #[test]
+    fn cursor_blink() {
+        let mut t = termout();
+        t.cursor_blink(false).cursor_blink(true);
+        assert_eq!(output(&mut t), "^[?12l^[?12h");
+        // The original state is assumed to be the opposite of the
+        // first change
+        assert!(t.restore_changed);
+        assert_eq!(t.restore_seq(), b"\x1B[?12h");
+    }
+
+    #[test]
+    fn tmux_passthrough() {
+        let mut t = termout();
+        t.tmux_passthrough(b"\x1B]52;c;YWJj\x1B\\");
+        assert_eq!(output(&mut t), "^Ptmux;^^]52;c;YWJj^^\\^\\");
+
+        // OSC helpers wrap themselves when inside tmux
+        let mut t = TermOut::new(Features {
+            tmux: true,
+            ..Features::default()
+        });
+        t.notify("hi");
+        assert_eq!(output(&mut t), "^Ptmux;^^]9;hi^^\\^\\");
+        t.set_title("a").set_icon_title("b");
+        assert_eq!(output(&mut t), "^Ptmux;^^]0;a\x07^\\^Ptmux;^^]1;b\x07^\\");
+        t.hyperlink("x:y").out("z").hyperlink_end();
+        assert_eq!(
+            output(&mut t),
+            "^Ptmux;^^]8;;x:y^^\\^\\z^Ptmux;^^]8;;^^\\^\\"
+        );
+    }
+
+    #[test]
+    fn clipboard_set() {
+        let mut t = termout();
+        t.clipboard_set("abc");
+        assert_eq!(output(&mut t), "^]52;c;YWJj^\\");
+
+        let mut t = TermOut::new(Features {
+            tmux: true,
+            ..Features::default()
+        });
+        t.clipboard_set("abc");
+        assert_eq!(output(&mut t), "^Ptmux;^^]52;c;YWJj^^\\^\\");
+    }
+
+    #[test]
+    fn out_fit() {
+        let mut t = termout();
+        assert_eq!(t.out_fit("hello", 5, true), 5);
+        assert_eq!(output(&mut t), "hello");
+        assert_eq!(t.out_fit("hello", 4, true), 4);
+        assert_eq!(output(&mut t), "hel…");
+        assert_eq!(t.out_fit("日本語", 4, false), 4);
+        assert_eq!(output(&mut t), "日本");
+        assert_eq!(t.out_fit("日本語", 4, true), 3);
+        assert_eq!(output(&mut t), "日…");
+        assert_eq!(t.out_fit("e\u{301}x", 1, false), 1);
+        assert_eq!(output(&mut t), "e\u{301}");
+        assert_eq!(t.out_fit("abc", 0, true), 0);
+        assert_eq!(output(&mut t), "");
+    }
+
+    #[test]
+    fn clear_run() {
+        // The default background can be erased, once that is shorter
+        let mut t = termout();
+        t.clear_run(8, Attr::from_hfb(88))
+            .clear_run(20, Attr::from_hfb(128));
+        assert_eq!(output(&mut t), "        ^[20X^[20C");
+
+        // Any other background, or reverse video, needs spaces
+        t.clear_run(20, Attr::from_hfb(81));
+        t.clear_run(20, Attr::default().with(Attr::REVERSE));
+        assert_eq!(output(&mut t), " ".repeat(40));
+        t.clear_run(0, Attr::default());
+        assert_eq!(output(&mut t), "");
+    }
+
+    #[test]
+    fn mark_rewind() {
+        let mut t = termout();
+        t.at(1, 1).hfb(88);
+        t.flush();
+        let mark = t.mark();
+        let state = t.save_state();
+        t.at(5, 5).hfb(12).enter_alt_screen().cursor_blink(false);
+        assert!(t.restore_changed);
+        t.restore_changed = false;
+        assert!(t.rewind(mark.clone()));
+        assert_eq!(output(&mut t), "^[2;2H^[0;39;49m");
+        assert_eq!(t.save_state(), state);
+        assert!(t.restore_seq().is_empty());
+        assert!(t.restore_changed);
+
+        // Attributes known to be the default still skip the reset
+        t.set_frame_reset(FrameReset::IfNeeded);
+        let mark = t.mark();
+        t.hfb(12);
+        t.rewind(mark);
+        t.end_frame();
+        assert_eq!(output(&mut t), "");
+
+        // Can't rewind into flushed data
+        let mark = t.mark();
+        t.out("x").flush();
+        assert!(!t.rewind(mark));
+        assert_eq!(output(&mut t), "x");
+    }
+
+    #[test]
+    fn mark_rewind_after_save_cleanup() {
+        let mut t = termout();
+        t.out("a");
+        let before = t.mark();
+        t.out("\x1Bc").save_cleanup();
+
+        // Data taken as the cleanup string can't be rewound into
+        t.out("xyz");
+        assert!(!t.rewind(before));
+        let mark = t.mark();
+        t.out("b");
+        assert!(t.rewind(mark));
+        assert_eq!(output(&mut t), "xyz");
+    }
+
+    #[test]
+    fn save_state() {
+        let mut t = termout();
+        t.set_restore(RestoreKey::Mode(1), b"B");
+        t.set_mode_seq(RestoreKey::Mode(1), b"A");
+        t.cursor_blink(true).hfb(12).at(3, 4);
+        let st = t.save_state();
+        output(&mut t);
+        t.out("clobber").attr_reset();
+        t.restore_state(&st);
+        assert_eq!(output(&mut t), "clobber^[0mA^[?12h^[0;34;41m^[4;5H");
+    }
+
+    #[test]
+    fn hide_cursor_on_flush() {
+        let mut t = termout();
+        t.set_hide_cursor_on_flush(true).out("x").flush();
+        assert_eq!(&t.frame_to_flush()[..], b"\x1B[?25lx\x1B[?25h");
+        t.drain_flush();
+        t.flush();
+        assert_eq!(&t.frame_to_flush()[..], b"");
+
+        // Nothing to do if the cursor is already hidden
+        t.hide_cursor().out("y").flush();
+        assert_eq!(output(&mut t), "^[?25l^[?1cy");
+        t.out("z").flush();
+        assert_eq!(&t.frame_to_flush()[..], b"z");
+    }
+
+    #[test]
+    fn downgrade() {
+        let mut t = termout();
+        assert_eq!(
+            Colour::Rgb(250, 10, 10).downgrade(Palette::Basic),
+            Colour::Ansi(9)
+        );
+        assert_eq!(
+            Colour::Rgb(0, 0, 0).downgrade(Palette::Indexed256),
+            Colour::Indexed(16)
+        );
+        assert_eq!(
+            Colour::Indexed(196).downgrade(Palette::Basic),
+            Colour::Ansi(9)
+        );
+        assert_eq!(
+            Colour::Indexed(240).downgrade(Palette::Basic),
+            Colour::Ansi(8)
+        );
+        t.set_attr(Attr::new(Colour::Rgb(250, 10, 10), Colour::Default));
+        assert_eq!(output(&mut t), "^[0;91;49m");
+        t.set_downgrade(|c, _| {
+            if c == Colour::Rgb(250, 10, 10) {
+                Colour::Ansi(4)
+            } else {
+                c
+            }
+        });
+        t.set_attr(Attr::new(Colour::Rgb(250, 10, 10), Colour::Default));
+        assert_eq!(output(&mut t), "^[0;34;49m");
+    }
+
+    #[test]
+    fn scroll_region() {
+        let mut t = termout();
+        t.scroll_region(-5, 100);
+        assert_eq!(output(&mut t), "^[1;24r");
+        t.scroll_region(2, 3);
+        assert_eq!(output(&mut t), "^[3;4r");
+        // Regions of less than two rows are ignored
+        t.scroll_region(5, 5)
+            .scroll_region(7, 3)
+            .scroll_region(23, 40);
+        assert_eq!(output(&mut t), "");
+        t.set_size(1, 80);
+        t.scroll_region(0, 0).scroll_region(0, 10);
+        assert_eq!(output(&mut t), "");
+        assert_eq!(t.restore_seq(), b"\x1B[r");
+        t.scroll_region_reset();
+        assert_eq!(output(&mut t), "^[r");
+    }
+
+    #[test]
+    fn at_zero_size() {
+        let mut t = TermOut::new(Features::default());
+        t.at(0, 0).at(-1, 5);
+        assert_eq!(output(&mut t), "^[1;1H^[1;1H");
+    }
+
+    #[test]
+    fn features_from_vars() {
+        let dir = std::env::temp_dir().join(format!("stakker_tui_features_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("t")).unwrap();
+        let entry = crate::terminfo::tests::entry;
+        std::fs::write(dir.join("t").join("test-256color"), entry(false, 8)).unwrap();
+        std::fs::write(dir.join("t").join("test-colours"), entry(true, 0x100_0000)).unwrap();
+        let terminfo = dir.to_string_lossy().into_owned();
+        let f = |vars: &[(&str, &str)]| {
+            Features::from_vars(|name| match name {
+                "TERMINFO" => Some(terminfo.clone()),
+                _ => vars
+                    .iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string()),
+            })
+        };
+
+        // The terminfo `colors` capability wins over the name
+        let x = f(&[("TERM", "test-256color")]);
+        assert!(!x.colour_256 && !x.truecolor);
+        let x = f(&[("TERM", "test-colours")]);
+        assert!(x.colour_256 && x.truecolor);
+        // But COLORTERM can still raise it
+        let x = f(&[("TERM", "test-256color"), ("COLORTERM", "truecolor")]);
+        assert!(x.colour_256 && x.truecolor);
+
+        // Guessed from the name if there's no terminfo entry
+        let x = f(&[("TERM", "test-missing-256color")]);
+        assert!(x.colour_256 && !x.truecolor);
+        let x = f(&[("TERM", "test-missing-direct")]);
+        assert!(x.colour_256 && x.truecolor);
+        let x = f(&[("TERM", "test-missing"), ("COLORTERM", "24bit")]);
+        assert!(x.colour_256 && x.truecolor);
+        let x = f(&[("TERM", "test-missing")]);
+        assert!(!x.colour_256 && !x.truecolor && !x.tmux);
+
+        let x = f(&[("TERM", "tmux-missing")]);
+        assert!(x.tmux);
+        let x = f(&[("TERM_PROGRAM", "WezTerm")]);
+        assert!(x.iterm_image && x.notify_777);
+        let x = f(&[("VTE_VERSION", "7600"), ("TMUX", "/tmp/x")]);
+        assert!(x.notify_777 && x.tmux && !x.iterm_image);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
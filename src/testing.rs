@@ -0,0 +1,335 @@
+use crate::{Attr, Colour, Features, TermOut};
+
+/// Stand-in for [`Terminal`] for testing rendering code
+///
+/// This owns a [`TermOut`] which may be written to just as the app
+/// would normally write to the shared [`TermOut`] passed by the
+/// [`Terminal`] actor.  Instead of writing flushed data to the TTY,
+/// it is captured, and may then be examined either as raw bytes or
+/// as a grid of cells produced by a minimal ANSI interpreter.
+///
+/// There is no terminal backend trait in this crate, so the app
+/// code under test must be structured to write to a `&mut TermOut`
+/// (or to render a [`Page`] to one), which is the case for most
+/// apps anyway.  The interpreter only understands the sequences
+/// which this crate generates for basic output: text, cursor
+/// movement, erasing and SGR colours, with 256-colour and 24-bit
+/// colours reduced to the nearest basic colour.  Anything else is
+/// ignored.  All characters are assumed to take up one cell.
+///
+/// This is only available with the `testing` cargo feature.
+///
+/// [`Page`]: struct.Page.html
+/// [`TermOut`]: struct.TermOut.html
+/// [`Terminal`]: struct.Terminal.html
+pub struct TestTerminal {
+    termout: TermOut,
+    data: Vec<u8>,
+}
+
+impl TestTerminal {
+    /// Create a test terminal of the given size: rows, columns.  No
+    /// optional features are enabled.
+    pub fn new(sy: i32, sx: i32) -> Self {
+        let mut termout = TermOut::new(Features::default());
+        termout.set_size(sy.max(1), sx.max(1));
+        Self {
+            termout,
+            data: Vec::new(),
+        }
+    }
+
+    /// Get the [`TermOut`] to write output to
+    ///
+    /// [`TermOut`]: struct.TermOut.html
+    pub fn termout(&mut self) -> &mut TermOut {
+        &mut self.termout
+    }
+
+    /// Capture all the data that's ready for sending from the
+    /// [`TermOut`] buffer, the equivalent of [`Terminal::flush`].
+    ///
+    /// [`TermOut`]: struct.TermOut.html
+    /// [`Terminal::flush`]: struct.Terminal.html#method.flush
+    pub fn flush(&mut self) {
+        self.data.extend_from_slice(self.termout.data_to_flush());
+        self.termout.drain_flush();
+    }
+
+    /// Get all the bytes captured so far
+    pub fn output(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Discard all the bytes captured so far.  Note that this also
+    /// means that [`TestTerminal::grid`] starts again from a blank
+    /// screen.
+    ///
+    /// [`TestTerminal::grid`]: struct.TestTerminal.html#method.grid
+    pub fn clear_output(&mut self) {
+        self.data.clear();
+    }
+
+    /// Run all the captured bytes through a minimal ANSI interpreter
+    /// starting from a blank screen, and return the resulting grid
+    /// of cells, indexed by row then column.  Each cell is a
+    /// character and the HFB colour it is displayed in (see
+    /// [`TermOut::hfb`]), with `88` for the default colours.
+    ///
+    /// [`TermOut::hfb`]: struct.TermOut.html#method.hfb
+    pub fn grid(&self) -> Vec<Vec<(char, u16)>> {
+        let (sy, sx) = self.termout.size();
+        let mut interp = Interp {
+            grid: vec![vec![(' ', DEFAULT_HFB); sx as usize]; sy as usize],
+            sy,
+            sx,
+            y: 0,
+            x: 0,
+            wrap: false,
+            hfb: DEFAULT_HFB,
+        };
+        let text = String::from_utf8_lossy(&self.data);
+        let mut it = text.chars().peekable();
+        while let Some(ch) = it.next() {
+            match ch {
+                '\x1B' => match it.next() {
+                    Some('[') => {
+                        let mut params = String::new();
+                        while let Some(&c) = it.peek() {
+                            if !('\x30'..='\x3F').contains(&c) {
+                                break;
+                            }
+                            params.push(c);
+                            it.next();
+                        }
+                        while let Some(&c) = it.peek() {
+                            if !('\x20'..='\x2F').contains(&c) {
+                                break;
+                            }
+                            params.push(c);
+                            it.next();
+                        }
+                        if let Some(c) = it.next() {
+                            interp.csi(&params, c);
+                        }
+                    }
+                    Some(']') => {
+                        // Skip OSC up to BEL or ST
+                        while let Some(c) = it.next() {
+                            if c == '\x07' || (c == '\x1B' && it.next_if_eq(&'\\').is_some()) {
+                                break;
+                            }
+                        }
+                    }
+                    Some('c') => {
+                        interp.hfb = DEFAULT_HFB;
+                        interp.erase(0, 0, sy, sx);
+                        interp.goto(0, 0);
+                    }
+                    Some('%') | Some('(') | Some(')') => {
+                        it.next();
+                    }
+                    _ => (),
+                },
+                '\r' => interp.goto(interp.y, 0),
+                '\n' => interp.linefeed(),
+                '\x08' => interp.goto(interp.y, interp.x - 1),
+                ch if ch < ' ' || ch == '\x7F' => (),
+                ch => interp.put(ch),
+            }
+        }
+        interp.grid
+    }
+}
+
+const DEFAULT_HFB: u16 = 88;
+
+// State of the ANSI interpreter
+struct Interp {
+    grid: Vec<Vec<(char, u16)>>,
+    sy: i32,
+    sx: i32,
+    y: i32,
+    x: i32,
+    wrap: bool, // Wrap is pending after writing to last column
+    hfb: u16,
+}
+
+impl Interp {
+    fn goto(&mut self, y: i32, x: i32) {
+        self.y = y.clamp(0, self.sy - 1);
+        self.x = x.clamp(0, self.sx - 1);
+        self.wrap = false;
+    }
+
+    fn linefeed(&mut self) {
+        if self.y + 1 < self.sy {
+            self.y += 1;
+        } else {
+            self.grid.remove(0);
+            self.grid.push(vec![(' ', DEFAULT_HFB); self.sx as usize]);
+        }
+        self.wrap = false;
+    }
+
+    fn put(&mut self, ch: char) {
+        if self.wrap {
+            self.linefeed();
+            self.x = 0;
+        }
+        self.grid[self.y as usize][self.x as usize] = (ch, self.hfb);
+        if self.x + 1 < self.sx {
+            self.x += 1;
+        } else {
+            self.wrap = true;
+        }
+    }
+
+    // Erase from (y0,x0) to just before (y1,x1), in reading order
+    fn erase(&mut self, y0: i32, x0: i32, y1: i32, x1: i32) {
+        for y in y0..=y1.min(self.sy - 1) {
+            let xa = if y == y0 { x0 } else { 0 };
+            let xb = if y == y1 { x1 } else { self.sx };
+            for x in xa.max(0)..xb.min(self.sx) {
+                self.grid[y as usize][x as usize] = (' ', self.hfb);
+            }
+        }
+    }
+
+    fn csi(&mut self, params: &str, cmd: char) {
+        if params.starts_with(|c: char| !c.is_ascii_digit() && c != ';') {
+            return; // Private or intermediate: ignore
+        }
+        let args: Vec<i32> = params.split(';').map(|a| a.parse().unwrap_or(0)).collect();
+        let arg = |i: usize, def: i32| match args.get(i) {
+            Some(&v) if v != 0 => v,
+            _ => def,
+        };
+        let (y, x, sy, sx) = (self.y, self.x, self.sy, self.sx);
+        match cmd {
+            'H' | 'f' => self.goto(arg(0, 1) - 1, arg(1, 1) - 1),
+            'A' => self.goto(y - arg(0, 1), x),
+            'B' => self.goto(y + arg(0, 1), x),
+            'C' => self.goto(y, x + arg(0, 1)),
+            'D' => self.goto(y, x - arg(0, 1)),
+            'G' => self.goto(y, arg(0, 1) - 1),
+            'd' => self.goto(arg(0, 1) - 1, x),
+            'J' => match arg(0, 0) {
+                0 => self.erase(y, x, sy, sx),
+                1 => self.erase(0, 0, y, x + 1),
+                _ => self.erase(0, 0, sy, sx),
+            },
+            'K' => match arg(0, 0) {
+                0 => self.erase(y, x, y, sx),
+                1 => self.erase(y, 0, y, x + 1),
+                _ => self.erase(y, 0, y, sx),
+            },
+            'X' => self.erase(y, x, y, x + arg(0, 1)),
+            'm' => self.sgr(params),
+            _ => (),
+        }
+    }
+
+    // Apply SGR parameters.  The extended colour selections `38` and
+    // `48` are followed by either `5;n` or `2;r;g;b`, or the same as
+    // `:` subparameters, which are all consumed together.  Extended
+    // colours are reduced to the nearest basic colour.
+    fn sgr(&mut self, params: &str) {
+        let fields: Vec<Vec<i32>> = params
+            .split(';')
+            .map(|f| f.split(':').map(|v| v.parse().unwrap_or(0)).collect())
+            .collect();
+        let mut i = 0;
+        while i < fields.len() {
+            let field = &fields[i];
+            i += 1;
+            let code = field[0];
+            if code != 38 && code != 48 {
+                self.sgr_code(code);
+                continue;
+            }
+            let args: Vec<i32> = if field.len() > 1 {
+                field[1..].to_vec()
+            } else {
+                let count = match fields.get(i).map(|f| f[0]) {
+                    Some(5) => 2,
+                    Some(2) => 4,
+                    _ => 1,
+                };
+                let end = (i + count).min(fields.len());
+                let args = fields[i..end].iter().map(|f| f[0]).collect();
+                i = end;
+                args
+            };
+            let colour = match args[..] {
+                [5, n] => Colour::Indexed(n as u8),
+                [2, r, g, b] | [2, _, r, g, b] => Colour::Rgb(r as u8, g as u8, b as u8),
+                _ => continue,
+            };
+            let digit = Attr::new(colour, Colour::Default).to_hfb() / 10 % 10;
+            let (h, f, b) = (self.hfb / 100, self.hfb / 10 % 10, self.hfb % 10);
+            self.hfb = match code {
+                38 => h * 100 + digit * 10 + b,
+                _ => h * 100 + f * 10 + digit,
+            };
+        }
+    }
+
+    fn sgr_code(&mut self, code: i32) {
+        // Colour-intensity order for ANSI colours 0-7
+        const CI: [u16; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+        let (h, f, b) = (self.hfb / 100, self.hfb / 10 % 10, self.hfb % 10);
+        let (h, f, b) = match code {
+            0 => (0, 8, 8),
+            1 => (1, f, b),
+            22 => (0, f, b),
+            30..=37 => (h, CI[(code - 30) as usize], b),
+            39 => (h, 8, b),
+            40..=47 => (h, f, CI[(code - 40) as usize]),
+            49 => (h, f, 8),
+            90..=97 => (h, CI[(code - 90) as usize], b),
+            100..=107 => (h, f, CI[(code - 100) as usize]),
+            _ => (h, f, b),
+        };
+        self.hfb = h * 100 + f * 10 + b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Get the characters on each row of the grid
+    fn rows(grid: &[Vec<(char, u16)>]) -> Vec<String> {
+        grid.iter()
+            .map(|r| r.iter().map(|c| c.0).collect())
+            .collect()
+    }
+
+    #[test]
+    fn grid() {
+        let mut t = TestTerminal::new(3, 5);
+        t.termout().hfb(12).out("abc").at(1, 3).hfb(188).out("xyz");
+        t.termout().attr_reset().flush();
+        t.flush();
+        let g = t.grid();
+        assert_eq!(rows(&g), ["abc  ", "   xy", "z    "]);
+        assert_eq!(g[0][0].1, 12);
+        assert_eq!(g[1][3].1, 188);
+        assert_eq!(g[2][0].1, 188);
+        assert_eq!(g[2][1].1, 88);
+    }
+
+    #[test]
+    fn extended_colours() {
+        let mut t = TestTerminal::new(1, 6);
+        t.termout().bytes(b"\x1B[38;5;196ma\x1B[48;2;0;0;200;1mb");
+        t.termout().bytes(b"\x1B[0;38:5:21mc\x1B[48:2::0:205:0md");
+        t.termout().bytes(b"\x1B[0;91me\x1B[38;5mf").flush();
+        t.flush();
+        let g = t.grid();
+        assert_eq!(rows(&g), ["abcdef"]);
+        let hfb: Vec<_> = g[0].iter().map(|c| c.1).collect();
+        assert_eq!(hfb, [28, 121, 18, 14, 28, 28]);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{Key, Region};
+
+/// Single-line text input field
+///
+/// This owns the text being edited and the cursor position, and
+/// handles the usual editing keys.  Draw it with
+/// [`TextInput::render`] on every redraw.  The text is scrolled
+/// horizontally as necessary to keep the cursor visible, with
+/// overflow markers shown at either end when some of the text is
+/// hidden.
+///
+/// Keys handled by [`TextInput::handle_key`]:
+///
+/// - Printable characters and pastes: insert at the cursor
+/// - `BackSp`, `Delete` or `C-D`: delete the character before or
+///   under the cursor
+/// - `Left`/`Right` or `C-B`/`C-F`: move by a character
+/// - `M-Left`/`M-Right` or `M-b`/`M-f`: move by a word
+/// - `Home`/`End` or `C-A`/`C-E`: move to the start or end
+/// - `M-BackSp` or `C-W`: delete the word before the cursor
+/// - `C-K`: delete from the cursor to the end
+///
+/// The cursor always sits on a character boundary, so a multi-byte
+/// character is inserted or deleted as a whole.  Control characters
+/// and the codepoints used for embedded colour changes are dropped
+/// from inserted text.
+///
+/// [`TextInput::handle_key`]: struct.TextInput.html#method.handle_key
+/// [`TextInput::render`]: struct.TextInput.html#method.render
+pub struct TextInput {
+    text: String,
+    // Byte offset of the cursor into `text`
+    cursor: usize,
+    // Pixels of text scrolled off to the left
+    shift: i32,
+    hfb: u16,
+    ov_hfb: u16,
+}
+
+impl TextInput {
+    /// Create an empty input field, with the given colours for the
+    /// text and for the overflow markers
+    pub fn new(hfb: u16, ov_hfb: u16) -> Self {
+        Self {
+            text: String::new(),
+            cursor: 0,
+            shift: 0,
+            hfb,
+            ov_hfb,
+        }
+    }
+
+    /// Get the text
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Replace the text, and put the cursor at the end
+    pub fn set_text(&mut self, text: &str) {
+        self.text.clear();
+        self.cursor = 0;
+        self.insert(text);
+    }
+
+    /// Get the cursor position as a byte offset into the text
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Set the cursor position as a byte offset into the text.  The
+    /// offset is clamped to the text and moved back to a character
+    /// boundary if necessary.
+    pub fn set_cursor(&mut self, cursor: usize) {
+        let mut cursor = cursor.min(self.text.len());
+        while !self.text.is_char_boundary(cursor) {
+            cursor -= 1;
+        }
+        self.cursor = cursor;
+    }
+
+    /// Handle a keypress.  Returns `true` if the key was used, or
+    /// `false` if it wasn't recognised, in which case the app may
+    /// want to handle it instead, e.g. `Return` or `Tab`.
+    pub fn handle_key(&mut self, key: &Key) -> bool {
+        match key {
+            Key::Pr(ch) => self.insert(ch.encode_utf8(&mut [0; 4])),
+            Key::Paste(text) => self.insert(text),
+            Key::BackSp | Key::Ctrl('H') => {
+                let start = self.prev_char();
+                self.text.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            }
+            Key::Delete | Key::Ctrl('D') => {
+                let end = self.next_char();
+                self.text.replace_range(self.cursor..end, "");
+            }
+            Key::Left | Key::Ctrl('B') => self.cursor = self.prev_char(),
+            Key::Right | Key::Ctrl('F') => self.cursor = self.next_char(),
+            Key::MetaLeft | Key::Meta('b') => self.cursor = self.prev_word(),
+            Key::MetaRight | Key::Meta('f') => self.cursor = self.next_word(),
+            Key::Home | Key::Ctrl('A') => self.cursor = 0,
+            Key::End | Key::Ctrl('E') => self.cursor = self.text.len(),
+            Key::MetaBackSp | Key::Ctrl('W') => {
+                let start = self.prev_word();
+                self.text.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            }
+            Key::Ctrl('K') => self.text.truncate(self.cursor),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Draw the field on the top row of `region`, scrolling the text
+    /// if necessary to keep the cursor visible.  Returns the cursor
+    /// position relative to the region, for the app to pass on to
+    /// [`Page::set_cursor`] after adjusting for the region's
+    /// position on the page.
+    ///
+    /// [`Page::set_cursor`]: struct.Page.html#method.set_cursor
+    pub fn render(&mut self, region: &mut Region<'_>) -> Option<(i32, i32)> {
+        let sx = region.sx();
+        let m = region.measure("<");
+        let cx = region.measure(&self.text[..self.cursor]);
+        let tw = region.measure(&self.text);
+
+        // The cursor needs one cell after the text when at the end.
+        // With the text scrolled, markers may take space at both ends.
+        if tw < sx {
+            self.shift = 0;
+        } else {
+            let limit = if self.shift > 0 { sx - m - m } else { sx - m } - 1;
+            if cx < self.shift {
+                self.shift = cx;
+            } else if cx - self.shift > limit {
+                self.shift = cx - (sx - m - m - 1);
+            }
+            self.shift = self.shift.min(tw + 1 + m - sx).max(0);
+        }
+        region.region(0, 0, 1, sx).field(
+            self.shift,
+            self.cursor,
+            self.hfb,
+            self.hfb,
+            self.ov_hfb,
+            &self.text,
+        )
+    }
+
+    // Insert text at the cursor, dropping anything that can't be
+    // displayed in a single-line field
+    fn insert(&mut self, text: &str) {
+        let text: String = text
+            .chars()
+            .filter(|ch| !ch.is_control() && !('\u{E000}'..='\u{F8FF}').contains(ch))
+            .collect();
+        self.text.insert_str(self.cursor, &text);
+        self.cursor += text.len();
+    }
+
+    // Offset of the character boundary before the cursor
+    fn prev_char(&self) -> usize {
+        match self.text[..self.cursor].char_indices().next_back() {
+            Some((i, _)) => i,
+            None => 0,
+        }
+    }
+
+    // Offset of the character boundary after the cursor
+    fn next_char(&self) -> usize {
+        match self.text[self.cursor..].chars().next() {
+            Some(ch) => self.cursor + ch.len_utf8(),
+            None => self.cursor,
+        }
+    }
+
+    // Offset of the start of the word before the cursor
+    fn prev_word(&self) -> usize {
+        let before = &self.text[..self.cursor];
+        let end = before.trim_end_matches(|ch: char| !ch.is_alphanumeric());
+        end.trim_end_matches(char::is_alphanumeric).len()
+    }
+
+    // Offset of the end of the word after the cursor
+    fn next_word(&self) -> usize {
+        let after = &self.text[self.cursor..];
+        let start = after.trim_start_matches(|ch: char| !ch.is_alphanumeric());
+        self.text.len() - start.trim_start_matches(char::is_alphanumeric).len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Page;
+
+    #[test]
+    fn textinput() {
+        let mut ti = TextInput::new(88, 13);
+        for k in [
+            Key::Pr('a'),
+            Key::Pr('é'),
+            Key::Pr('中'),
+            Key::Pr('b'),
+            Key::Left,
+            Key::BackSp,
+        ] {
+            assert!(ti.handle_key(&k));
+        }
+        assert_eq!((ti.text(), ti.cursor()), ("aéb", 3));
+        ti.handle_key(&Key::Left);
+        ti.handle_key(&Key::Delete);
+        assert_eq!((ti.text(), ti.cursor()), ("ab", 1));
+        ti.handle_key(&Key::Paste("x\n\u{e00c}y z".into()));
+        assert_eq!((ti.text(), ti.cursor()), ("axy zb", 5));
+        ti.handle_key(&Key::MetaLeft);
+        assert_eq!(ti.cursor(), 4);
+        ti.handle_key(&Key::MetaLeft);
+        assert_eq!(ti.cursor(), 0);
+        ti.handle_key(&Key::MetaRight);
+        assert_eq!(ti.cursor(), 3);
+        ti.handle_key(&Key::Ctrl('W'));
+        assert_eq!((ti.text(), ti.cursor()), (" zb", 0));
+        assert!(!ti.handle_key(&Key::Return));
+        ti.set_text("0123456789abcdef");
+        let mut p = Page::new(1, 8, 88);
+        let rows = |p: &mut Page| {
+            let mut v = String::new();
+            p.for_each_change(&Page::new(1, 8, 0), |_, _, _, t| v.push_str(t));
+            v
+        };
+        assert_eq!(ti.render(&mut p.full()), Some((0, 7)));
+        assert_eq!(rows(&mut p), "<abcdef ");
+        ti.handle_key(&Key::Home);
+        assert_eq!(ti.render(&mut p.full()), Some((0, 0)));
+        assert_eq!(rows(&mut p), "0123456>");
+        for _ in 0..7 {
+            ti.handle_key(&Key::Right);
+        }
+        assert_eq!(ti.render(&mut p.full()), Some((0, 6)));
+        assert_eq!(rows(&mut p), "<234567>");
+    }
+}
@@ -0,0 +1,262 @@
+use crate::page::{Meas, Scan};
+use crate::Region;
+
+/// Scrollable view of word-wrapped text
+///
+/// This holds a string which may contain embedded colour changes
+/// (see [`hfb_char`]) and newlines.  When drawn, the text is
+/// word-wrapped to the width of the region, less one column which
+/// is kept for the overflow indicators: `▲` at the top-right if
+/// there is text above the visible window, and `▼` at the
+/// bottom-right if there is text below.  Colour changes carry on
+/// across wrap points.  Words too long for a line are broken at the
+/// last glyph that fits.
+///
+/// The scroll offset is counted in wrapped lines.  When the width
+/// changes, for example on a terminal resize, the text is wrapped
+/// again and the scroll offset is clamped so that the window doesn't
+/// run off the end of the text.
+///
+/// [`hfb_char`]: fn.hfb_char.html
+pub struct TextView {
+    text: String,
+    hfb: u16,
+    scroll: i32,
+    // Height of the window at the last draw
+    view_sy: i32,
+    // Width that `lines` was wrapped to, or -1 if not wrapped yet
+    width: i32,
+    // Cell width of the page last drawn on
+    csx: u16,
+    // Wrapped lines: byte range in `text`, and colour at the start
+    lines: Vec<(usize, usize, u16)>,
+}
+
+impl TextView {
+    /// Create an empty view, with `hfb` as the initial colour of the
+    /// text and the colour of the background
+    pub fn new(hfb: u16) -> Self {
+        Self {
+            text: String::new(),
+            hfb,
+            scroll: 0,
+            view_sy: 0,
+            width: -1,
+            csx: 1,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Replace the text.  The scroll offset is kept, so that a
+    /// growing log can be updated without losing the user's place.
+    pub fn set_text(&mut self, text: &str) {
+        text.clone_into(&mut self.text);
+        self.width = -1;
+    }
+
+    /// Get the text
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Get the scroll offset, in wrapped lines from the top
+    pub fn scroll(&self) -> i32 {
+        self.scroll
+    }
+
+    /// Scroll to the given offset, in wrapped lines from the top
+    pub fn scroll_to(&mut self, line: i32) {
+        self.scroll = line;
+        self.clamp();
+    }
+
+    /// Scroll by the given number of lines, down if positive, up if
+    /// negative
+    pub fn scroll_lines(&mut self, delta: i32) {
+        self.scroll_to(self.scroll.saturating_add(delta));
+    }
+
+    /// Scroll by the given number of pages, down if positive, up if
+    /// negative.  A page is one line less than the height of the
+    /// window at the last draw, so that one line of context is kept.
+    pub fn scroll_pages(&mut self, delta: i32) {
+        let page = (self.view_sy - 1).max(1);
+        self.scroll_lines(delta.saturating_mul(page));
+    }
+
+    /// Get the total height of the text in wrapped lines when drawn
+    /// into a region of width `sx`, e.g. for sizing a scrollbar.  The
+    /// text is measured using the cell width of the page last drawn
+    /// on.
+    pub fn height(&mut self, sx: i32) -> i32 {
+        self.wrap(sx - mark_width(self.csx));
+        self.lines.len() as i32
+    }
+
+    /// Draw the visible window of the text into the region, clearing
+    /// the rest of it
+    pub fn draw(&mut self, region: &mut Region<'_>) {
+        let (sy, sx) = (region.sy(), region.sx());
+        self.view_sy = sy;
+        let csx = region.cell_sx() as u16;
+        if csx != self.csx {
+            self.csx = csx;
+            self.width = -1;
+        }
+        let mw = mark_width(csx);
+        self.wrap(sx - mw);
+        self.clamp();
+        region.clear(self.hfb);
+        let start = self.scroll as usize;
+        for (y, &(a, b, hfb)) in self
+            .lines
+            .iter()
+            .skip(start)
+            .take(sy.max(0) as usize)
+            .enumerate()
+        {
+            region.write(y as i32, 0, hfb, &self.text[a..b]);
+        }
+        if sy > 0 && sx >= mw {
+            if self.scroll > 0 {
+                region.write(0, sx - mw, self.hfb, "▲");
+            }
+            if self.lines.len() > start + sy as usize {
+                region.write(sy - 1, sx - mw, self.hfb, "▼");
+            }
+        }
+    }
+
+    // Keep the scroll offset within the text, if it has been wrapped
+    fn clamp(&mut self) {
+        let max = if self.width < 0 {
+            i32::MAX
+        } else {
+            (self.lines.len() as i32 - self.view_sy).max(0)
+        };
+        self.scroll = self.scroll.min(max).max(0);
+    }
+
+    // Wrap the text to the given width, if not already done
+    fn wrap(&mut self, width: i32) {
+        let width = width.max(1);
+        if width == self.width {
+            return;
+        }
+        self.width = width;
+        self.lines.clear();
+        let mut hfb = self.hfb;
+        let mut pos = 0;
+        for para in self.text.split('\n') {
+            let end = pos + para.len();
+            let mut start = (pos, hfb);
+            let mut brk = None;
+            let mut x = 0;
+            let mut p = Scan(para.as_bytes(), self.csx);
+            loop {
+                let at = end - p.0.len();
+                let space = p.0.first() == Some(&b' ');
+                match p.measure() {
+                    Meas::End => break,
+                    Meas::Attr(v) => hfb = v,
+                    Meas::Glyph(inc) => {
+                        let inc = i32::from(inc);
+                        let next = end - p.0.len();
+                        if x + inc <= width || at == start.0 {
+                            x += inc;
+                            if space {
+                                brk = Some((next, hfb));
+                            }
+                        } else if space {
+                            // Let an overflowing space hang off the end
+                            self.lines.push((start.0, next, start.1));
+                            start = (next, hfb);
+                            brk = None;
+                            x = 0;
+                        } else {
+                            // Break after the last space if there
+                            // was one, else just before this glyph
+                            let (cut, cut_hfb) = brk.unwrap_or((at, hfb));
+                            self.lines.push((start.0, cut, start.1));
+                            start = (cut, cut_hfb);
+                            brk = None;
+                            x = Scan(&self.text.as_bytes()[cut..next], self.csx).measure_rest()
+                                as i32;
+                        }
+                    }
+                }
+            }
+            self.lines.push((start.0, end, start.1));
+            pos = end + 1;
+        }
+    }
+}
+
+// Width of the column kept for the overflow indicators
+fn mark_width(csx: u16) -> i32 {
+    Scan("▲".as_bytes(), csx).measure_rest() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Page;
+
+    // Draw the view on a page of the given size, and return the text
+    fn draw(tv: &mut TextView, sy: i32, sx: i32) -> String {
+        let mut p = Page::new(sy, sx, 88);
+        tv.draw(&mut p.full());
+        p.to_string_plain()
+    }
+
+    #[test]
+    fn scrolling() {
+        let mut tv = TextView::new(88);
+        tv.set_text("one two three four five six seven eight\nabcdefghijklmnop\n\nend");
+        assert_eq!(tv.height(10), 9);
+        assert_eq!(
+            draw(&mut tv, 4, 10),
+            "one two   \nthree     \nfour five \nsix seven▼"
+        );
+        tv.scroll_pages(1);
+        assert_eq!(tv.scroll(), 3);
+        assert_eq!(
+            draw(&mut tv, 4, 10),
+            "six seven▲\neight     \nabcdefghi \njklmnop  ▼"
+        );
+        tv.scroll_lines(100);
+        assert_eq!(tv.scroll(), 5);
+        assert_eq!(
+            draw(&mut tv, 4, 10),
+            "abcdefghi▲\njklmnop   \n          \nend       "
+        );
+
+        // Rewrapped and clamped after a resize
+        let text = draw(&mut tv, 4, 30);
+        let lines: Vec<_> = text.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines,
+            [
+                "seven eight                  ▲",
+                "abcdefghijklmnop",
+                "",
+                "end"
+            ]
+        );
+        assert_eq!(tv.height(30), 5);
+        assert_eq!(tv.scroll(), 1);
+    }
+
+    #[test]
+    fn colour_across_wrap() {
+        let mut tv = TextView::new(88);
+        tv.set_text("a\u{e00c}bc de fg");
+        let mut p = Page::new(3, 5, 88);
+        tv.draw(&mut p.full());
+        assert_eq!(p.to_string_plain(), "abc  \nde   \nfg   ");
+        assert_eq!(p.cell_at(0, 0), Some(("a".into(), 88, false)));
+        assert_eq!(p.cell_at(0, 1), Some(("b".into(), 12, false)));
+        assert_eq!(p.cell_at(1, 0), Some(("d".into(), 12, false)));
+        assert_eq!(p.cell_at(2, 1), Some(("g".into(), 12, false)));
+    }
+}